@@ -0,0 +1,202 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+use futures::future::join_all;
+use lazy_static::lazy_static;
+use rocket::http::uri::Origin;
+use rocket::http::Header;
+use rocket::local::asynchronous::Client;
+use rocket::serde::json::{self, Json};
+use rocket::{Build, Orbit, Rocket, State};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::sync::OnceCell;
+
+use crate::config::API_CONFIG;
+use crate::deadline::{Deadline, BUDGET_HEADER};
+use crate::request_id::RequestId;
+use crate::response::{ApiError, ApiResponse};
+
+/// Headers of sub-requests set by the batch itself: bodies are embedded in the JSON of the
+/// batch, so they are requested uncompressed and as JSON, and within the deadline of the batch.
+const RESERVED_HEADERS: [&str; 4] = ["accept", "accept-encoding", "x-request-id", "x-batch-budget"];
+
+/// Copy of a state of the running instance to the instance answering the requests of batches.
+type Share = fn(&Rocket<Orbit>, Rocket<Build>) -> Rocket<Build>;
+
+/// States of the running instance shared with the instance answering the requests of batches,
+/// as registered through [`Sharing`].
+#[derive(Default)]
+struct Shared(Mutex<Vec<Share>>);
+
+/// Management of the states shared with batches, such as storage and caches: the requests of a
+/// batch are answered by an instance of their own, which gets every state shared this way.
+pub trait Sharing {
+    /// Manage `state`, sharing it with batches.
+    fn share<T: Clone + Send + Sync + 'static>(self, state: T) -> Self;
+
+    /// Share a state managed by some other means (e.g. a fairing) through `share`.
+    fn share_with(self, share: Share) -> Self;
+}
+
+impl Sharing for Rocket<Build> {
+    fn share<T: Clone + Send + Sync + 'static>(self, state: T) -> Self {
+        fn copy<T: Clone + Send + Sync + 'static>(from: &Rocket<Orbit>, to: Rocket<Build>) -> Rocket<Build> {
+            match from.state::<T>() {
+                Some(state) => to.manage(state.clone()),
+                None => to,
+            }
+        }
+        self.manage(state).share_with(copy::<T>)
+    }
+
+    fn share_with(self, share: Share) -> Self {
+        let rocket = match self.state::<Shared>() {
+            Some(_) => self,
+            None => self.manage(Shared::default()),
+        };
+        if let Some(shared) = rocket.state::<Shared>() {
+            shared.0.lock().unwrap_or_else(|e| e.into_inner()).push(share);
+        }
+        rocket
+    }
+}
+
+/// Request of a batch: a GET to `path`, either absolute (`/api/v1/map/stop/urban/12`) or
+/// relative to `/api/v1` (`map/stop/urban/12`).
+#[derive(Deserialize)]
+pub struct SubRequest {
+    #[serde(default = "SubRequest::default_method")]
+    method: String,
+    path: String,
+    #[serde(default)]
+    headers: BTreeMap<String, String>,
+}
+
+impl SubRequest {
+    fn default_method() -> String {
+        "GET".to_owned()
+    }
+
+    /// Target of the request, or why it cannot be dispatched.
+    fn target(&self) -> Result<Origin<'static>, ApiError> {
+        if !self.method.eq_ignore_ascii_case("GET") {
+            return Err(ApiError::Generic(400, format!("only GET requests can be batched, not {}", self.method)));
+        }
+        let path = match self.path.strip_prefix('/') {
+            Some(_) => self.path.clone(),
+            None => format!("/api/v1/{}", self.path),
+        };
+        Origin::parse_owned(path)
+            .map_err(|e| ApiError::Generic(400, format!("invalid path `{}`: {}", self.path, e)))
+    }
+}
+
+/// Outcome of a request of a batch, as it would have been answered on its own.
+#[derive(Serialize)]
+pub struct SubResponse {
+    status: u16,
+    headers: BTreeMap<String, String>,
+    /// JSON bodies are embedded as they are, other ones as strings.
+    body: Value,
+}
+
+/// Client dispatching the requests of batches to an instance of the API with the same routes,
+/// fairings and [shared](Sharing) states as the running one, created on the first batch.
+///
+/// Rocket can't route a request it didn't receive itself, hence the second instance.
+#[derive(Default)]
+pub struct BatchClient(OnceCell<Client>);
+
+impl BatchClient {
+    async fn get(&self, rocket: &Rocket<Orbit>) -> Result<&Client, rocket::Error> {
+        self.0.get_or_try_init(|| async {
+            let shares = match rocket.state::<Shared>() {
+                Some(shared) => shared.0.lock().unwrap_or_else(|e| e.into_inner()).clone(),
+                None => vec![],
+            };
+            let app = shares.into_iter().fold(crate::app(), |app, share| share(rocket, app));
+            Client::untracked(app).await
+        }).await
+    }
+}
+
+/// Dispatch the `index`th request of the batch `batch_id`, within the time left to the batch.
+async fn dispatch(client: &Client, batch_id: &RequestId, deadline: &Deadline, index: usize, sub: SubRequest) -> SubResponse {
+    let target = match sub.target() {
+        Ok(t) => t,
+        Err(e) => {
+            let problem = e.detached_problem(&sub.path, batch_id);
+            return SubResponse {
+                status: e.status(),
+                headers: BTreeMap::from([("content-type".to_owned(), "application/problem+json".to_owned())]),
+                body: serde_json::to_value(problem).unwrap_or(Value::Null),
+            };
+        }
+    };
+    let mut local = client.get(target)
+        .header(Header::new("Accept", "application/json"))
+        .header(Header::new("X-Request-Id", format!("{}.{}", batch_id, index)))
+        .header(Header::new(BUDGET_HEADER, deadline.remaining().unwrap_or_default().as_millis().to_string()));
+    for (name, value) in sub.headers {
+        if !RESERVED_HEADERS.contains(&name.to_ascii_lowercase().as_str()) {
+            local.add_header(Header::new(name, value));
+        }
+    }
+
+    let response = local.dispatch().await;
+    let status = response.status().code;
+    let mut headers = BTreeMap::<String, String>::new();
+    for h in response.headers().iter() {
+        headers.entry(h.name().as_str().to_ascii_lowercase())
+            .and_modify(|v| {
+                v.push_str(", ");
+                v.push_str(h.value());
+            })
+            .or_insert_with(|| h.value().to_owned());
+    }
+    let json = response.content_type().is_some_and(|c| c.is_json() || c.sub().as_str().ends_with("+json"));
+    let body = match response.into_bytes().await {
+        None => Value::Null,
+        Some(b) if b.is_empty() => Value::Null,
+        Some(b) if json => serde_json::from_slice(&b)
+            .unwrap_or_else(|_| Value::String(String::from_utf8_lossy(&b).into_owned())),
+        Some(b) => Value::String(String::from_utf8_lossy(&b).into_owned()),
+    };
+    SubResponse { status, headers, body }
+}
+
+/// Run a batch of GET requests, concurrently, answering with their outcomes in the same order.
+/// Failed requests are reported in their own outcome, without failing the whole batch, and
+/// none is given more time than what's left to the batch.
+#[post("/", data = "<requests>")]
+async fn batch(
+    id: RequestId,
+    deadline: Deadline,
+    rocket: &Rocket<Orbit>,
+    client: &State<BatchClient>,
+    requests: Result<Json<Vec<SubRequest>>, json::Error<'_>>,
+) -> ApiResponse<Vec<SubResponse>> {
+    let requests = requests
+        .map_err(|e| ApiError::Generic(400, format!("invalid batch: {}", e)))?
+        .into_inner();
+    if requests.len() > API_CONFIG.batch_max_requests {
+        return ApiError::Generic(400, format!("a batch can hold at most {} requests", API_CONFIG.batch_max_requests)).respond();
+    }
+    let client = match client.get(rocket).await {
+        Ok(c) => c,
+        // inspecting the kind of a Rocket error marks it as handled
+        Err(e) => return ApiError::InternalServer(format!("cannot start the batch client: {}", e.kind()).into()).respond(),
+    };
+
+    let responses = join_all(requests.into_iter()
+        .enumerate()
+        .map(|(i, sub)| dispatch(client, &id, &deadline, i, sub)))
+        .await;
+    let total = responses.len();
+    ApiResponse::Ok(responses, Some(total))
+}
+
+lazy_static!{
+    pub static ref ROUTES: Vec<rocket::Route> = routes![batch];
+}
@@ -0,0 +1,65 @@
+use bruss_config::{CachePolicy, CONFIGS};
+use chrono::{TimeDelta, Utc};
+use rocket::http::{Header, Method, Status};
+use rocket::{Request, Response};
+use rocket::fairing::{Fairing, Info, Kind};
+
+use crate::config::match_path;
+
+/// Apply the `Cache-Control` and `Expires` headers of the policies configured in the shared
+/// bruss configuration (`CONFIGS.api.cache_policies`) to successful responses.
+///
+/// The `path` of a policy is matched segment by segment against the request path: `*` matches
+/// any single segment, and the pattern matches any path it is a prefix of. When more than one
+/// policy matches, the one with the most segments wins. Without a `max_age`, `no-cache` is sent.
+pub struct CacheControl;
+
+impl CacheControl {
+    fn policy_for(path: &str) -> Option<&'static CachePolicy> {
+        CONFIGS.api.cache_policies.iter()
+            .filter_map(|p| match_path(&p.path, path).map(|m| (m, p)))
+            .max_by_key(|(m, _)| *m)
+            .map(|(_, p)| p)
+    }
+
+    fn cache_control(policy: &CachePolicy) -> String {
+        match policy.max_age {
+            Some(max_age) => {
+                let mut directives = vec!["public".to_owned(), format!("max-age={}", max_age)];
+                if let Some(swr) = policy.stale_while_revalidate {
+                    directives.push(format!("stale-while-revalidate={}", swr));
+                }
+                directives.join(", ")
+            }
+            None => "no-cache".to_owned()
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl Fairing for CacheControl {
+    fn info(&self) -> Info {
+        Info {
+            name: "Add Cache-Control headers to responses",
+            kind: Kind::Response
+        }
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        if request.method() != Method::Get
+            || !matches!(response.status(), Status::Ok | Status::NotModified)
+            || response.headers().contains("Cache-Control")
+        {
+            return;
+        }
+        let policy = match Self::policy_for(request.uri().path().as_str()) {
+            Some(p) => p,
+            None => return,
+        };
+        response.set_header(Header::new("Cache-Control", Self::cache_control(policy)));
+        if let Some(max_age) = policy.max_age {
+            let expires = Utc::now() + TimeDelta::seconds(max_age as i64);
+            response.set_header(Header::new("Expires", expires.format("%a, %d %b %Y %H:%M:%S GMT").to_string()));
+        }
+    }
+}
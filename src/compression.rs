@@ -0,0 +1,161 @@
+use std::io::{Cursor, Write};
+
+use rocket::http::{Header, Status};
+use rocket::{Request, Response};
+use rocket::fairing::{Fairing, Info, Kind};
+
+use crate::config::API_CONFIG;
+
+/// Content codings supported for response bodies, in order of preference when the client
+/// accepts more than one with the same weight.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Encoding {
+    Brotli,
+    Zstd,
+    Gzip,
+}
+
+impl Encoding {
+    const ALL: [Encoding; 3] = [Self::Brotli, Self::Zstd, Self::Gzip];
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Brotli => "br",
+            Self::Zstd => "zstd",
+            Self::Gzip => "gzip",
+        }
+    }
+
+    /// Pick the encoding with the highest weight in an `Accept-Encoding` header.
+    fn negotiate(accept_encoding: &str) -> Option<Self> {
+        let weights = accept_encoding.split(',')
+            .filter_map(|c| {
+                let mut parts = c.split(';').map(str::trim);
+                let name = parts.next()?.to_ascii_lowercase();
+                let q = parts
+                    .find_map(|p| p.strip_prefix("q="))
+                    .and_then(|q| q.parse::<f32>().ok())
+                    .unwrap_or(1.0);
+                Some((name, q))
+            })
+            .collect::<Vec<_>>();
+        let weight = |e: Encoding| weights.iter()
+            .find(|(n, _)| n == e.name())
+            .or_else(|| weights.iter().find(|(n, _)| n == "*"))
+            .map(|(_, q)| *q)
+            .unwrap_or(0.0);
+
+        Self::ALL.into_iter()
+            .map(|e| (e, weight(e)))
+            .filter(|(_, q)| *q > 0.0)
+            // `max_by` returns the last maximum: reverse to keep the preferred encoding on ties
+            .rev()
+            .max_by(|(_, a), (_, b)| a.total_cmp(b))
+            .map(|(e, _)| e)
+    }
+
+    fn encode(self, body: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            Self::Brotli => {
+                let mut w = brotli::CompressorWriter::new(Vec::new(), 4096, 5, 22);
+                w.write_all(body)?;
+                Ok(w.into_inner())
+            }
+            Self::Zstd => zstd::encode_all(body, 3),
+            Self::Gzip => {
+                let mut w = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                w.write_all(body)?;
+                w.finish()
+            }
+        }
+    }
+}
+
+/// Compress response bodies larger than
+/// [`ApiConfig::compression_min_size`](crate::config::ApiConfig) according to the
+/// `Accept-Encoding` request header.
+///
+/// Streamed bodies, whose size isn't known in advance, are left untouched.
+pub struct Compression;
+
+#[rocket::async_trait]
+impl Fairing for Compression {
+    fn info(&self) -> Info {
+        Info {
+            name: "Compress response bodies",
+            kind: Kind::Response
+        }
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        if response.status() != Status::Ok || response.headers().contains("Content-Encoding") {
+            return;
+        }
+        match response.body().preset_size() {
+            Some(size) if size >= API_CONFIG.compression_min_size => {}
+            _ => return,
+        }
+        response.adjoin_header(Header::new("Vary", "Accept-Encoding"));
+
+        let encoding = match request.headers().get_one("Accept-Encoding").and_then(Encoding::negotiate) {
+            Some(e) => e,
+            None => return,
+        };
+        let body = match response.body_mut().to_bytes().await {
+            Ok(b) => b,
+            Err(e) => {
+                error!("failed to read response body for compression: {}", e);
+                return;
+            }
+        };
+        match encoding.encode(&body) {
+            Ok(encoded) => {
+                response.set_sized_body(encoded.len(), Cursor::new(encoded));
+                response.set_header(Header::new("Content-Encoding", encoding.name()));
+            }
+            Err(e) => {
+                error!("failed to {} encode response body: {}", encoding.name(), e);
+                response.set_sized_body(body.len(), Cursor::new(body));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Encoding::{self, *};
+
+    #[test]
+    fn negotiate_weights() {
+        assert_eq!(Encoding::negotiate("gzip"), Some(Gzip));
+        assert_eq!(Encoding::negotiate("gzip, deflate, br, zstd"), Some(Brotli));
+        assert_eq!(Encoding::negotiate("gzip;q=1.0, br;q=0.5, zstd;q=0.8"), Some(Gzip));
+        assert_eq!(Encoding::negotiate("GZIP ; q=0.2, Zstd;q=0.3"), Some(Zstd));
+        // same weight: the preferred encoding wins
+        assert_eq!(Encoding::negotiate("gzip;q=0.5, zstd;q=0.5"), Some(Zstd));
+        // malformed weights count as 1
+        assert_eq!(Encoding::negotiate("gzip;q=high, br;q=0.9"), Some(Gzip));
+        assert_eq!(Encoding::negotiate("deflate"), None);
+        assert_eq!(Encoding::negotiate(""), None);
+    }
+
+    #[test]
+    fn negotiate_exclusions() {
+        assert_eq!(Encoding::negotiate("br;q=0, gzip"), Some(Gzip));
+        assert_eq!(Encoding::negotiate("gzip;q=0"), None);
+        // explicit weights take precedence over the wildcard
+        assert_eq!(Encoding::negotiate("*"), Some(Brotli));
+        assert_eq!(Encoding::negotiate("br;q=0, *;q=0.5"), Some(Zstd));
+        assert_eq!(Encoding::negotiate("*;q=0, gzip;q=0.1"), Some(Gzip));
+        assert_eq!(Encoding::negotiate("*;q=0"), None);
+    }
+
+    #[test]
+    fn negotiate_identity() {
+        // refusing an uncompressed body doesn't make us pick an encoding the client didn't list
+        assert_eq!(Encoding::negotiate("identity;q=0"), None);
+        assert_eq!(Encoding::negotiate("identity;q=0, gzip;q=0.1"), Some(Gzip));
+        assert_eq!(Encoding::negotiate("identity;q=0, *"), Some(Brotli));
+        assert_eq!(Encoding::negotiate("identity, zstd;q=0.5"), Some(Zstd));
+    }
+}
@@ -0,0 +1,144 @@
+use std::time::Duration;
+
+use lazy_static::lazy_static;
+use serde::Deserialize;
+
+use crate::indexes::IndexMode;
+
+/// Settings specific to the API server, read from the `bruss_api` table of the Rocket
+/// configuration (`Rocket.toml` or `ROCKET_BRUSS_API`), so that they can be tuned without a
+/// rebuild. Settings shared with the other bruss components, and those ops tune the most, such
+/// as the caching policies, are in `bruss_config::CONFIGS`.
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+pub struct ApiConfig {
+    /// Minimum size, in bytes, of response bodies to be compressed.
+    pub compression_min_size: usize,
+    /// Include the error description and backtrace in the body of server errors. Meant for
+    /// development and staging deployments only.
+    pub verbose_errors: bool,
+    /// Capped collection server errors are stored into, besides being logged.
+    pub error_collection: Option<String>,
+    /// Size, in bytes, of the capped collection of server errors.
+    pub error_collection_size: u64,
+    /// Time allowed to a request, its database queries and upstream calls included, in
+    /// milliseconds, unless a more specific deadline is configured in `deadlines`.
+    pub default_deadline_ms: u64,
+    /// Per-endpoint deadlines, see [`DeadlinePolicy`].
+    pub deadlines: Vec<DeadlinePolicy>,
+    /// Queries taking longer than this, in milliseconds, are logged and recorded as slow.
+    pub slow_query_ms: u64,
+    /// Capped collection slow queries are stored into, besides being logged.
+    pub slow_query_collection: Option<String>,
+    /// Size, in bytes, of the capped collection of slow queries.
+    pub slow_query_collection_size: u64,
+    /// Time, in seconds, the results of queries on areas, routes and stops are kept in memory.
+    /// Zero disables the cache.
+    pub cache_ttl: u64,
+    /// Maximum number of query results kept in memory, the least recently used being evicted
+    /// first.
+    pub cache_max_entries: usize,
+    /// Interval, in seconds, between checks for a new dataset, which drops the cache.
+    pub cache_check_interval: u64,
+    /// Let clients get the query plans of a request instead of its data, through the
+    /// `X-Debug-Explain` header, and the query cache statistics. Always allowed in debug builds.
+    pub explain: bool,
+    /// Whether the indexes needed by the queries are created at startup, only checked, or
+    /// ignored, see [`IndexMode`].
+    pub index_mode: IndexMode,
+    /// Time, in seconds, realtime trip updates are kept in the database before expiring.
+    pub trip_updates_ttl: u64,
+    /// Directory of JSON fixtures to serve from memory instead of connecting to the database,
+    /// see [`MemoryStore::from_fixtures`](crate::storage::MemoryStore::from_fixtures).
+    pub fixtures: Option<String>,
+    /// Maximum number of requests of a batch sent to `/api/v1/batch`.
+    pub batch_max_requests: usize,
+    /// Maximum nesting of the queries sent to `/api/v1/graphql`.
+    pub graphql_max_depth: usize,
+    /// Maximum complexity of the queries sent to `/api/v1/graphql`: each field counts one,
+    /// times the page size of the lists it is nested in.
+    pub graphql_max_complexity: usize,
+}
+
+impl Default for ApiConfig {
+    fn default() -> Self {
+        Self {
+            compression_min_size: 1024,
+            verbose_errors: false,
+            error_collection: None,
+            error_collection_size: 16 * 1024 * 1024,
+            default_deadline_ms: 5000,
+            deadlines: DeadlinePolicy::defaults(),
+            slow_query_ms: 500,
+            slow_query_collection: None,
+            slow_query_collection_size: 16 * 1024 * 1024,
+            cache_ttl: 60 * 60,
+            cache_max_entries: 10_000,
+            cache_check_interval: 60,
+            explain: false,
+            index_mode: IndexMode::default(),
+            trip_updates_ttl: 24 * 60 * 60,
+            fixtures: None,
+            batch_max_requests: 20,
+            graphql_max_depth: 8,
+            graphql_max_complexity: 2000,
+        }
+    }
+}
+
+/// Number of segments of `path` matched by `pattern`, if it matches at all.
+///
+/// `pattern` is matched segment by segment: `*` matches any single segment, and the pattern
+/// matches any path it is a prefix of.
+pub fn match_path(pattern: &str, path: &str) -> Option<usize> {
+    let mut segments = path.split('/').filter(|s| !s.is_empty());
+    let mut matched = 0;
+    for p in pattern.split('/').filter(|s| !s.is_empty()) {
+        match segments.next() {
+            Some(s) if p == "*" || p == s => matched += 1,
+            _ => return None,
+        }
+    }
+    Some(matched)
+}
+
+/// Deadline of the requests matching `path`, matched by [`match_path`].
+#[derive(Deserialize, Debug, Clone)]
+pub struct DeadlinePolicy {
+    pub path: String,
+    pub timeout_ms: u64,
+}
+
+impl DeadlinePolicy {
+    fn defaults() -> Vec<Self> {
+        vec![
+            // departures are requested often and should be answered quickly, or not at all
+            Self { path: "/api/v1/map/route/*/trips".to_owned(), timeout_ms: 2000 },
+            Self { path: "/api/v1/map/stop/*/*/trips".to_owned(), timeout_ms: 2000 },
+        ]
+    }
+}
+
+impl ApiConfig {
+    /// Deadline of requests to `path`: the one of the most specific matching policy, or the
+    /// default one.
+    pub fn deadline_for(&self, path: &str) -> Duration {
+        let ms = self.deadlines.iter()
+            .filter_map(|d| match_path(&d.path, path).map(|m| (m, d)))
+            .max_by_key(|(m, _)| *m)
+            .map(|(_, d)| d.timeout_ms)
+            .unwrap_or(self.default_deadline_ms);
+        Duration::from_millis(ms)
+    }
+}
+
+lazy_static! {
+    pub static ref API_CONFIG: ApiConfig = {
+        let figment = rocket::Config::figment();
+        if figment.contains("bruss_api") {
+            figment.extract_inner("bruss_api").expect("invalid `bruss_api` configuration")
+        } else {
+            ApiConfig::default()
+        }
+    };
+}
@@ -1,3 +1,7 @@
+use bruss_config::CONFIGS;
+use mongodb::error::ErrorKind;
+use mongodb::options::CreateCollectionOptions;
+use rocket::{Build, Orbit, Rocket};
 use rocket_db_pools::Database;
 use rocket_db_pools::mongodb::Client;
 
@@ -5,3 +9,29 @@ use rocket_db_pools::mongodb::Client;
 #[database("bruss")]
 pub struct BrussData(Client);
 
+/// Pool of `from`, managed by `to` too once connected: see [`Sharing`](crate::batch::Sharing).
+pub fn share(from: &Rocket<Orbit>, to: Rocket<Build>) -> Rocket<Build> {
+    match BrussData::fetch(from) {
+        Some(db) => to.manage(BrussData::from((**db).clone())),
+        None => to,
+    }
+}
+
+
+/// Create the capped collection `name` of `size` bytes, holding `what`, if not already existing.
+pub async fn create_capped_collection(rocket: &Rocket<Orbit>, name: &str, size: u64, what: &str) {
+    let db = match BrussData::fetch(rocket) {
+        Some(db) => db.database(CONFIGS.db.get_db()),
+        None => return,
+    };
+    let options = CreateCollectionOptions::builder()
+        .capped(true)
+        .size(size)
+        .build();
+    match db.create_collection(name, options).await {
+        Ok(()) => info!("created capped collection `{}` for {}", name, what),
+        // NamespaceExists
+        Err(e) if matches!(*e.kind, ErrorKind::Command(ref c) if c.code == 48) => {}
+        Err(e) => error!("cannot create collection `{}` for {}: {}", name, what, e),
+    }
+}
@@ -0,0 +1,181 @@
+use std::io;
+use std::time::Duration;
+
+use mongodb::error::{Error as MongoError, ErrorKind};
+use rocket::request::{FromRequest, Outcome};
+use rocket::route::{self, Handler, Route};
+use rocket::{Data, Request};
+use tokio::time::Instant;
+
+use crate::config::API_CONFIG;
+use crate::response::{ApiError, ApiResponse};
+
+/// Header of the sub-requests of a batch, holding the time left to the batch in milliseconds:
+/// a request is never given more than that, whatever its endpoint's deadline.
+pub const BUDGET_HEADER: &str = "X-Batch-Budget";
+
+/// Code of the server error returned when an operation exceeds its `maxTimeMS`.
+const MAX_TIME_MS_EXPIRED: i32 = 50;
+
+/// Time a handler is given past its deadline, so that the queries still running can report
+/// their expiry themselves, and be recorded as slow.
+const HANDLER_GRACE: Duration = Duration::from_millis(500);
+
+/// Time budget of a request, configured per endpoint through
+/// [`ApiConfig::deadlines`](crate::config::ApiConfig).
+///
+/// The budget is shared by all the queries and upstream calls of the request: each query is sent
+/// with the time left as `maxTimeMS`, and abandoned if the database doesn't answer in time. The
+/// handler as a whole is abandoned shortly after the deadline, whatever it's waiting for.
+#[derive(Clone, Debug)]
+pub struct Deadline {
+    start: Instant,
+    budget: Duration,
+    endpoint: String,
+}
+
+impl Deadline {
+    /// Get the deadline of `request`, starting from the first time it's asked for.
+    pub fn of(request: &Request<'_>) -> Self {
+        let (start, budget) = *request.local_cache(|| {
+            let budget = API_CONFIG.deadline_for(request.uri().path().as_str());
+            let left = request.headers()
+                .get_one(BUDGET_HEADER)
+                .and_then(|ms| ms.parse().ok())
+                .map(Duration::from_millis);
+            (Instant::now(), left.map_or(budget, |l| l.min(budget)))
+        });
+        let endpoint = request.route()
+            .map(|r| format!("{} {}", r.method, r.uri))
+            .unwrap_or_else(|| request.uri().path().to_string());
+        Deadline { start, budget, endpoint }
+    }
+
+    pub fn budget(&self) -> Duration {
+        self.budget
+    }
+
+    /// Route serving the request, used to group slow queries.
+    pub fn endpoint(&self) -> &str {
+        &self.endpoint
+    }
+
+    /// Time left before the deadline, or an error if it's already expired.
+    pub fn remaining(&self) -> Result<Duration, MongoError> {
+        self.budget
+            .checked_sub(self.start.elapsed())
+            .filter(|d| !d.is_zero())
+            .ok_or_else(exceeded)
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Deadline {
+    type Error = std::convert::Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(Self::of(request))
+    }
+}
+
+/// Handler of a route, abandoned once the deadline of the request is exceeded.
+#[derive(Clone)]
+struct Bounded(Box<dyn Handler>);
+
+#[rocket::async_trait]
+impl Handler for Bounded {
+    async fn handle<'r>(&self, request: &'r Request<'_>, data: Data<'r>) -> route::Outcome<'r> {
+        // asked for first here, so that the budget is counted from the start of the handler
+        let budget = Deadline::of(request).budget;
+        match tokio::time::timeout(budget + HANDLER_GRACE, self.0.handle(request, data)).await {
+            Ok(outcome) => outcome,
+            Err(_) => {
+                warn!("handler abandoned after exceeding its {:?} deadline", budget);
+                let error = ApiError::from(exceeded());
+                route::Outcome::from(request, ApiResponse::<()>::Error(error.into()))
+            }
+        }
+    }
+}
+
+/// `routes`, with their handlers abandoned once the deadline of the request is exceeded.
+pub fn bounded(routes: Vec<Route>) -> Vec<Route> {
+    routes.into_iter()
+        .map(|mut r| {
+            r.handler = Box::new(Bounded(r.handler));
+            r
+        })
+        .collect()
+}
+
+/// Error reported when the queries of a request exceed their deadline on the client side.
+pub fn exceeded() -> MongoError {
+    io::Error::new(io::ErrorKind::TimedOut, "query deadline exceeded").into()
+}
+
+/// Whether `error` was caused by a query exceeding its deadline, either on the server
+/// (`maxTimeMS`) or on the client side.
+pub fn is_exceeded(error: &MongoError) -> bool {
+    match *error.kind {
+        ErrorKind::Command(ref c) => c.code == MAX_TIME_MS_EXPIRED,
+        ErrorKind::Io(ref e) => e.kind() == io::ErrorKind::TimedOut,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::time::Duration;
+
+    use rocket::http::Header;
+    use rocket::local::asynchronous::Client;
+    use tokio::time::Instant;
+
+    use super::{exceeded, is_exceeded, Deadline, BUDGET_HEADER};
+    use crate::config::API_CONFIG;
+
+    #[rocket::async_test]
+    async fn budgets() {
+        let client = Client::untracked(rocket::build()).await.expect("valid rocket instance");
+        let budget = |path: &'static str, left: Option<&'static str>| {
+            let mut request = client.get(path);
+            if let Some(left) = left {
+                request.add_header(Header::new(BUDGET_HEADER, left));
+            }
+            Deadline::of(request.inner()).budget()
+        };
+        let trips = API_CONFIG.deadline_for("/api/v1/map/route/400/trips");
+        let default = Duration::from_millis(API_CONFIG.default_deadline_ms);
+
+        assert_eq!(budget("/api/v1/map/route/400/trips", None), trips);
+        assert_eq!(budget("/api/v1/map/area", None), default);
+        // the time left to a batch caps the budget, but never extends it
+        assert_eq!(budget("/api/v1/map/area", Some("120")), Duration::from_millis(120));
+        assert_eq!(budget("/api/v1/map/area", Some("0")), Duration::ZERO);
+        assert_eq!(budget("/api/v1/map/area", Some("999999999")), default);
+        assert_eq!(budget("/api/v1/map/area", Some("soon")), default);
+    }
+
+    #[test]
+    fn remaining() {
+        let deadline = |elapsed: u64| Deadline {
+            start: Instant::now() - Duration::from_millis(elapsed),
+            budget: Duration::from_secs(1),
+            endpoint: "GET /".to_owned(),
+        };
+        // the `maxTimeMS` of the queries is what's left of the budget
+        let left = deadline(400).remaining().expect("time left");
+        assert!(left <= Duration::from_millis(600) && left > Duration::from_millis(500), "{:?}", left);
+        for elapsed in [1000, 1500] {
+            let error = deadline(elapsed).remaining().expect_err("expired deadline");
+            assert!(is_exceeded(&error), "{}", error);
+        }
+    }
+
+    #[test]
+    fn exceeded_errors() {
+        assert!(is_exceeded(&exceeded()));
+        assert!(!is_exceeded(&io::Error::new(io::ErrorKind::ConnectionRefused, "refused").into()));
+    }
+}
@@ -0,0 +1,92 @@
+use bruss_config::CONFIGS;
+use chrono::{DateTime, Utc};
+use rocket::{Orbit, Request, Rocket};
+use rocket_db_pools::Database;
+use serde::Serialize;
+
+use crate::config::API_CONFIG;
+use crate::db::{self, BrussData};
+use crate::request_id::RequestId;
+use crate::routes::map::query::QueryTrace;
+
+/// Server side record of a server error, referenced in the response body by its `error_id`.
+#[derive(Serialize, Debug)]
+pub struct ErrorReport {
+    error_id: String,
+    request_id: String,
+    #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
+    time: DateTime<Utc>,
+    method: String,
+    uri: String,
+    status: u16,
+    code: String,
+    error: String,
+    backtrace: Option<String>,
+    /// Pipelines run while serving the request.
+    pipelines: Vec<String>,
+}
+
+impl ErrorReport {
+    pub fn new(request: &Request<'_>, status: u16, code: &str, error: String, backtrace: Option<String>) -> Self {
+        Self {
+            error_id: uuid::Uuid::new_v4().to_string(),
+            request_id: RequestId::of(request).to_string(),
+            time: Utc::now(),
+            method: request.method().to_string(),
+            uri: request.uri().to_string(),
+            status,
+            code: code.to_owned(),
+            error,
+            backtrace,
+            pipelines: QueryTrace::of(request).pipelines(),
+        }
+    }
+
+    pub fn error_id(&self) -> &str {
+        &self.error_id
+    }
+}
+
+/// Record `report` in the logs and, if `error_collection` is configured, in the database.
+///
+/// Storing is done in the background, so that responses aren't delayed by it.
+pub fn record(request: &Request<'_>, report: ErrorReport) {
+    error!("error {}: {} {} -> {} {}: {}\n  pipelines: {:?}\n  backtrace:\n{}",
+        report.error_id,
+        report.method,
+        report.uri,
+        report.status,
+        report.code,
+        report.error,
+        report.pipelines,
+        report.backtrace.as_deref().unwrap_or("<not captured>"),
+    );
+
+    let coll = match API_CONFIG.error_collection {
+        Some(ref c) => c,
+        None => return,
+    };
+    let client = match BrussData::fetch(request.rocket()) {
+        Some(db) => (**db).clone(),
+        None => {
+            warn!("database not available, error {} not stored", report.error_id);
+            return;
+        }
+    };
+    tokio::spawn(async move {
+        let r = client.database(CONFIGS.db.get_db())
+            .collection::<ErrorReport>(coll)
+            .insert_one(&report, None)
+            .await;
+        if let Err(e) = r {
+            warn!("cannot store error {}: {}", report.error_id, e);
+        }
+    }.in_current_span());
+}
+
+/// Create the capped collection for error reports, if configured and not already existing.
+pub async fn create_collection(rocket: &Rocket<Orbit>) {
+    if let Some(ref coll) = API_CONFIG.error_collection {
+        db::create_capped_collection(rocket, coll, API_CONFIG.error_collection_size, "error reports").await;
+    }
+}
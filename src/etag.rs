@@ -0,0 +1,164 @@
+use std::io::Cursor;
+
+use chrono::{DateTime, Utc};
+use rocket::http::{Header, Method, Status};
+use rocket::{Request, Response};
+use rocket::fairing::{Fairing, Info, Kind};
+use sha2::{Digest, Sha256};
+
+use crate::query_cache::QueryCache;
+
+/// Mount point of the static map data: responses under it only change on data imports, so they
+/// can be safely revalidated by the client.
+const CACHEABLE_PREFIX: &str = "/api/v1/map";
+
+/// Mount points of the collections whose imports are dated by the dataset watcher, see
+/// [`QueryCache::last_modified`].
+const DATED_PREFIXES: [&str; 4] = ["/api/v1/map/area", "/api/v1/map/route", "/api/v1/map/stop", "/api/v1/map/search"];
+
+/// Add a strong `ETag` to successful responses of static data, and a `Last-Modified` set to the
+/// import time of the dataset when known, and answer `If-None-Match` (or, without it,
+/// `If-Modified-Since`) with `304 Not Modified` when the client already holds the current
+/// representation.
+pub struct ETag;
+
+impl ETag {
+    /// Whether the response to a request for `path` only changes along with the collections
+    /// dated by the dataset watcher: the departures of routes and stops depend on the time they
+    /// are asked at, and the routes of a stop on its trips, so they are only tagged.
+    fn dated(path: &str) -> bool {
+        let path = path.trim_end_matches('/');
+        let mounted = DATED_PREFIXES.iter()
+            .any(|p| path.strip_prefix(p).is_some_and(|rest| rest.is_empty() || rest.starts_with('/')));
+        mounted && !path.ends_with("/trips") && !path.ends_with("/routes")
+    }
+
+    fn applies_to(request: &Request<'_>, response: &Response<'_>) -> bool {
+        request.method() == Method::Get
+            && response.status() == Status::Ok
+            && request.uri().path().as_str().starts_with(CACHEABLE_PREFIX)
+            // streamed bodies are never buffered just to be hashed
+            && response.body().preset_size().is_some()
+    }
+
+    fn tag(body: &[u8]) -> String {
+        let digest = Sha256::digest(body);
+        let hex = digest.iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<String>();
+        format!("\"{}\"", hex)
+    }
+
+    /// Weak comparison of `If-None-Match` entity tags, as mandated by RFC 9110.
+    fn matches(if_none_match: &str, tag: &str) -> bool {
+        if_none_match.split(',')
+            .map(str::trim)
+            .any(|t| t == "*" || t.trim_start_matches("W/") == tag)
+    }
+
+    /// Whether a representation last modified at `modified` is not newer than the one held by a
+    /// client sending `if_modified_since`. HTTP dates have a one second resolution.
+    fn unmodified_since(if_modified_since: &str, modified: DateTime<Utc>) -> bool {
+        DateTime::parse_from_rfc2822(if_modified_since.trim())
+            .is_ok_and(|since| modified.timestamp() <= since.timestamp())
+    }
+
+    /// `modified` as an HTTP date (RFC 9110 IMF-fixdate).
+    fn http_date(modified: DateTime<Utc>) -> String {
+        modified.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+    }
+}
+
+#[rocket::async_trait]
+impl Fairing for ETag {
+    fn info(&self) -> Info {
+        Info {
+            name: "Add ETag to static data responses",
+            kind: Kind::Response
+        }
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        if !Self::applies_to(request, response) {
+            return;
+        }
+        let body = match response.body_mut().to_bytes().await {
+            Ok(b) => b,
+            Err(e) => {
+                error!("failed to read response body for etag generation: {}", e);
+                return;
+            }
+        };
+        let tag = Self::tag(&body);
+        let modified = request.rocket().state::<QueryCache>()
+            .filter(|_| Self::dated(request.uri().path().as_str()))
+            .and_then(QueryCache::last_modified);
+        // `If-Modified-Since` is only evaluated without `If-None-Match`, as mandated by RFC 9110
+        let not_modified = match request.headers().get_one("If-None-Match") {
+            Some(_) => request.headers()
+                .get("If-None-Match")
+                .any(|h| Self::matches(h, &tag)),
+            None => match (request.headers().get_one("If-Modified-Since"), modified) {
+                (Some(since), Some(modified)) => Self::unmodified_since(since, modified),
+                _ => false,
+            },
+        };
+
+        if not_modified {
+            response.set_status(Status::NotModified);
+            response.body_mut().take();
+        } else {
+            response.set_sized_body(body.len(), Cursor::new(body));
+        }
+        response.set_header(Header::new("ETag", tag));
+        if let Some(modified) = modified {
+            response.set_header(Header::new("Last-Modified", Self::http_date(modified)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    #[test]
+    fn if_none_match() {
+        let tag = "\"abc\"";
+        assert!(ETag::matches("\"abc\"", tag));
+        assert!(ETag::matches("W/\"abc\"", tag));
+        assert!(ETag::matches("*", tag));
+        assert!(ETag::matches("\"xyz\", W/\"abc\"", tag));
+        assert!(ETag::matches("\"xyz\",\"abc\"", tag));
+        assert!(!ETag::matches("\"xyz\"", tag));
+        assert!(!ETag::matches("abc", tag));
+        assert!(!ETag::matches("", tag));
+    }
+
+    #[test]
+    fn if_modified_since() {
+        let modified = Utc.with_ymd_and_hms(2024, 3, 1, 12, 0, 0).unwrap();
+        assert_eq!(ETag::http_date(modified), "Fri, 01 Mar 2024 12:00:00 GMT");
+        assert!(ETag::unmodified_since("Fri, 01 Mar 2024 12:00:00 GMT", modified));
+        assert!(ETag::unmodified_since("Sat, 02 Mar 2024 08:00:00 GMT", modified));
+        assert!(!ETag::unmodified_since("Fri, 01 Mar 2024 11:59:59 GMT", modified));
+        assert!(!ETag::unmodified_since("yesterday", modified));
+    }
+
+    #[test]
+    fn dated_paths() {
+        assert!(ETag::dated("/api/v1/map/area"));
+        assert!(ETag::dated("/api/v1/map/area/1"));
+        assert!(ETag::dated("/api/v1/map/stop/u/1"));
+        assert!(ETag::dated("/api/v1/map/stop/near"));
+        assert!(ETag::dated("/api/v1/map/search/"));
+        assert!(!ETag::dated("/api/v1/map/route/400/trips"));
+        assert!(!ETag::dated("/api/v1/map/stop/u/1/trips"));
+        assert!(!ETag::dated("/api/v1/map/stop/u/1/routes"));
+        assert!(!ETag::dated("/api/v1/map/path/p1"));
+        assert!(!ETag::dated("/api/v1/map/segment/u/1-2"));
+        assert!(!ETag::dated("/api/v1/map/trip/t1"));
+        assert!(!ETag::dated("/api/v1/map/areas"));
+    }
+}
@@ -0,0 +1,143 @@
+use std::io::Cursor;
+
+use rocket::http::{Accept, ContentType, Header, MediaType, Status};
+use rocket::{Request, Response};
+use serde::Serialize;
+
+/// Serialization format of response bodies, negotiated through the `Accept` header.
+///
+/// Every format uses the same serde shapes, so a body decoded from MessagePack or CBOR has the
+/// same structure as its JSON counterpart.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Json,
+    MsgPack,
+    Cbor,
+}
+
+impl Format {
+    /// Pick the format preferred by the client, falling back to JSON when the `Accept` header
+    /// is missing or doesn't name any supported format: we'd rather answer than send a 406.
+    pub fn negotiate(request: &Request) -> Self {
+        Self::from_accept(request.accept())
+    }
+
+    fn from_accept(accept: Option<&Accept>) -> Self {
+        let accept = match accept {
+            Some(a) => a,
+            None => return Self::Json,
+        };
+        let mut types = accept.iter()
+            // `q=0` marks a type as not acceptable
+            .filter(|t| t.weight_or(1.0) > 0.0)
+            .collect::<Vec<_>>();
+        // stable sort: equally weighted types keep the order in which the client sent them
+        types.sort_by(|a, b| b.weight_or(1.0).total_cmp(&a.weight_or(1.0)));
+        types.into_iter()
+            .find_map(|t| Self::from_media_type(t.media_type()))
+            .unwrap_or(Self::Json)
+    }
+
+    /// Format named by `media_type`. Wildcards resolve to JSON.
+    fn from_media_type(media_type: &MediaType) -> Option<Self> {
+        if media_type.top().as_str() == "*" {
+            return Some(Self::Json);
+        }
+        if !media_type.top().as_str().eq_ignore_ascii_case("application") {
+            return None;
+        }
+        let sub = media_type.sub().as_str().to_ascii_lowercase();
+        if sub == "msgpack" || sub == "x-msgpack" {
+            Some(Self::MsgPack)
+        } else if sub == "cbor" {
+            Some(Self::Cbor)
+        } else if sub == "json" || sub == "*" || sub.ends_with("+json") {
+            Some(Self::Json)
+        } else {
+            None
+        }
+    }
+
+    /// Content type of a body in this format. `json` is used for JSON bodies, so that error
+    /// documents can be served as `application/problem+json`.
+    fn content_type(self, json: ContentType) -> ContentType {
+        match self {
+            Self::Json => json,
+            Self::MsgPack => ContentType::MsgPack,
+            Self::Cbor => ContentType::new("application", "cbor"),
+        }
+    }
+
+    fn serialize<T: Serialize>(self, value: &T) -> Result<Vec<u8>, String> {
+        match self {
+            Self::Json => serde_json::to_vec(value).map_err(|e| e.to_string()),
+            Self::MsgPack => rmp_serde::to_vec_named(value).map_err(|e| e.to_string()),
+            Self::Cbor => {
+                let mut buf = Vec::new();
+                ciborium::into_writer(value, &mut buf).map_err(|e| e.to_string())?;
+                Ok(buf)
+            }
+        }
+    }
+
+    /// Serialize `value` into a response body, using `json` as content type for JSON bodies.
+    pub fn respond<'o, T: Serialize>(self, value: &T, json: ContentType) -> rocket::response::Result<'o> {
+        let body = self.serialize(value)
+            .map_err(|e| {
+                error!("failed to serialize response body as {:?}: {}", self, e);
+                Status::InternalServerError
+            })?;
+        Response::build()
+            .header(self.content_type(json))
+            .header(Header::new("Vary", "Accept"))
+            .sized_body(body.len(), Cursor::new(body))
+            .ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use rocket::http::Accept;
+
+    use super::Format::{self, *};
+
+    fn negotiate(accept: &str) -> Format {
+        Format::from_accept(Some(&Accept::from_str(accept).expect("valid Accept header")))
+    }
+
+    #[test]
+    fn negotiate_formats() {
+        assert_eq!(Format::from_accept(None), Json);
+        assert_eq!(negotiate("application/json"), Json);
+        assert_eq!(negotiate("application/problem+json"), Json);
+        assert_eq!(negotiate("application/msgpack"), MsgPack);
+        assert_eq!(negotiate("application/x-msgpack"), MsgPack);
+        assert_eq!(negotiate("Application/CBOR"), Cbor);
+        // weights first, then the order of the header
+        assert_eq!(negotiate("application/json;q=0.5, application/cbor"), Cbor);
+        assert_eq!(negotiate("application/cbor, application/msgpack"), Cbor);
+        assert_eq!(negotiate("application/msgpack, application/cbor"), MsgPack);
+    }
+
+    #[test]
+    fn negotiate_wildcards() {
+        assert_eq!(negotiate("*/*"), Json);
+        assert_eq!(negotiate("application/*"), Json);
+        assert_eq!(negotiate("application/cbor;q=0.9, */*;q=0.1"), Cbor);
+        assert_eq!(negotiate("application/cbor;q=0.1, */*"), Json);
+        assert_eq!(negotiate("text/*, application/msgpack;q=0.2"), MsgPack);
+    }
+
+    #[test]
+    fn negotiate_unacceptable() {
+        // refused types are never picked, not even as the last resort
+        assert_eq!(negotiate("application/msgpack;q=0, application/json;q=0.1"), Json);
+        assert_eq!(negotiate("application/cbor;q=0, */*;q=0.5, application/msgpack;q=0.1"), Json);
+        // nothing we can serve: JSON rather than a 406
+        assert_eq!(negotiate("text/html"), Json);
+        assert_eq!(negotiate("application/cbor;q=0"), Json);
+        assert_eq!(negotiate("image/png, text/*;q=0.5"), Json);
+    }
+}
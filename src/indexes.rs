@@ -0,0 +1,192 @@
+use bruss_config::CONFIGS;
+use bruss_data::{Path, Segment};
+use futures::TryStreamExt;
+use mongodb::bson::{Bson, Document};
+use mongodb::error::{Error as MongoError, ErrorKind};
+use mongodb::{Database, IndexModel};
+use rocket::fairing;
+use rocket::{Build, Rocket};
+use rocket_db_pools::Database as _;
+use serde::Deserialize;
+
+use crate::config::API_CONFIG;
+use crate::db::BrussData;
+use crate::routes::map::{area::AreaQuery, route::RouteQuery, stop::StopQuery, trip::{MultiTripQuery, TripQuerySingle}};
+use crate::routes::tracking::TripUpdate;
+
+/// Code of the server error returned when listing the indexes of a missing collection.
+const NAMESPACE_NOT_FOUND: i32 = 26;
+
+/// What to do with the indexes declared by [`Indexed`] types at startup, configured through
+/// [`ApiConfig::index_mode`](crate::config::ApiConfig).
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum IndexMode {
+    /// Create the missing indexes.
+    #[default]
+    Create,
+    /// Fail startup if any index is missing, for deployments where indexes are managed
+    /// separately.
+    Verify,
+    /// Don't check indexes at all.
+    Off,
+}
+
+/// Queries (or, for collections looked up directly, their types) which need indexes on their
+/// collection to run efficiently.
+pub trait Indexed {
+    /// Collection the indexes are on.
+    fn collection() -> String;
+
+    /// Indexes backing the filters and sorts of the query.
+    fn indexes() -> Vec<IndexModel>;
+}
+
+/// Indexes declared by every query, grouped by collection.
+fn declared() -> Vec<(String, Vec<IndexModel>)> {
+    fn of<I: Indexed>() -> (String, Vec<IndexModel>) {
+        (I::collection(), I::indexes())
+    }
+
+    vec![
+        of::<AreaQuery>(),
+        of::<RouteQuery>(),
+        of::<StopQuery>(),
+        of::<TripQuerySingle>(),
+        of::<MultiTripQuery>(),
+        of::<Segment>(),
+        of::<Path>(),
+        of::<TripUpdate>(),
+    ]
+}
+
+/// Name of `index`, or its keys if it isn't explicitly named, for logging.
+fn describe(index: &IndexModel) -> String {
+    match index.options.as_ref().and_then(|o| o.name.as_ref()) {
+        Some(name) => name.clone(),
+        None => index.keys.to_string(),
+    }
+}
+
+/// Whether two index key values are the same: the direction of an index created as `1` is
+/// reported back as `1.0` by some tools, so numbers are compared by sign.
+fn same_key(a: &Bson, b: &Bson) -> bool {
+    fn direction(v: &Bson) -> Option<bool> {
+        match *v {
+            Bson::Int32(i) => Some(i > 0),
+            Bson::Int64(i) => Some(i > 0),
+            Bson::Double(d) => Some(d > 0.),
+            _ => None,
+        }
+    }
+    match (direction(a), direction(b)) {
+        (Some(a), Some(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+/// Whether `existing` satisfies the `declared` index: either it has the same name, or the same
+/// keys in the same order.
+fn satisfies(existing: &IndexModel, declared: &IndexModel) -> bool {
+    let name = |i: &IndexModel| i.options.as_ref().and_then(|o| o.name.clone());
+    if let (Some(a), Some(b)) = (name(existing), name(declared)) {
+        return a == b;
+    }
+    existing.keys.len() == declared.keys.len()
+        && existing.keys.iter().zip(declared.keys.iter()).all(|((ka, va), (kb, vb))| ka == kb && same_key(va, vb))
+}
+
+async fn existing_indexes(db: &Database, collection: &str) -> Result<Vec<IndexModel>, MongoError> {
+    match db.collection::<Document>(collection).list_indexes(None).await {
+        Ok(cursor) => cursor.try_collect().await,
+        // the collection will be created along with its first index
+        Err(e) if matches!(*e.kind, ErrorKind::Command(ref c) if c.code == NAMESPACE_NOT_FOUND) => Ok(Vec::new()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Make sure the indexes declared by every [`Indexed`] query exist, creating the missing ones or,
+/// in [`IndexMode::Verify`], failing startup if any is missing. Documents the indexes can't
+/// handle yet, as trip updates with an integer `updated` time, are migrated first or, in
+/// [`IndexMode::Verify`], which never writes, fail startup as well.
+///
+/// Runs on ignite, after the database pool is initialized.
+pub async fn provision(rocket: Rocket<Build>) -> fairing::Result {
+    let mode = API_CONFIG.index_mode;
+    if mode == IndexMode::Off {
+        return Ok(rocket);
+    }
+    let db = match BrussData::fetch(&rocket) {
+        Some(db) => db.database(CONFIGS.db.get_db()),
+        None => return Ok(rocket),
+    };
+
+    // before its TTL index exists, which only expires dates
+    let mut legacy = false;
+    match mode {
+        IndexMode::Verify => match TripUpdate::count_legacy(&db).await {
+            Ok(0) => {}
+            Ok(n) => {
+                error!("{} legacy trip updates have an integer `updated` time, which won't expire", n);
+                legacy = true;
+            }
+            Err(e) => {
+                error!("cannot count the legacy trip updates: {}", e);
+                return Err(rocket);
+            }
+        },
+        _ => match TripUpdate::convert_legacy(&db).await {
+            Ok(0) => {}
+            Ok(n) => info!("converted the `updated` time of {} legacy trip updates to a date", n),
+            Err(e) => error!("cannot convert the legacy trip updates, they won't expire: {}", e),
+        },
+    }
+
+    let mut missing = Vec::new();
+    for (collection, indexes) in declared() {
+        let existing = match existing_indexes(&db, &collection).await {
+            Ok(e) => e,
+            Err(e) => {
+                error!("cannot list the indexes of `{}`: {}", collection, e);
+                if mode == IndexMode::Verify {
+                    return Err(rocket);
+                }
+                continue;
+            }
+        };
+        for index in indexes {
+            let wanted_ttl = index.options.as_ref().and_then(|o| o.expire_after);
+            match existing.iter().find(|e| satisfies(e, &index)) {
+                Some(e) => {
+                    let ttl = e.options.as_ref().and_then(|o| o.expire_after);
+                    if ttl != wanted_ttl {
+                        warn!("index {} on `{}` expires documents after {:?} instead of {:?}: drop it to have it recreated",
+                            describe(&index), collection, ttl, wanted_ttl);
+                    }
+                }
+                None => missing.push((collection.clone(), index)),
+            }
+        }
+    }
+
+    if missing.is_empty() {
+        info!("all the required indexes exist");
+        return if legacy { Err(rocket) } else { Ok(rocket) };
+    }
+
+    if mode == IndexMode::Verify {
+        for (collection, index) in missing.iter() {
+            error!("missing index {} on `{}`", describe(index), collection);
+        }
+        return Err(rocket);
+    }
+
+    for (collection, index) in missing {
+        let description = describe(&index);
+        match db.collection::<Document>(&collection).create_index(index, None).await {
+            Ok(r) => info!("created index `{}` on `{}`", r.index_name, collection),
+            Err(e) => error!("cannot create index {} on `{}`: {}", description, collection, e),
+        }
+    }
+    Ok(rocket)
+}
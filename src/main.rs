@@ -4,12 +4,30 @@
 extern crate rocket;
 
 use rocket::fairing::AdHoc;
+use rocket::{Build, Rocket, Route};
+use crate::batch::Sharing;
+use crate::config::API_CONFIG;
 use crate::db::BrussData;
+use crate::storage::MemoryStore;
 use rocket_db_pools::Database;
 
 mod routes;
+mod batch;
 mod db;
 mod cors;
+mod cache;
+mod compression;
+mod config;
+mod deadline;
+mod error_registry;
+mod etag;
+mod format;
+mod indexes;
+mod upstream;
+mod query_cache;
+mod request_id;
+mod slow_queries;
+mod storage;
 #[cfg(test)]
 mod tests;
 mod response;
@@ -25,16 +43,23 @@ fn welcome_api() -> &'static str {
     "Welcome to the Bruss API!"
 }
 
-#[launch]
-fn rocket() -> _ {
+/// `routes`, with their handlers run within the span and the deadline of the request.
+fn api(routes: Vec<Route>) -> Vec<Route> {
+    request_id::traced(deadline::bounded(routes))
+}
+
+/// The API, without any storage nor background task: queries are run on MongoDB once
+/// [`BrussData`] is attached, or on the [`MemoryStore`] managed by Rocket, if any.
+fn app() -> Rocket<Build> {
     rocket::build()
-        .mount("/", routes![welcome_api, welcome_app])
-        .mount("/api/v1/map/area", routes::map::area::ROUTES.clone())
-        .mount("/api/v1/map/route", routes::map::route::ROUTES.clone())
-        .mount("/api/v1/map/stop", routes::map::stop::ROUTES.clone())
-        .mount("/api/v1/map/path", routes::map::path::ROUTES.clone())
-        .mount("/api/v1/map/segment", routes::map::segment::ROUTES.clone())
-        .mount("/api/v1/map/trip", routes::map::trip::ROUTES.clone())
+        .mount("/", api(routes![welcome_api, welcome_app]))
+        .mount("/api/v1/map/area", api(routes::map::area::ROUTES.clone()))
+        .mount("/api/v1/map/route", api(routes::map::route::ROUTES.clone()))
+        .mount("/api/v1/map/stop", api(routes::map::stop::ROUTES.clone()))
+        .mount("/api/v1/map/path", api(routes::map::path::ROUTES.clone()))
+        .mount("/api/v1/map/segment", api(routes::map::segment::ROUTES.clone()))
+        .mount("/api/v1/map/trip", api(routes::map::trip::ROUTES.clone()))
+        .mount("/api/v1/map/search", api(routes::map::search::ROUTES.clone()))
             // routes::map::,
             // routes::map::get_route_opt,
             // routes::map::get_segments,
@@ -43,16 +68,46 @@ fn rocket() -> _ {
             // routes::map::get_trips_route,
             // routes::map::get_trips_stop,
             // routes::map::get_path,
-        .mount("/api/v1/map", routes![routes::options])
-        .mount("/api/v1/tracking/", routes::tracking::ROUTES.clone())
-        .register("/api/v1/", catchers![
+        .mount("/api/v1/map", api(routes![routes::options]))
+        .mount("/api/v1/tracking/", api(routes::tracking::ROUTES.clone()))
+        .mount("/api/v1/cache", api(query_cache::ROUTES.clone()))
+        .mount("/api/v1/batch", api(batch::ROUTES.clone()))
+        .mount("/api/v1/graphql", api(routes::graphql::ROUTES.clone()))
+        .register("/api/v1/", request_id::traced_catchers(catchers![
             response::api_catch_default,
             response::api_catch_404,
-        ])
-        .attach(AdHoc::on_ignite("Database connect", |rocket| async {
-            rocket.attach(BrussData::init())
-            // .attach(AdHoc::try_on_ignite("Database migrate", migrate))
-        }))
+        ]))
+        .manage(batch::BatchClient::default())
+        .manage(routes::graphql::schema())
+        .attach(request_id::RequestIdFairing)
         .attach(cors::CORS)
+        .attach(cache::CacheControl)
+        // compress before hashing, so that every encoding gets its own entity tag
+        .attach(compression::Compression)
+        .attach(etag::ETag)
+}
+
+#[launch]
+fn rocket() -> _ {
+    request_id::init_logging();
+    let rocket = app()
+        .share(query_cache::QueryCache::default())
+        .attach(AdHoc::on_liftoff("Dataset watcher", |rocket| Box::pin(query_cache::watch_dataset(rocket))))
+        .attach(AdHoc::on_liftoff("Error registry", |rocket| Box::pin(error_registry::create_collection(rocket))))
+        .attach(AdHoc::on_liftoff("Slow query log", |rocket| Box::pin(slow_queries::create_collection(rocket))));
+    match API_CONFIG.fixtures {
+        Some(ref dir) => {
+            let store = MemoryStore::from_fixtures(dir).unwrap_or_else(|e| panic!("{}", e));
+            warn!("serving the fixtures in {} instead of the database", dir);
+            rocket.share(store)
+        }
+        None => rocket
+            .attach(AdHoc::on_ignite("Database connect", |rocket| async {
+                rocket.attach(BrussData::init())
+                    .share_with(db::share)
+                    .attach(AdHoc::try_on_ignite("Database indexes", indexes::provision))
+                // .attach(AdHoc::try_on_ignite("Database migrate", migrate))
+            })),
+    }
 }
 
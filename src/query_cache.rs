@@ -0,0 +1,314 @@
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use bruss_config::CONFIGS;
+use bruss_data::{Area, BrussType, Route, Stop};
+use chrono::{DateTime, Utc};
+use lazy_static::lazy_static;
+use lru::LruCache;
+use mongodb::bson::{doc, Bson, Document};
+use mongodb::options::FindOneOptions;
+use mongodb::Database;
+use rocket::{Orbit, Rocket};
+use rocket_db_pools::Database as _;
+use serde::Serialize;
+use tokio::time::Instant;
+
+use crate::config::API_CONFIG;
+use crate::db::BrussData;
+use crate::response::{ApiError, ApiResponse};
+use crate::routes::map::pipeline::BuiltPipeline;
+
+/// Documents returned by a query, along with their total count if computed.
+pub type CachedResult = Arc<(Vec<Document>, Option<usize>)>;
+
+/// Whether the queries on the collection of `X` are cached: only the small collections which
+/// only change when a new dataset is imported are, unless caching is disabled with a zero
+/// `cache_ttl`.
+pub fn is_cached<X: BrussType>() -> bool {
+    API_CONFIG.cache_ttl > 0 && [Area::TYPE.collection(), Route::TYPE.collection(), Stop::TYPE.collection()]
+        .iter()
+        .any(|c| c == &X::TYPE.collection())
+}
+
+struct Entry {
+    result: CachedResult,
+    inserted: Instant,
+}
+
+/// Version of the dataset: size and newest document of each cached collection. Imports insert
+/// new documents, so that any of them changes the version.
+#[derive(PartialEq, Debug, Clone, Serialize)]
+pub(crate) struct DatasetVersion {
+    pub(crate) collections: Vec<(String, u64, Option<String>)>,
+    /// Creation time of the newest document, taken from its `ObjectId`: the time of the import.
+    pub(crate) imported_at: Option<DateTime<Utc>>,
+}
+
+/// In-process cache of the results of the queries on static collections, managed as Rocket
+/// state.
+///
+/// Results are keyed by the whole pipeline they come from, so that filters, sorting and paging
+/// give exactly the same results as the database would. Entries expire after `cache_ttl`
+/// seconds, the least recently used ones are evicted past `cache_max_entries`, and the whole
+/// cache is dropped when a new dataset version is detected.
+#[derive(Clone, Default)]
+pub struct QueryCache(Arc<Inner>);
+
+struct Inner {
+    entries: Mutex<LruCache<String, Entry>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    invalidations: AtomicU64,
+    version: Mutex<Option<DatasetVersion>>,
+    modified_at: Mutex<Option<DateTime<Utc>>>,
+    invalidated_at: Mutex<Option<DateTime<Utc>>>,
+}
+
+impl Default for Inner {
+    fn default() -> Self {
+        Self::with_capacity(NonZeroUsize::new(API_CONFIG.cache_max_entries).unwrap_or(NonZeroUsize::MIN))
+    }
+}
+
+impl Inner {
+    fn with_capacity(capacity: NonZeroUsize) -> Self {
+        Inner {
+            entries: Mutex::new(LruCache::new(capacity)),
+            hits: AtomicU64::default(),
+            misses: AtomicU64::default(),
+            invalidations: AtomicU64::default(),
+            version: Mutex::default(),
+            modified_at: Mutex::default(),
+            invalidated_at: Mutex::default(),
+        }
+    }
+}
+
+impl QueryCache {
+    /// Key of the results of `pipeline` on the collection of `X`.
+    pub fn key<X: BrussType>(pipeline: &BuiltPipeline) -> String {
+        format!("{}|{:?}|{:?}|{:?}|{:?}|{:?}",
+            X::TYPE.collection(), pipeline.fetch, pipeline.count, pipeline.collation, pipeline.count_mode, pipeline.projection)
+    }
+
+    /// Key of the result of a single document lookup on the collection of `X`.
+    pub fn key_single<X: BrussType>(query: &Document, projection: &Option<Document>) -> String {
+        format!("{}|find_one|{:?}|{:?}", X::TYPE.collection(), query, projection)
+    }
+
+    pub fn get(&self, key: &str) -> Option<CachedResult> {
+        self.get_within(key, Duration::from_secs(API_CONFIG.cache_ttl))
+    }
+
+    /// Result cached under `key` less than `ttl` ago, if any.
+    fn get_within(&self, key: &str, ttl: Duration) -> Option<CachedResult> {
+        let hit = self.0.entries.lock().ok().and_then(|mut entries| {
+            let hit = entries.get(key)
+                .filter(|e| e.inserted.elapsed() < ttl)
+                .map(|e| e.result.clone());
+            if hit.is_none() {
+                // expired entries are dropped, rather than evicted later in place of fresh ones
+                entries.pop(key);
+            }
+            hit
+        });
+        match hit {
+            Some(_) => self.0.hits.fetch_add(1, Ordering::Relaxed),
+            None => self.0.misses.fetch_add(1, Ordering::Relaxed),
+        };
+        hit
+    }
+
+    /// Cache `result` under `key`, evicting the least recently used entry if the cache is full.
+    pub fn insert(&self, key: String, result: CachedResult) {
+        if let Ok(mut entries) = self.0.entries.lock() {
+            entries.put(key, Entry { result, inserted: Instant::now() });
+        }
+    }
+
+    pub fn clear(&self) {
+        if let Ok(mut entries) = self.0.entries.lock() {
+            entries.clear();
+        }
+        self.0.invalidations.fetch_add(1, Ordering::Relaxed);
+        if let Ok(mut at) = self.0.invalidated_at.lock() {
+            *at = Some(Utc::now());
+        }
+    }
+
+    /// Record the current dataset `version`, dropping the cache if it changed.
+    pub(crate) fn update_version(&self, version: DatasetVersion) {
+        let Ok(mut current) = self.0.version.lock() else { return };
+        let changed = match *current {
+            Some(ref v) if *v != version => {
+                info!("new dataset version detected, dropping the query cache: {:?}", version);
+                self.clear();
+                true
+            }
+            Some(_) => false,
+            None => true,
+        };
+        if changed {
+            if let Ok(mut at) = self.0.modified_at.lock() {
+                // datasets without `ObjectId`s are dated when they are first seen
+                *at = Some(version.imported_at.unwrap_or_else(Utc::now));
+            }
+        }
+        *current = Some(version);
+    }
+
+    /// Time the current dataset was imported at, once its version has been checked.
+    pub fn last_modified(&self) -> Option<DateTime<Utc>> {
+        *self.0.modified_at.lock().ok()?
+    }
+
+    fn stats(&self) -> CacheStats {
+        CacheStats {
+            entries: self.0.entries.lock().map(|e| e.len()).unwrap_or(0),
+            hits: self.0.hits.load(Ordering::Relaxed),
+            misses: self.0.misses.load(Ordering::Relaxed),
+            invalidations: self.0.invalidations.load(Ordering::Relaxed),
+            last_invalidation: self.0.invalidated_at.lock().ok().and_then(|a| *a),
+            version: self.0.version.lock().ok().and_then(|v| v.clone()),
+        }
+    }
+}
+
+async fn dataset_version(db: &Database) -> Result<DatasetVersion, mongodb::error::Error> {
+    let mut collections = Vec::new();
+    let mut imported_at = None;
+    for coll in [Area::TYPE.collection(), Route::TYPE.collection(), Stop::TYPE.collection()] {
+        let coll = db.collection::<Document>(&coll);
+        let count = coll.estimated_document_count(None).await?;
+        let options = FindOneOptions::builder()
+            .sort(doc!{"_id": -1})
+            .projection(doc!{"_id": 1})
+            .build();
+        let newest = coll.find_one(None, options).await?
+            .and_then(|d| d.get("_id").cloned());
+        if let Some(Bson::ObjectId(id)) = newest {
+            imported_at = imported_at.max(Some(id.timestamp().to_chrono()));
+        }
+        collections.push((coll.name().to_owned(), count, newest.as_ref().map(Bson::to_string)));
+    }
+    Ok(DatasetVersion { collections, imported_at })
+}
+
+/// Periodically check the dataset version, every `cache_check_interval` seconds, to drop the
+/// cache as soon as a new dataset is imported.
+pub async fn watch_dataset(rocket: &Rocket<Orbit>) {
+    let (Some(cache), Some(db)) = (rocket.state::<QueryCache>(), BrussData::fetch(rocket)) else { return };
+    let cache = cache.clone();
+    let db = db.database(CONFIGS.db.get_db());
+    let interval = Duration::from_secs(API_CONFIG.cache_check_interval.max(1));
+    tokio::spawn(async move {
+        loop {
+            match dataset_version(&db).await {
+                Ok(v) => cache.update_version(v),
+                Err(e) => warn!("cannot check the dataset version: {}", e),
+            }
+            tokio::time::sleep(interval).await;
+        }
+    });
+}
+
+#[derive(Serialize)]
+pub struct CacheStats {
+    entries: usize,
+    hits: u64,
+    misses: u64,
+    invalidations: u64,
+    last_invalidation: Option<DateTime<Utc>>,
+    version: Option<DatasetVersion>,
+}
+
+/// Hit and miss counts of the query cache, if the running instance has one. Like query plans,
+/// they are only exposed in debug builds, or when enabled by the `explain` setting.
+#[get("/stats")]
+fn get_stats(rocket: &Rocket<Orbit>) -> ApiResponse<CacheStats> {
+    if !(cfg!(debug_assertions) || API_CONFIG.explain) {
+        return ApiError::NotFound.respond();
+    }
+    match rocket.state::<QueryCache>() {
+        Some(cache) => ApiResponse::Ok(cache.stats(), None),
+        None => ApiError::NotFound.respond(),
+    }
+}
+
+lazy_static!{
+    pub static ref ROUTES: Vec<rocket::Route> = routes![get_stats];
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn result(n: i32) -> CachedResult {
+        Arc::new((vec![doc!{"id": n}], Some(1)))
+    }
+
+    fn version(imported_at: Option<DateTime<Utc>>, count: u64) -> DatasetVersion {
+        DatasetVersion { collections: vec![("stops".to_owned(), count, None)], imported_at }
+    }
+
+    #[test]
+    fn hits_and_misses() {
+        let cache = QueryCache::default();
+        assert!(cache.get("a").is_none());
+        cache.insert("a".to_owned(), result(1));
+        assert_eq!(cache.get("a").map(|r| r.0.clone()), Some(vec![doc!{"id": 1}]));
+        let stats = cache.stats();
+        assert_eq!((stats.entries, stats.hits, stats.misses), (1, 1, 1));
+    }
+
+    #[test]
+    fn evicts_the_least_recently_used() {
+        let cache = QueryCache(Arc::new(Inner::with_capacity(NonZeroUsize::new(2).unwrap())));
+        cache.insert("a".to_owned(), result(1));
+        cache.insert("b".to_owned(), result(2));
+        // `a` is now more recently used than `b`
+        assert!(cache.get("a").is_some());
+        cache.insert("c".to_owned(), result(3));
+        assert!(cache.get("b").is_none());
+        assert!(cache.get("a").is_some());
+        assert!(cache.get("c").is_some());
+        assert_eq!(cache.stats().entries, 2);
+    }
+
+    #[test]
+    fn expires_entries() {
+        let cache = QueryCache::default();
+        cache.insert("a".to_owned(), result(1));
+        assert!(cache.get_within("a", Duration::from_secs(60)).is_some());
+        assert!(cache.get_within("a", Duration::ZERO).is_none());
+        // expired entries are dropped on lookup
+        assert_eq!(cache.stats().entries, 0);
+        assert!(cache.get_within("a", Duration::from_secs(60)).is_none());
+    }
+
+    #[test]
+    fn invalidates_on_new_datasets() {
+        let cache = QueryCache::default();
+        let imported_at = Utc::now() - chrono::TimeDelta::days(1);
+        cache.insert("a".to_owned(), result(1));
+
+        // the first version seen only dates the dataset
+        cache.update_version(version(Some(imported_at), 10));
+        assert!(cache.get("a").is_some());
+        assert_eq!(cache.last_modified(), Some(imported_at));
+        cache.update_version(version(Some(imported_at), 10));
+        assert!(cache.get("a").is_some());
+        assert_eq!(cache.stats().invalidations, 0);
+
+        let reimported_at = Utc::now();
+        cache.update_version(version(Some(reimported_at), 12));
+        assert!(cache.get("a").is_none());
+        assert_eq!(cache.last_modified(), Some(reimported_at));
+        let stats = cache.stats();
+        assert_eq!((stats.entries, stats.invalidations), (0, 1));
+        assert!(stats.last_invalidation.is_some());
+    }
+}
@@ -0,0 +1,127 @@
+use std::fmt::Display;
+
+use rocket::catcher::{self, Catcher};
+use rocket::http::{Header, Status};
+use rocket::request::{FromRequest, Outcome};
+use rocket::route::{self, Handler, Route};
+use rocket::{Request, Response, Data};
+use rocket::fairing::{Fairing, Info, Kind};
+use tracing::{Instrument, Span};
+use tracing_subscriber::EnvFilter;
+
+const HEADER: &str = "X-Request-Id";
+
+/// Identifier of a request, used to correlate log lines, error bodies and upstream calls.
+///
+/// It's taken from the `X-Request-Id` request header when the client (or a proxy) provides a
+/// sensible one, and generated otherwise.
+#[derive(Debug, Clone)]
+pub struct RequestId(String);
+
+impl RequestId {
+    /// Get the id of `request`, assigning one on first use.
+    pub fn of(request: &Request<'_>) -> Self {
+        request.local_cache(|| {
+            request.headers()
+                .get_one(HEADER)
+                .filter(|id| !id.is_empty() && id.len() <= 128 && id.bytes().all(|b| b.is_ascii_graphic()))
+                .map(|id| RequestId(id.to_owned()))
+                .unwrap_or_else(|| RequestId(uuid::Uuid::new_v4().to_string()))
+        }).clone()
+    }
+}
+
+/// Span of the handling of `request`: every log line emitted within it, Rocket's and the
+/// database driver's included, carries the id of the request.
+pub fn span(request: &Request<'_>) -> Span {
+    tracing::info_span!("request", id = %RequestId::of(request))
+}
+
+/// Handler of a route, run within the span of the request.
+#[derive(Clone)]
+struct Traced(Box<dyn Handler>);
+
+#[rocket::async_trait]
+impl Handler for Traced {
+    async fn handle<'r>(&self, request: &'r Request<'_>, data: Data<'r>) -> route::Outcome<'r> {
+        self.0.handle(request, data).instrument(span(request)).await
+    }
+}
+
+/// Handler of a catcher, run within the span of the request.
+#[derive(Clone)]
+struct TracedCatcher(Box<dyn catcher::Handler>);
+
+#[rocket::async_trait]
+impl catcher::Handler for TracedCatcher {
+    async fn handle<'r>(&self, status: Status, request: &'r Request<'_>) -> catcher::Result<'r> {
+        self.0.handle(status, request).instrument(span(request)).await
+    }
+}
+
+/// `routes`, with their handlers run within the span of the request.
+pub fn traced(routes: Vec<Route>) -> Vec<Route> {
+    routes.into_iter()
+        .map(|mut r| {
+            r.handler = Box::new(Traced(r.handler));
+            r
+        })
+        .collect()
+}
+
+/// `catchers`, run within the span of the request.
+pub fn traced_catchers(catchers: Vec<Catcher>) -> Vec<Catcher> {
+    catchers.into_iter()
+        .map(|mut c| {
+            c.handler = Box::new(TracedCatcher(c.handler));
+            c
+        })
+        .collect()
+}
+
+/// Log through `tracing`, forwarding the records of the `log` macros, so that they are printed
+/// along with the span they are emitted in. This replaces the Rocket logger: levels are set with
+/// `RUST_LOG`, and default to `info`.
+pub fn init_logging() {
+    let filter = EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| EnvFilter::new("info,hyper=warn,rustls=warn"));
+    if let Err(e) = tracing_subscriber::fmt().with_env_filter(filter).try_init() {
+        eprintln!("cannot initialize logging: {}", e);
+    }
+}
+
+impl Display for RequestId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for RequestId {
+    type Error = std::convert::Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(Self::of(request))
+    }
+}
+
+/// Assign an id to every request and echo it in the `X-Request-Id` response header.
+pub struct RequestIdFairing;
+
+#[rocket::async_trait]
+impl Fairing for RequestIdFairing {
+    fn info(&self) -> Info {
+        Info {
+            name: "Assign request ids",
+            kind: Kind::Request | Kind::Response
+        }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _data: &mut Data<'_>) {
+        span(request).in_scope(|| info!("{} {}", request.method(), request.uri()));
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        response.set_header(Header::new(HEADER, RequestId::of(request).0));
+    }
+}
@@ -2,40 +2,78 @@ use std::convert::Infallible;
 use std::ops::FromResidual;
 
 use rocket::form::Errors;
-use rocket::http::Status;
+use rocket::http::{ContentType, Status};
 use rocket::response::Responder;
 use rocket::Response;
-use rocket::{serde::json::Json, Request};
+use rocket::Request;
 use serde::Serialize;
-use serde::ser::SerializeMap;
 
+use crate::config::API_CONFIG;
+use crate::error_registry::{self, ErrorReport};
+use crate::format::Format;
+use crate::request_id::RequestId;
+use crate::upstream::UpstreamError;
+
+use crate::routes::map::explain::Explain;
 use crate::routes::map::params::ParamError;
-use crate::routes::map::query::QueryResult;
+use crate::routes::map::query::{QueryError, QueryResult};
 
 pub enum ApiResponse<T> {
     Ok(T, Option<usize>),
-    #[cfg(not(debug_assertions))]
-    Error(ApiError),
-    #[cfg(debug_assertions)]
+    Page(T, Paging),
     Error(ApiErrorWithStack)
 }
 
-#[cfg(debug_assertions)]
-#[derive(Serialize)]
-pub(crate) struct ApiErrorWithStack(ApiError, String);
+/// An `ApiError` along with the backtrace of the point it was raised at. The backtrace is only
+/// captured for internal server errors: upstream and database failures come in bursts during
+/// outages, and their backtrace would say nothing more than their error.
+pub(crate) struct ApiErrorWithStack(ApiError, Option<String>);
 
-#[cfg(debug_assertions)]
 impl ApiErrorWithStack {
     fn status(&self) -> u16 {
         self.0.status()
     }
+
+    /// Build the problem document for this error. Server errors are recorded in the error
+    /// registry and referenced by an opaque `error_id`; the error itself and its backtrace are
+    /// only exposed to the client when `verbose_errors` is enabled.
+    fn problem(&self, request: &Request) -> Problem {
+        let mut problem = self.0.problem(request);
+        if self.status() >= 500 {
+            let report = ErrorReport::new(request, self.status(), &problem.code, self.0.debug(), self.1.clone());
+            problem.error_id = Some(report.error_id().to_owned());
+            if API_CONFIG.verbose_errors {
+                problem.error_debug = Some(self.0.debug());
+                problem.stack = self.1.clone();
+            }
+            error_registry::record(request, report);
+        }
+        problem
+    }
+}
+
+impl From<mongodb::error::Error> for ApiError {
+    fn from(value: mongodb::error::Error) -> Self {
+        ApiError::Query(value.into())
+    }
+}
+
+impl From<QueryError> for ApiError {
+    fn from(value: QueryError) -> Self {
+        ApiError::Query(value)
+    }
+}
+
+impl From<UpstreamError> for ApiError {
+    fn from(value: UpstreamError) -> Self {
+        ApiError::Upstream(value)
+    }
 }
 
-#[cfg(debug_assertions)]
 impl From<ApiError> for ApiErrorWithStack {
     fn from(value: ApiError) -> Self {
-        let stack = std::backtrace::Backtrace::capture().to_string();
-        eprint!("---------- stack trace ----------\n{}\n---------------------------------\n", stack);
+        let stack = (value.status() == 500)
+            .then(|| std::backtrace::Backtrace::force_capture().to_string());
         ApiErrorWithStack(value, stack)
     }
 }
@@ -43,66 +81,245 @@ impl From<ApiError> for ApiErrorWithStack {
 impl<T> ApiResponse<T> {
     pub fn status(&self) -> u16 {
         match self {
-            Self::Ok(..) => 200,
+            Self::Ok(..) | Self::Page(..) => 200,
             Self::Error(e) => e.status()
         }
     }
+
+    /// Set the cursor of the page following this one, for lists supporting keyset pagination.
+    pub fn next_cursor(mut self, cursor: Option<String>) -> Self {
+        if let Self::Page(_, ref mut paging) = self {
+            paging.next_cursor = cursor;
+        }
+        self
+    }
+
+    /// Wrap paged results in a `{data, total, skip, limit, next}` envelope instead of returning
+    /// the bare list.
+    pub fn envelope(mut self, envelope: Option<bool>) -> Self {
+        if let Self::Page(_, ref mut paging) = self {
+            paging.envelope = envelope.unwrap_or(false);
+        }
+        self
+    }
 }
 
+/// Position of a page of results in the whole result set.
+#[derive(Debug, Clone)]
+pub struct Paging {
+    /// Total number of results, if counted.
+    pub total: Option<usize>,
+    pub skip: usize,
+    pub limit: usize,
+    /// Number of results in this page.
+    pub returned: usize,
+    /// Opaque cursor pointing right after the last result of the page, for lists supporting
+    /// keyset pagination.
+    pub next_cursor: Option<String>,
+    envelope: bool,
+}
+
+impl Paging {
+    pub fn new(total: Option<usize>, skip: usize, limit: usize, returned: usize) -> Self {
+        Paging { total, skip, limit, returned, next_cursor: None, envelope: false }
+    }
+
+    /// Offset of the next page, if any. Without a total, a full page is assumed to be followed
+    /// by another one.
+    fn next(&self) -> Option<usize> {
+        let more = match self.total {
+            Some(total) => self.skip + self.limit < total,
+            None => self.returned >= self.limit,
+        };
+        (self.limit > 0 && more).then_some(self.skip + self.limit)
+    }
+
+    fn prev(&self) -> Option<usize> {
+        (self.limit > 0 && self.skip > 0).then(|| self.skip.saturating_sub(self.limit))
+    }
+
+    fn last(&self) -> Option<usize> {
+        match self.total? {
+            0 => Some(0),
+            _ if self.limit == 0 => Some(0),
+            total => Some((total - 1) / self.limit * self.limit),
+        }
+    }
+
+    /// Uri of the same request, with the paging parameters (`skip`, `limit` and `cursor`)
+    /// replaced by `params` to point at another page.
+    fn uri_with(&self, request: &Request, params: &[(&str, String)]) -> String {
+        let uri = request.uri();
+        let mut query = uri.query()
+            .map(|q| q.as_str()
+                .split('&')
+                .filter(|f| !f.is_empty())
+                .filter(|f| {
+                    let key = f.split('=').next().unwrap_or_default();
+                    key != "skip" && key != "limit" && key != "cursor"
+                })
+                .map(str::to_owned)
+                .collect::<Vec<String>>()
+            )
+            .unwrap_or_default();
+        query.extend(params.iter().map(|(k, v)| format!("{}={}", k, v)));
+        query.push(format!("limit={}", self.limit));
+        format!("{}?{}", uri.path(), query.join("&"))
+    }
+
+    fn uri_for(&self, request: &Request, skip: usize) -> String {
+        self.uri_with(request, &[("skip", skip.to_string())])
+    }
+
+    /// Uri of the next page: the one pointed at by the cursor, if any, or the one at the next
+    /// offset.
+    fn next_uri(&self, request: &Request) -> Option<String> {
+        match self.next_cursor {
+            Some(ref c) => Some(self.uri_with(request, &[("cursor", c.clone())])),
+            None => self.next().map(|n| self.uri_for(request, n)),
+        }
+    }
+
+    /// Value of the RFC 8288 `Link` header pointing to the first, previous, next and last pages.
+    ///
+    /// Pages reached through a cursor have no offset, so only the first and next ones are
+    /// linked; the last one is also omitted when the results were not counted.
+    fn link_header(&self, request: &Request) -> Option<String> {
+        if self.limit == 0 {
+            return None;
+        }
+        let by_cursor = request.query_value::<&str>("cursor").is_some();
+        let mut links = vec![format!("<{}>; rel=\"first\"", self.uri_for(request, 0))];
+        if let Some(prev) = self.prev().filter(|_| !by_cursor) {
+            links.push(format!("<{}>; rel=\"prev\"", self.uri_for(request, prev)));
+        }
+        if let Some(next) = self.next_uri(request) {
+            links.push(format!("<{}>; rel=\"next\"", next));
+        }
+        if let Some(last) = self.last().filter(|_| !by_cursor) {
+            links.push(format!("<{}>; rel=\"last\"", self.uri_for(request, last)));
+        }
+        Some(links.join(", "))
+    }
+}
+
+impl<T> From<&QueryResult<T>> for Paging {
+    fn from(value: &QueryResult<T>) -> Self {
+        Paging::new(value.total, value.skip, value.limit, value.data.len())
+    }
+}
+
+#[derive(Serialize)]
+struct Envelope<T> {
+    data: T,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    total: Option<usize>,
+    skip: usize,
+    limit: usize,
+    next: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_cursor: Option<String>,
+}
+
+/// Body of the response, as it is served when no request is at hand: envelopes have no link to
+/// the next page, and errors are problem documents without instance nor request id.
 impl<T: Serialize> Serialize for ApiResponse<T> {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
         where S: serde::Serializer {
 
         match self {
             Self::Ok(v, ..) => v.serialize(serializer),
-            Self::Error(e) => e.serialize(serializer)
+            Self::Page(v, paging) if paging.envelope => Envelope {
+                data: v,
+                total: paging.total,
+                skip: paging.skip,
+                limit: paging.limit,
+                next: None,
+                next_cursor: paging.next_cursor.clone(),
+            }.serialize(serializer),
+            Self::Page(v, _) => v.serialize(serializer),
+            Self::Error(e) => e.0.document(String::new(), String::new()).serialize(serializer),
         }
     }
 }
 
 impl<'r, 'o: 'r, T: Serialize> Responder<'r, 'o> for ApiResponse<T> {
     fn respond_to(self, request: &'r Request<'_>) -> rocket::response::Result<'o> {
-        // if let Self::Error(e) = self {
-        //     if matches!(e, ApiError::NotFound(_)) {
-        //         Err(Status::from_code(404).unwrap())
-        //     } else {
-        //         Response::build()
-        //             .status(Status::from_code(e.status()).unwrap())
-        //             .merge(Json::respond_to(Json(e), request)?)
-        //             .ok()
-        //    }
-        // } else {
+        let format = Format::negotiate(request);
         let mut build = Response::build();
+        // the query plans replace whatever the route would have answered
+        if let Some(reports) = Explain::of(request).reports().filter(|r| !r.is_empty()) {
+            build.raw_header("Cache-Control", "no-store");
+            build.merge(format.respond(&reports, ContentType::JSON)?);
+            return build.ok();
+        }
         build.status(Status::new(self.status()));
-        if let Self::Ok(_, Some(count)) = self {
-            build.raw_header("X-Total-Count", count.to_string());
+        match self {
+            Self::Ok(v, count) => {
+                if let Some(count) = count {
+                    build.raw_header("X-Total-Count", count.to_string());
+                }
+                build.merge(format.respond(&v, ContentType::JSON)?);
+            }
+            Self::Page(v, paging) => {
+                if let Some(total) = paging.total {
+                    build.raw_header("X-Total-Count", total.to_string());
+                }
+                if let Some(link) = paging.link_header(request) {
+                    build.raw_header("Link", link);
+                }
+                if let Some(ref cursor) = paging.next_cursor {
+                    build.raw_header("X-Next-Cursor", cursor.clone());
+                }
+                if paging.envelope {
+                    let envelope = Envelope {
+                        data: v,
+                        total: paging.total,
+                        skip: paging.skip,
+                        limit: paging.limit,
+                        next: paging.next_uri(request),
+                        next_cursor: paging.next_cursor.clone(),
+                    };
+                    build.merge(format.respond(&envelope, ContentType::JSON)?);
+                } else {
+                    build.merge(format.respond(&v, ContentType::JSON)?);
+                }
+            }
+            Self::Error(e) => {
+                if let Some(retry_after) = e.0.retry_after() {
+                    build.raw_header("Retry-After", retry_after.to_string());
+                }
+                build.merge(format.respond(&e.problem(request), problem_content_type())?);
+            }
         }
-        build
-            .merge(Json(self).respond_to(request)?)
-            .ok()
-        // }
+        build.ok()
     }
+}
 
-    
+impl<T> From<QueryResult<T>> for ApiResponse<Vec<T>> {
+    fn from(value: QueryResult<T>) -> Self {
+        let paging = Paging::from(&value);
+        ApiResponse::Page(value.data, paging)
+    }
 }
 
-impl<T> From<Result<QueryResult<T>, mongodb::error::Error>> for ApiResponse<Vec<T>> {
-    fn from(value: Result<QueryResult<T>, mongodb::error::Error>) -> Self {
+impl<T, E: Into<ApiError>> From<Result<QueryResult<T>, E>> for ApiResponse<Vec<T>> {
+    fn from(value: Result<QueryResult<T>, E>) -> Self {
         match value {
-            Ok(v) => ApiResponse::Ok(v.data, Some(v.total)),
-            Err(e) => ApiResponse::Error(ApiError::InternalServer(Box::new(e)).into())
+            Ok(v) => v.into(),
+            Err(e) => e.into().respond()
         }
     }
 }
 
-impl<T> From<Result<Option<T>, mongodb::error::Error>> for ApiResponse<T> {
-    fn from(value: Result<Option<T>, mongodb::error::Error>) -> Self {
+impl<T, E: Into<ApiError>> From<Result<Option<T>, E>> for ApiResponse<T> {
+    fn from(value: Result<Option<T>, E>) -> Self {
         match value {
             Ok(v) => match v {
                 Some(v) => ApiResponse::Ok(v, None),
                 None => ApiError::NotFound.respond() 
             }
-            Err(e) => ApiError::InternalServer(Box::new(e)).respond()
+            Err(e) => e.into().respond()
         }
     }
 }
@@ -111,7 +328,16 @@ impl<T> FromResidual<Result<Infallible, mongodb::error::Error>> for ApiResponse<
     fn from_residual(residual: Result<Infallible, mongodb::error::Error>) -> Self {
         match residual {
             Ok(_inf) => panic!(),
-            Err(e) => ApiError::InternalServer(Box::new(e)).respond()
+            Err(e) => ApiError::from(e).respond()
+        }
+    }
+}
+
+impl<T> FromResidual<Result<Infallible, QueryError>> for ApiResponse<T> {
+    fn from_residual(residual: Result<Infallible, QueryError>) -> Self {
+        match residual {
+            Ok(_inf) => panic!(),
+            Err(e) => ApiError::from(e).respond()
         }
     }
 }
@@ -140,7 +366,11 @@ impl<T, E: std::error::Error> FromResidual<Result<Infallible, ParamError<E>>> fo
     fn from_residual(residual: Result<Infallible, ParamError<E>>) -> Self {
         match residual {
             Ok(_inf) => panic!(),
-            Err(_e) => ApiError::Form(vec![FormError { name: None, value: None, kind: "invalid identifier".to_owned() }]).respond()
+            Err(e) => ApiError::Param(FormError {
+                name: Some(e.name().to_owned()),
+                value: Some(e.value().to_owned()),
+                kind: e.reason(),
+            }).respond()
         }
     }
 }
@@ -149,12 +379,21 @@ impl<T> FromResidual<Result<Infallible, tt::TTError>> for ApiResponse<T> {
     fn from_residual(residual: Result<Infallible, tt::TTError>) -> Self {
         match residual {
             Ok(_inf) => panic!(),
-            Err(e) => ApiError::Generic(500, e.to_string()).respond()
+            Err(e) => ApiError::Upstream(UpstreamError::from_tt(&e, None)).respond()
         }
     }
 }
 
-#[derive(Serialize)]
+impl<T> FromResidual<Result<Infallible, ApiError>> for ApiResponse<T> {
+    fn from_residual(residual: Result<Infallible, ApiError>) -> Self {
+        match residual {
+            Ok(_inf) => panic!(),
+            Err(e) => e.respond()
+        }
+    }
+}
+
+#[derive(Serialize, Clone)]
 pub struct FormError {
     name: Option<String>,
     value: Option<String>,
@@ -176,8 +415,12 @@ impl From<rocket::form::Error<'_>> for FormError {
 pub enum ApiError {
     NotFound,
     InternalServer(Box<dyn std::error::Error>),
+    /// A database query failed, or didn't complete within the deadline of the endpoint.
+    Query(QueryError),
+    Upstream(UpstreamError),
     Generic(u16, String),
-    Form(Vec<FormError>)
+    Form(Vec<FormError>),
+    Param(FormError),
 }
 
 impl ApiError {
@@ -185,56 +428,151 @@ impl ApiError {
         match self {
             Self::NotFound => 404,
             Self::InternalServer(_) => 500,
+            Self::Query(e) => e.status(),
+            Self::Upstream(e) => e.status(),
             Self::Generic(c, _) => *c,
-            Self::Form(_) => 422,
+            Self::Form(_) | Self::Param(_) => 422,
+        }
+    }
+
+    /// Stable, machine-readable identifier of the error, in the form `<category>.<reason>`.
+    ///
+    /// `NotFound` errors are qualified with the entity of the matched route (e.g.
+    /// `stop.not_found`), or `resource.not_found` if no route matched at all.
+    pub fn code(&self, request: &Request) -> String {
+        self.code_for(entity(request))
+    }
+
+    /// Code of the error, `NotFound` errors being about `entity`.
+    fn code_for(&self, entity: &str) -> String {
+        match self {
+            Self::NotFound => format!("{}.not_found", entity),
+            Self::InternalServer(_) => "internal.server_error".to_owned(),
+            Self::Query(e) => e.code().to_owned(),
+            Self::Upstream(e) => e.code().to_owned(),
+            Self::Generic(c, _) => format!("http.{}", Status::new(*c).reason_lossy().to_lowercase().replace(' ', "_")),
+            Self::Form(_) => "query.invalid".to_owned(),
+            Self::Param(e) => format!("param.invalid_{}", e.name.as_deref().unwrap_or("identifier")),
+        }
+    }
+
+    pub fn title(&self) -> &'static str {
+        Status::new(self.status()).reason_lossy()
+    }
+
+    pub fn detail(&self) -> String {
+        match self {
+            Self::NotFound => "the requested resource does not exist".to_owned(),
+            Self::InternalServer(_) => "an internal error occurred while processing the request".to_owned(),
+            Self::Query(e) => e.detail(),
+            Self::Upstream(e) => e.detail(),
+            Self::Generic(_, d) => d.clone(),
+            Self::Form(_) => "one or more query parameters are invalid".to_owned(),
+            Self::Param(e) => format!("path parameter `{}` is invalid: {}", e.name.as_deref().unwrap_or("identifier"), e.kind),
         }
     }
 
-    pub fn name(&self) -> &str {
+    /// Full description of the error, for server side diagnostics.
+    pub fn debug(&self) -> String {
         match self {
-            Self::NotFound => "not found",
-            Self::InternalServer(_) => "internal server error",
-            Self::Generic(_, n) => n,
-            Self::Form(_) => "unprocessable entity",
+            Self::InternalServer(e) => e.to_string(),
+            Self::Query(e) => e.to_string(),
+            Self::Upstream(e) => e.to_string(),
+            _ => self.detail(),
+        }
+    }
+
+    /// Seconds after which the client may retry the request, for transient failures.
+    pub fn retry_after(&self) -> Option<u64> {
+        match self {
+            Self::Upstream(e) => e.retry_after(),
+            Self::Query(e) => e.retry_after(),
+            _ => None,
         }
     }
 
     pub fn respond<T>(self) -> ApiResponse<T> {
         ApiResponse::Error(self.into())
     }
-}
 
-impl Serialize for ApiError {
-    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
-        where S: serde::Serializer {
-        
-        let mut map = serializer.serialize_map(None)?;
-        // map.serialize_entry("status", &self.status())?;
-        map.serialize_entry("error", &self.name())?;
-        match self {
-            // Self::NotFound(t) => {
-            //     map.serialize_entry("uri", &uri)?;
-            // },
-            Self::InternalServer(e) => {
-                error!("internal server error generated: {}", e);
-                #[cfg(debug_assertions)]
-                map.serialize_entry("error_debug", &e.to_string())?;
-            }
-            Self::Form(e) => {
-                map.serialize_entry("errors", &e)?;
-            }
-            _ => {}
+    fn problem(&self, request: &Request) -> Problem {
+        let mut problem = self.detached_problem(&request.uri().to_string(), &RequestId::of(request));
+        problem.code = self.code(request);
+        problem
+    }
+
+    /// Problem document of this error about `instance` rather than the request being served, as
+    /// a request of a batch which could not be dispatched.
+    pub(crate) fn detached_problem(&self, instance: &str, request_id: &RequestId) -> Problem {
+        self.document(instance.to_owned(), request_id.to_string())
+    }
+
+    /// Problem document of this error, `NotFound` errors being about a generic resource.
+    fn document(&self, instance: String, request_id: String) -> Problem {
+        let errors = match self {
+            Self::Form(e) => Some(e.clone()),
+            Self::Param(e) => Some(vec![e.clone()]),
+            _ => None
+        };
+        Problem {
+            ty: "about:blank",
+            code: self.code_for("resource"),
+            title: self.title(),
+            status: self.status(),
+            detail: self.detail(),
+            instance,
+            request_id,
+            errors,
+            error_id: None,
+            error_debug: None,
+            stack: None,
         }
-        map.end()
     }
 }
 
+/// Name of the entity served by the route that handled `request`, taken from the last segment of
+/// its mount point (`/api/v1/map/stop` -> `stop`).
+fn entity<'a>(request: &'a Request<'_>) -> &'a str {
+    request.route()
+        .and_then(|r| r.uri.base().rsplit('/').find(|s| !s.is_empty()))
+        .unwrap_or("resource")
+}
+
+fn problem_content_type() -> ContentType {
+    ContentType::new("application", "problem+json")
+}
+
+/// RFC 7807 problem details document, the body of every error response.
+#[derive(Serialize)]
+pub(crate) struct Problem {
+    #[serde(rename = "type")]
+    ty: &'static str,
+    code: String,
+    title: &'static str,
+    status: u16,
+    detail: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    instance: String,
+    /// Id of the request, also sent in the `X-Request-Id` header, to be quoted in bug reports.
+    #[serde(skip_serializing_if = "String::is_empty")]
+    request_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    errors: Option<Vec<FormError>>,
+    /// Id of the error report recorded server side, for server errors.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error_debug: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stack: Option<String>,
+}
+
 #[catch(default)]
-pub fn api_catch_default(status: Status, _req: &Request) -> Json<ApiResponse<()>> {
-    Json(ApiError::Generic(status.code, "generic error".to_owned()).respond())
+pub fn api_catch_default(status: Status, _req: &Request) -> ApiResponse<()> {
+    ApiError::Generic(status.code, "the request could not be processed".to_owned()).respond()
 }
 
 #[catch(404)]
-pub fn api_catch_404(_req: &Request) -> Json<ApiResponse<()>> {
-    Json(ApiError::NotFound.respond())
+pub fn api_catch_404(_req: &Request) -> ApiResponse<()> {
+    ApiError::NotFound.respond()
 }
@@ -0,0 +1,202 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::marker::PhantomData;
+use std::sync::Arc;
+
+use async_graphql::dataloader::{DataLoader, HashMapCache, Loader};
+use async_graphql::Error;
+use bruss_data::{Area, BrussType, Path, Route, Segment, Stop, Trip};
+use mongodb::bson::{doc, Document};
+use tracing::Instrument;
+
+use crate::routes::map::fields::Projected;
+use crate::routes::map::pipeline::{CountMode, Pipeline};
+use crate::routes::map::query::{DBInterface, Queryable};
+use crate::routes::tracking::{TripTracking, TripUpdate};
+use super::objects::int;
+use super::{api_error, query_error};
+
+/// Largest batch of keys looked up at once, as lists are capped to 100 results.
+const MAX_BATCH: usize = 100;
+
+/// Key of a document, looked up along with the other keys of the same batch.
+pub trait DocKey: Sized + Send + Sync + Clone + Eq + Hash + 'static {
+    /// Filter matching the documents of `keys`.
+    fn filter(keys: &[Self]) -> Document;
+
+    /// Key of `doc`, if it has one.
+    fn of(doc: &Document) -> Option<Self>;
+}
+
+/// Numeric ids, of areas and routes.
+impl DocKey for i32 {
+    fn filter(keys: &[Self]) -> Document {
+        doc!{"id": {"$in": keys}}
+    }
+
+    fn of(doc: &Document) -> Option<Self> {
+        int(doc, "id")
+    }
+}
+
+/// String ids, of trips and paths.
+impl DocKey for String {
+    fn filter(keys: &[Self]) -> Document {
+        doc!{"id": {"$in": keys}}
+    }
+
+    fn of(doc: &Document) -> Option<Self> {
+        doc.get_str("id").ok().map(str::to_owned)
+    }
+}
+
+/// Stops are identified by their area type and id.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct StopKey {
+    pub ty: String,
+    pub id: i32,
+}
+
+impl DocKey for StopKey {
+    fn filter(keys: &[Self]) -> Document {
+        doc!{"$or": keys.iter().map(|k| doc!{"type": &k.ty, "id": k.id}).collect::<Vec<_>>()}
+    }
+
+    fn of(doc: &Document) -> Option<Self> {
+        Some(StopKey { ty: doc.get_str("type").ok()?.to_owned(), id: int(doc, "id")? })
+    }
+}
+
+/// Segments are identified by their area type and the stops they connect.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct SegmentKey {
+    pub ty: String,
+    pub from: i32,
+    pub to: i32,
+}
+
+impl DocKey for SegmentKey {
+    fn filter(keys: &[Self]) -> Document {
+        doc!{"$or": keys.iter().map(|k| doc!{"type": &k.ty, "from": k.from, "to": k.to}).collect::<Vec<_>>()}
+    }
+
+    fn of(doc: &Document) -> Option<Self> {
+        Some(SegmentKey { ty: doc.get_str("type").ok()?.to_owned(), from: int(doc, "from")?, to: int(doc, "to")? })
+    }
+}
+
+/// Loader of the documents of the collection of `X`, in a single query per batch of keys.
+pub struct DocLoader<X>(Arc<DBInterface>, PhantomData<fn() -> X>);
+
+impl<K: DocKey, X: BrussType + Sync + Unpin + Send + 'static> Loader<K> for DocLoader<X> {
+    type Value = Document;
+    type Error = Error;
+
+    async fn load(&self, keys: &[K]) -> Result<HashMap<K, Document>, Error> {
+        let pipeline = Pipeline::new(K::filter(keys))
+            .limit(Some(keys.len() as u32))
+            .count_mode(Some(CountMode::None));
+        let result = Queryable::<Projected, X>::query(&*self.0, pipeline).await
+            .map_err(query_error)?;
+        Ok(result.data.into_iter()
+            .map(Projected::into_document)
+            .filter_map(|d| K::of(&d).map(|k| (k, d)))
+            .collect())
+    }
+}
+
+/// Loader of the ids of the routes serving each stop, found through the trips stopping there.
+pub struct StopRoutesLoader(Arc<DBInterface>);
+
+impl Loader<StopKey> for StopRoutesLoader {
+    type Value = Vec<i32>;
+    type Error = Error;
+
+    async fn load(&self, keys: &[StopKey]) -> Result<HashMap<StopKey, Vec<i32>>, Error> {
+        let conds = keys.iter()
+            .map(|k| doc!{"type": &k.ty, format!("times.{}", k.id): {"$exists": true}})
+            .collect::<Vec<_>>();
+        // only the times at the stops of the batch are needed to tell which ones a trip serves
+        let mut projection = doc!{"_id": 0, "route": 1, "type": 1};
+        for k in keys {
+            projection.insert(format!("times.{}", k.id), 1);
+        }
+        let fetch = vec![doc!{"$match": {"$or": conds}}, doc!{"$project": projection}];
+        let pipeline = Pipeline::custom(fetch, vec![]).count_mode(Some(CountMode::None));
+        let trips = Queryable::<Projected, Trip>::query(&*self.0, pipeline).await
+            .map_err(query_error)?;
+
+        let mut routes = HashMap::<StopKey, Vec<i32>>::new();
+        for trip in trips.data {
+            let doc = trip.document();
+            let (Some(route), Ok(ty), Ok(times)) = (int(doc, "route"), doc.get_str("type"), doc.get_document("times")) else {
+                continue;
+            };
+            for id in times.keys().filter_map(|s| s.parse().ok()) {
+                let key = StopKey { ty: ty.to_owned(), id };
+                if keys.contains(&key) {
+                    let served = routes.entry(key).or_default();
+                    if !served.contains(&route) {
+                        served.push(route);
+                    }
+                }
+            }
+        }
+        Ok(routes)
+    }
+}
+
+/// Loader of the realtime tracking of trips, as served by `/api/v1/tracking/trip`.
+pub struct TrackingLoader(Arc<DBInterface>);
+
+impl Loader<String> for TrackingLoader {
+    type Value = TripTracking;
+    type Error = Error;
+
+    async fn load(&self, keys: &[String]) -> Result<HashMap<String, TripTracking>, Error> {
+        let updates = match TripUpdate::get_by_ids(&self.0, keys.to_vec()).await {
+            Ok(u) => u,
+            Err(e) => return Err(api_error(e)),
+        };
+        Ok(updates.into_iter()
+            .map(TripTracking::from)
+            .map(|t| (t.trip_id().to_owned(), t))
+            .collect())
+    }
+}
+
+/// Loader of a request, caching what it loaded until the request is answered.
+type RequestLoader<T> = DataLoader<T, HashMapCache>;
+
+/// Loaders of a GraphQL request, so that the nodes resolved at the same level are looked up
+/// together instead of one by one, and the nodes reached more than once are looked up once.
+pub struct Loaders {
+    pub areas: RequestLoader<DocLoader<Area>>,
+    pub routes: RequestLoader<DocLoader<Route>>,
+    pub stops: RequestLoader<DocLoader<Stop>>,
+    pub trips: RequestLoader<DocLoader<Trip>>,
+    pub paths: RequestLoader<DocLoader<Path>>,
+    pub segments: RequestLoader<DocLoader<Segment>>,
+    pub stop_routes: RequestLoader<StopRoutesLoader>,
+    pub tracking: RequestLoader<TrackingLoader>,
+}
+
+impl Loaders {
+    pub fn new(db: Arc<DBInterface>) -> Self {
+        fn loader<T>(loader: T) -> RequestLoader<T> {
+            // batches are loaded in tasks of their own, still logging within the span of the request
+            DataLoader::with_cache(loader, |f| tokio::spawn(f.in_current_span()), HashMapCache::default())
+                .max_batch_size(MAX_BATCH)
+        }
+        Self {
+            areas: loader(DocLoader(db.clone(), PhantomData)),
+            routes: loader(DocLoader(db.clone(), PhantomData)),
+            stops: loader(DocLoader(db.clone(), PhantomData)),
+            trips: loader(DocLoader(db.clone(), PhantomData)),
+            paths: loader(DocLoader(db.clone(), PhantomData)),
+            segments: loader(DocLoader(db.clone(), PhantomData)),
+            stop_routes: loader(StopRoutesLoader(db.clone())),
+            tracking: loader(TrackingLoader(db)),
+        }
+    }
+}
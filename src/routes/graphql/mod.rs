@@ -0,0 +1,172 @@
+mod loaders;
+mod objects;
+
+use std::sync::Arc;
+
+use async_graphql::{Context, EmptyMutation, EmptySubscription, ErrorExtensions, Object, Result, Schema};
+use bruss_data::{Area, Route, Stop};
+use lazy_static::lazy_static;
+use mongodb::bson::{doc, Document};
+use rocket::serde::json::{self, Json};
+use rocket::State;
+
+use crate::config::API_CONFIG;
+use crate::response::{ApiError, ApiResponse};
+use crate::routes::map::fields::Projected;
+use crate::routes::map::pipeline::{CountMode, Pipeline};
+use crate::routes::map::query::{DBInterface, QueryError, Queryable};
+use loaders::{Loaders, StopKey};
+use objects::{area_type, page_size, AreaNode, PathNode, RouteNode, StopNode, TrackingNode, TripNode};
+
+pub type BrussSchema = Schema<QueryRoot, EmptyMutation, EmptySubscription>;
+
+/// Schema of `/api/v1/graphql`, limited in depth and complexity so that a single query can't
+/// walk the whole dataset.
+pub fn schema() -> BrussSchema {
+    Schema::build(QueryRoot, EmptyMutation, EmptySubscription)
+        .limit_depth(API_CONFIG.graphql_max_depth)
+        .limit_complexity(API_CONFIG.graphql_max_complexity)
+        .finish()
+}
+
+/// GraphQL error for a failed query, with the same message and code a REST endpoint would
+/// answer with.
+fn query_error(e: QueryError) -> async_graphql::Error {
+    error!("GraphQL query failed: {}", e);
+    let code = e.code();
+    async_graphql::Error::new(e.detail()).extend_with(|_, ext| ext.set("code", code))
+}
+
+/// GraphQL error for a failed lookup of realtime data.
+fn api_error(e: ApiError) -> async_graphql::Error {
+    error!("GraphQL tracking lookup failed: {}", e.detail());
+    let status = e.status();
+    async_graphql::Error::new(e.detail()).extend_with(|_, ext| ext.set("status", status))
+}
+
+/// First page of the documents of the collection of `X` matching `filter`.
+async fn list<X>(ctx: &Context<'_>, filter: Document, skip: Option<u32>, limit: Option<u32>) -> Result<Vec<Document>>
+where
+    X: bruss_data::BrussType + Sync + Unpin + Send,
+{
+    let db = ctx.data::<Arc<DBInterface>>()?;
+    let pipeline = Pipeline::new(filter)
+        .skip(skip)
+        .limit(Some(page_size(limit)))
+        .count_mode(Some(CountMode::None));
+    let result = Queryable::<Projected, X>::query(&**db, pipeline).await
+        .map_err(query_error)?;
+    Ok(result.data.into_iter().map(Projected::into_document).collect())
+}
+
+pub struct QueryRoot;
+
+#[Object]
+impl QueryRoot {
+    async fn area(&self, ctx: &Context<'_>, id: i32) -> Result<Option<AreaNode>> {
+        Ok(ctx.data::<Loaders>()?.areas.load_one(id).await?.map(AreaNode))
+    }
+
+    #[graphql(complexity = "page_size(limit) as usize * child_complexity")]
+    async fn areas(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(name = "type")] ty: Option<String>,
+        skip: Option<u32>,
+        limit: Option<u32>,
+    ) -> Result<Vec<AreaNode>> {
+        let mut filter = doc!{};
+        if let Some(ty) = ty {
+            filter.insert("type", area_type(&ty)?);
+        }
+        Ok(list::<Area>(ctx, filter, skip, limit).await?.into_iter().map(AreaNode).collect())
+    }
+
+    async fn route(&self, ctx: &Context<'_>, id: i32) -> Result<Option<RouteNode>> {
+        Ok(ctx.data::<Loaders>()?.routes.load_one(id).await?.map(RouteNode))
+    }
+
+    #[graphql(complexity = "page_size(limit) as usize * child_complexity")]
+    async fn routes(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(name = "type")] ty: Option<String>,
+        area: Option<i32>,
+        skip: Option<u32>,
+        limit: Option<u32>,
+    ) -> Result<Vec<RouteNode>> {
+        let mut filter = doc!{};
+        if let Some(ty) = ty {
+            filter.insert("area_ty", area_type(&ty)?);
+        }
+        if let Some(area) = area {
+            filter.insert("area", area);
+        }
+        Ok(list::<Route>(ctx, filter, skip, limit).await?.into_iter().map(RouteNode).collect())
+    }
+
+    async fn stop(&self, ctx: &Context<'_>, #[graphql(name = "type")] ty: String, id: i32) -> Result<Option<StopNode>> {
+        let key = StopKey { ty: area_type(&ty)?, id };
+        Ok(ctx.data::<Loaders>()?.stops.load_one(key).await?.map(StopNode))
+    }
+
+    #[graphql(complexity = "page_size(limit) as usize * child_complexity")]
+    async fn stops(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(name = "type")] ty: Option<String>,
+        skip: Option<u32>,
+        limit: Option<u32>,
+    ) -> Result<Vec<StopNode>> {
+        let mut filter = doc!{};
+        if let Some(ty) = ty {
+            filter.insert("type", area_type(&ty)?);
+        }
+        Ok(list::<Stop>(ctx, filter, skip, limit).await?.into_iter().map(StopNode).collect())
+    }
+
+    async fn trip(&self, ctx: &Context<'_>, id: String) -> Result<Option<TripNode>> {
+        Ok(ctx.data::<Loaders>()?.trips.load_one(id).await?.map(TripNode))
+    }
+
+    async fn path(&self, ctx: &Context<'_>, id: String) -> Result<Option<PathNode>> {
+        Ok(ctx.data::<Loaders>()?.paths.load_one(id).await?.map(|p| PathNode::new(p, None)))
+    }
+
+    /// Realtime tracking of the trips `ids`, in the same order, skipping the unknown ones.
+    #[graphql(complexity = "ids.len() * child_complexity")]
+    async fn tracking(&self, ctx: &Context<'_>, ids: Vec<String>) -> Result<Vec<TrackingNode>> {
+        if ids.len() > 100 {
+            return Err("at most 100 trips can be tracked at once".into());
+        }
+        let mut tracking = ctx.data::<Loaders>()?.tracking.load_many(ids.iter().cloned()).await?;
+        Ok(ids.iter().filter_map(|id| tracking.remove(id)).map(TrackingNode).collect())
+    }
+}
+
+/// Run a GraphQL query on areas, routes, stops, trips and their realtime tracking. Errors of
+/// the query are reported in the `errors` of the GraphQL response, along with the data that
+/// could be resolved.
+#[post("/", data = "<request>")]
+async fn post(
+    db: DBInterface,
+    schema: &State<BrussSchema>,
+    request: Result<Json<async_graphql::Request>, json::Error<'_>>,
+) -> ApiResponse<async_graphql::Response> {
+    let request = request
+        .map_err(|e| ApiError::Generic(400, format!("invalid GraphQL request: {}", e)))?
+        .into_inner();
+    let db = Arc::new(db);
+    let request = request.data(Loaders::new(db.clone())).data(db);
+    ApiResponse::Ok(schema.execute(request).await, None)
+}
+
+/// Schema of the endpoint, in the GraphQL schema definition language.
+#[get("/schema")]
+fn get_schema(schema: &State<BrussSchema>) -> String {
+    schema.sdl()
+}
+
+lazy_static!{
+    pub static ref ROUTES: Vec<rocket::Route> = routes![post, get_schema];
+}
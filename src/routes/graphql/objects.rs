@@ -0,0 +1,436 @@
+use std::sync::Arc;
+
+use async_graphql::{Context, Json, Object, Result};
+use bruss_data::Schedule;
+use chrono::{DateTime, Utc};
+use mongodb::bson::{Bson, Document};
+use serde_json::Value;
+use tt::AreaType;
+
+use crate::routes::map::fields::Projected;
+use crate::routes::map::pipeline::{CountMode, CustomPipeline};
+use crate::routes::map::query::{DBInterface, Queryable};
+use crate::routes::map::trip::MultiTripQuery;
+use crate::routes::tracking::TripTracking;
+use super::loaders::{Loaders, SegmentKey, StopKey};
+use super::query_error;
+
+/// Page size of the lists, unless requested otherwise.
+pub const DEFAULT_LIMIT: u32 = 20;
+
+/// Page size of a list: `limit`, if any, up to 100 as in the REST endpoints.
+pub fn page_size(limit: Option<u32>) -> u32 {
+    limit.unwrap_or(DEFAULT_LIMIT).clamp(1, 100)
+}
+
+/// Longest sequence of stops or segments of a path, unless requested otherwise.
+pub const MAX_SEQUENCE: u32 = 200;
+
+/// Number of stops or segments of a path: `limit`, if any, up to [`MAX_SEQUENCE`].
+pub fn sequence_size(limit: Option<u32>) -> usize {
+    limit.map_or(MAX_SEQUENCE, |l| l.min(MAX_SEQUENCE)) as usize
+}
+
+/// Field `key` of `doc` as an integer, whichever way it is stored.
+pub fn int(doc: &Document, key: &str) -> Option<i32> {
+    match doc.get(key)? {
+        Bson::Int32(i) => Some(*i),
+        Bson::Int64(i) => i32::try_from(*i).ok(),
+        Bson::Double(d) => Some(*d as i32),
+        _ => None,
+    }
+}
+
+fn required(doc: &Document, key: &str) -> Result<i32> {
+    int(doc, key).ok_or_else(|| format!("missing `{}`", key).into())
+}
+
+/// Id `key` of `doc`, as the REST endpoints take it.
+fn id16(doc: &Document, key: &str) -> Result<u16> {
+    let id = required(doc, key)?;
+    u16::try_from(id).map_err(|_| format!("`{}` {} is out of range", key, id).into())
+}
+
+fn string(doc: &Document, key: &str) -> Option<String> {
+    doc.get_str(key).ok().map(str::to_owned)
+}
+
+fn float(value: &Bson) -> Option<f64> {
+    match value {
+        Bson::Double(d) => Some(*d),
+        Bson::Int32(i) => Some(*i as f64),
+        Bson::Int64(i) => Some(*i as f64),
+        _ => None,
+    }
+}
+
+fn datetime(doc: &Document, key: &str) -> Option<DateTime<Utc>> {
+    doc.get_datetime(key).ok().map(|d| d.to_chrono())
+}
+
+/// Field `key` of `doc` as plain JSON, for the fields without a GraphQL type of their own.
+fn json(doc: &Document, key: &str) -> Option<Json<Value>> {
+    doc.get(key).map(|v| Json(Projected::plain(v)))
+}
+
+/// Area type `ty`, as stored in the documents.
+pub fn area_type(ty: &str) -> Result<String> {
+    ty.parse::<AreaType>()
+        .map(|t| t.to_string())
+        .map_err(|_| format!("invalid area type `{}`: expected `u` or `e`", ty).into())
+}
+
+/// Departures of `pipeline`, a trip pipeline on the schedules.
+async fn departures(ctx: &Context<'_>, pipeline: CustomPipeline) -> Result<Vec<DepartureNode>> {
+    let db = ctx.data::<Arc<DBInterface>>()?;
+    let result = Queryable::<Projected, Schedule>::query(&**db, pipeline.count_mode(Some(CountMode::None))).await
+        .map_err(query_error)?;
+    Ok(result.data.into_iter().map(|p| DepartureNode(p.into_document())).collect())
+}
+
+pub struct AreaNode(pub Document);
+
+#[Object(name = "Area")]
+impl AreaNode {
+    async fn id(&self) -> Result<i32> {
+        required(&self.0, "id")
+    }
+
+    async fn label(&self) -> Option<String> {
+        string(&self.0, "label")
+    }
+
+    /// `u` for urban areas, `e` for extra-urban ones.
+    #[graphql(name = "type")]
+    async fn ty(&self) -> Option<String> {
+        string(&self.0, "type")
+    }
+}
+
+pub struct RouteNode(pub Document);
+
+#[Object(name = "Route")]
+impl RouteNode {
+    async fn id(&self) -> Result<i32> {
+        required(&self.0, "id")
+    }
+
+    #[graphql(name = "type")]
+    async fn ty(&self) -> Option<i32> {
+        int(&self.0, "type")
+    }
+
+    async fn code(&self) -> Option<String> {
+        string(&self.0, "code")
+    }
+
+    async fn name(&self) -> Option<String> {
+        string(&self.0, "name")
+    }
+
+    async fn color(&self) -> Option<String> {
+        string(&self.0, "color")
+    }
+
+    async fn area_type(&self) -> Option<String> {
+        string(&self.0, "area_ty")
+    }
+
+    async fn area(&self, ctx: &Context<'_>) -> Result<Option<AreaNode>> {
+        let Some(id) = int(&self.0, "area") else {
+            return Ok(None);
+        };
+        let area = ctx.data::<Loaders>()?.areas.load_one(id).await?;
+        Ok(area.map(AreaNode))
+    }
+
+    /// Next trips of the route, from `time` or from now on.
+    #[graphql(complexity = "page_size(limit) as usize * child_complexity")]
+    async fn departures(&self, ctx: &Context<'_>, time: Option<DateTime<Utc>>, limit: Option<u32>) -> Result<Vec<DepartureNode>> {
+        let id = id16(&self.0, "id")?;
+        let pipeline = MultiTripQuery::at(time).into_pipeline_route(id, None, Some(page_size(limit)), None);
+        departures(ctx, pipeline).await
+    }
+}
+
+pub struct StopNode(pub Document);
+
+impl StopNode {
+    fn key(&self) -> Result<StopKey> {
+        let ty = string(&self.0, "type").ok_or("missing `type`")?;
+        Ok(StopKey { ty, id: required(&self.0, "id")? })
+    }
+}
+
+#[Object(name = "Stop")]
+impl StopNode {
+    async fn id(&self) -> Result<i32> {
+        required(&self.0, "id")
+    }
+
+    async fn code(&self) -> Option<String> {
+        string(&self.0, "code")
+    }
+
+    async fn description(&self) -> Option<String> {
+        string(&self.0, "description")
+    }
+
+    /// Latitude and longitude.
+    async fn position(&self) -> Option<Vec<f64>> {
+        let position = self.0.get_array("position").ok()?;
+        position.iter().map(float).collect()
+    }
+
+    async fn altitude(&self) -> Option<i32> {
+        int(&self.0, "altitude")
+    }
+
+    async fn name(&self) -> Option<String> {
+        string(&self.0, "name")
+    }
+
+    async fn town(&self) -> Option<String> {
+        string(&self.0, "town")
+    }
+
+    #[graphql(name = "type")]
+    async fn ty(&self) -> Option<String> {
+        string(&self.0, "type")
+    }
+
+    async fn wheelchair_boarding(&self) -> Option<bool> {
+        self.0.get_bool("wheelchair_boarding").ok()
+    }
+
+    /// Routes whose trips stop here.
+    #[graphql(complexity = "10 * child_complexity")]
+    async fn routes(&self, ctx: &Context<'_>) -> Result<Vec<RouteNode>> {
+        let loaders = ctx.data::<Loaders>()?;
+        let ids = loaders.stop_routes.load_one(self.key()?).await?.unwrap_or_default();
+        let mut routes = loaders.routes.load_many(ids.iter().copied()).await?;
+        Ok(ids.iter().filter_map(|id| routes.remove(id)).map(RouteNode).collect())
+    }
+
+    /// Next trips stopping here, from `time` or from now on.
+    #[graphql(complexity = "page_size(limit) as usize * child_complexity")]
+    async fn departures(&self, ctx: &Context<'_>, time: Option<DateTime<Utc>>, limit: Option<u32>) -> Result<Vec<DepartureNode>> {
+        let id = id16(&self.0, "id")?;
+        let ty = string(&self.0, "type").ok_or("missing `type`")?;
+        let ty = ty.parse::<AreaType>().map_err(|_| format!("invalid area type `{}`", ty))?;
+        let pipeline = MultiTripQuery::at(time).into_pipeline_stop(id, ty, None, Some(page_size(limit)), None);
+        departures(ctx, pipeline).await
+    }
+}
+
+/// Trip leaving its first stop at `departure`, and reaching the stop it was listed for at
+/// `arrivalAtStop`.
+pub struct DepartureNode(Document);
+
+#[Object(name = "Departure")]
+impl DepartureNode {
+    async fn departure(&self) -> Option<DateTime<Utc>> {
+        datetime(&self.0, "departure")
+    }
+
+    async fn arrival_at_stop(&self) -> Option<DateTime<Utc>> {
+        datetime(&self.0, "arrival_at_stop")
+    }
+
+    async fn trip(&self) -> Option<TripNode> {
+        self.0.get_document("trip").ok().cloned().map(TripNode)
+    }
+}
+
+pub struct TripNode(pub Document);
+
+#[Object(name = "Trip")]
+impl TripNode {
+    async fn id(&self) -> Option<String> {
+        string(&self.0, "id")
+    }
+
+    async fn delay(&self) -> Option<i32> {
+        int(&self.0, "delay")
+    }
+
+    /// `f` going forward, `b` going back.
+    async fn direction(&self) -> Option<String> {
+        string(&self.0, "direction")
+    }
+
+    async fn next_stop(&self) -> Option<i32> {
+        int(&self.0, "next_stop")
+    }
+
+    async fn last_stop(&self) -> Option<i32> {
+        int(&self.0, "last_stop")
+    }
+
+    async fn bus_id(&self) -> Option<i32> {
+        int(&self.0, "bus_id")
+    }
+
+    async fn headsign(&self) -> Option<String> {
+        string(&self.0, "headsign")
+    }
+
+    #[graphql(name = "type")]
+    async fn ty(&self) -> Option<String> {
+        string(&self.0, "type")
+    }
+
+    /// Arrival and departure times by stop id, as served by `/api/v1/map/trip`.
+    async fn times(&self) -> Option<Json<Value>> {
+        json(&self.0, "times")
+    }
+
+    async fn route(&self, ctx: &Context<'_>) -> Result<Option<RouteNode>> {
+        let Some(id) = int(&self.0, "route") else {
+            return Ok(None);
+        };
+        let route = ctx.data::<Loaders>()?.routes.load_one(id).await?;
+        Ok(route.map(RouteNode))
+    }
+
+    async fn path(&self, ctx: &Context<'_>) -> Result<Option<PathNode>> {
+        let Some(id) = string(&self.0, "path") else {
+            return Ok(None);
+        };
+        let path = ctx.data::<Loaders>()?.paths.load_one(id).await?;
+        Ok(path.map(|p| PathNode::new(p, string(&self.0, "type"))))
+    }
+
+    /// Realtime tracking of the trip, fetched from upstream when not recent enough.
+    async fn tracking(&self, ctx: &Context<'_>) -> Result<Option<TrackingNode>> {
+        let Some(id) = string(&self.0, "id") else {
+            return Ok(None);
+        };
+        Ok(ctx.data::<Loaders>()?.tracking.load_one(id).await?.map(TrackingNode))
+    }
+}
+
+/// Realtime tracking of a trip, as served by `/api/v1/tracking/trip`.
+pub struct TrackingNode(pub TripTracking);
+
+#[Object(name = "TripTracking")]
+impl TrackingNode {
+    async fn id(&self) -> &str {
+        &self.0.id
+    }
+
+    async fn delay(&self) -> i32 {
+        self.0.delay
+    }
+
+    async fn last_stop(&self) -> Option<u16> {
+        self.0.last_stop
+    }
+
+    async fn next_stop(&self) -> Option<u16> {
+        self.0.next_stop
+    }
+
+    /// Area type of the route of the trip, `u` or `e`.
+    async fn area(&self) -> Option<String> {
+        self.0.area.map(|a| a.to_string())
+    }
+
+    async fn bus_id(&self) -> Option<u16> {
+        self.0.bus_id
+    }
+
+    async fn last_event(&self) -> Option<DateTime<Utc>> {
+        self.0.last_event
+    }
+}
+
+/// Sequence of stops of a trip. Its stops and segments are those of the area type of the path,
+/// or else of the trip it was reached through.
+pub struct PathNode {
+    doc: Document,
+    ty: Option<String>,
+}
+
+impl PathNode {
+    pub fn new(doc: Document, trip_type: Option<String>) -> Self {
+        let ty = string(&doc, "type").or(trip_type);
+        PathNode { doc, ty }
+    }
+
+    fn sequence(&self) -> Vec<i32> {
+        self.doc.get_array("sequence")
+            .map(|s| s.iter().filter_map(|v| float(v).map(|f| f as i32)).collect())
+            .unwrap_or_default()
+    }
+}
+
+#[Object(name = "Path")]
+impl PathNode {
+    async fn id(&self) -> Option<String> {
+        string(&self.doc, "id")
+    }
+
+    #[graphql(name = "type")]
+    async fn ty(&self) -> Option<&str> {
+        self.ty.as_deref()
+    }
+
+    /// Ids of the stops, in order.
+    #[graphql(name = "sequence")]
+    async fn stop_ids(&self) -> Vec<i32> {
+        self.sequence()
+    }
+
+    /// First `limit` stops, up to 200.
+    #[graphql(complexity = "sequence_size(limit) * child_complexity")]
+    async fn stops(&self, ctx: &Context<'_>, limit: Option<u32>) -> Result<Vec<StopNode>> {
+        let Some(ty) = self.ty.clone() else {
+            return Ok(vec![]);
+        };
+        let keys = self.sequence().into_iter()
+            .take(sequence_size(limit))
+            .map(|id| StopKey { ty: ty.clone(), id })
+            .collect::<Vec<_>>();
+        let stops = ctx.data::<Loaders>()?.stops.load_many(keys.iter().cloned()).await?;
+        // a stop may be visited more than once
+        Ok(keys.iter().filter_map(|k| stops.get(k).cloned()).map(StopNode).collect())
+    }
+
+    /// Segments between consecutive stops, the first `limit` ones, up to 200.
+    #[graphql(complexity = "sequence_size(limit) * child_complexity")]
+    async fn segments(&self, ctx: &Context<'_>, limit: Option<u32>) -> Result<Vec<SegmentNode>> {
+        let Some(ty) = self.ty.clone() else {
+            return Ok(vec![]);
+        };
+        let keys = self.sequence().windows(2)
+            .take(sequence_size(limit))
+            .map(|w| SegmentKey { ty: ty.clone(), from: w[0], to: w[1] })
+            .collect::<Vec<_>>();
+        let segments = ctx.data::<Loaders>()?.segments.load_many(keys.iter().cloned()).await?;
+        Ok(keys.iter().filter_map(|k| segments.get(k).cloned()).map(SegmentNode).collect())
+    }
+}
+
+pub struct SegmentNode(pub Document);
+
+#[Object(name = "Segment")]
+impl SegmentNode {
+    async fn from(&self) -> Option<i32> {
+        int(&self.0, "from")
+    }
+
+    async fn to(&self) -> Option<i32> {
+        int(&self.0, "to")
+    }
+
+    #[graphql(name = "type")]
+    async fn ty(&self) -> Option<String> {
+        string(&self.0, "type")
+    }
+
+    /// Coordinates of the segment, as served by `/api/v1/map/segment`.
+    async fn geometry(&self) -> Option<Json<Value>> {
+        json(&self.0, "geometry")
+    }
+}
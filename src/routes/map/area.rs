@@ -3,10 +3,12 @@ use std::fmt::Debug;
 use lazy_static::lazy_static;
 use rocket::form::Strict;
 use mongodb::bson::{doc, Document};
-use bruss_data::Area;
+use bruss_data::{Area, BrussType};
+use mongodb::IndexModel;
 use tt::AreaType;
-use super::{FromStringFormField,query::DBQuery,gen_generic_getters};
+use super::{FromStringFormField,query::DBQuery,sort::Sortable,gen_generic_getters};
 use super::pipeline::Pipeline;
+use crate::indexes::Indexed;
 
 
 #[derive(FromForm,Debug)]
@@ -20,11 +22,24 @@ impl DBQuery for AreaQuery {
     fn to_doc(self) -> Document {
         let mut d = Document::new();
         if let Some(ty) = self.ty.into_inner() { d.insert("type", ty.into_bson()); }
-        error!("{d:?}");
         d
     }
 }
 
+impl Sortable for AreaQuery {
+    const SORT_FIELDS: &'static [&'static str] = &["id", "label", "type"];
+}
+
+impl Indexed for AreaQuery {
+    fn collection() -> String {
+        Area::TYPE.collection().to_string()
+    }
+
+    fn indexes() -> Vec<IndexModel> {
+        vec![IndexModel::builder().keys(doc!{"type": 1, "id": 1}).build()]
+    }
+}
+
 gen_generic_getters!(Area, AreaQuery, u16);
 
 lazy_static!{
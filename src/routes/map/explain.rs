@@ -0,0 +1,174 @@
+use std::sync::{Arc, Mutex};
+
+use mongodb::bson::{doc, Bson, Document};
+use mongodb::Database;
+use mongodb::error::Error as MongoError;
+use rocket::Request;
+use serde::Serialize;
+
+use crate::config::API_CONFIG;
+use super::fields::Projected;
+use super::pipeline::BuiltPipeline;
+
+/// Header clients set to get the query plans of a request instead of its data.
+pub const EXPLAIN_HEADER: &str = "X-Debug-Explain";
+
+/// Query plans of the pipelines run while serving a request, collected when the client asked
+/// for them through `X-Debug-Explain`.
+///
+/// Explaining is only allowed in debug builds, or when enabled by the `explain` setting, as it
+/// exposes the internals of the database.
+#[derive(Default, Clone, Debug)]
+pub struct Explain {
+    enabled: bool,
+    reports: Arc<Mutex<Vec<ExplainReport>>>,
+}
+
+impl Explain {
+    /// Get the explain state of `request`.
+    pub fn of(request: &Request<'_>) -> Self {
+        request.local_cache(|| {
+            let requested = request.headers()
+                .get_one(EXPLAIN_HEADER)
+                .is_some_and(|v| v == "1" || v.eq_ignore_ascii_case("true"));
+            Explain {
+                enabled: requested && (cfg!(debug_assertions) || API_CONFIG.explain),
+                reports: Default::default(),
+            }
+        }).clone()
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn record(&self, report: ExplainReport) {
+        if let Ok(mut r) = self.reports.lock() {
+            r.push(report);
+        }
+    }
+
+    /// Reports collected so far, or `None` if the request isn't being explained.
+    pub fn reports(&self) -> Option<Vec<ExplainReport>> {
+        if !self.enabled {
+            return None;
+        }
+        self.reports.lock().ok().map(|r| r.clone())
+    }
+}
+
+/// Outcome of the `explain` command for a pipeline.
+#[derive(Serialize, Clone, Debug)]
+pub struct ExplainReport {
+    collection: String,
+    /// Pipelines as generated by the route, before being merged in a single `$facet`.
+    fetch: Vec<Projected>,
+    count: Vec<Projected>,
+    /// Pipeline actually run on the database.
+    executed: Vec<Projected>,
+    summary: ExplainSummary,
+    explain: Projected,
+}
+
+/// Figures of the explain output worth a first look.
+#[derive(Serialize, Clone, Debug)]
+struct ExplainSummary {
+    indexes: Vec<String>,
+    docs_examined: Option<i64>,
+    keys_examined: Option<i64>,
+    execution_time_ms: Option<i64>,
+}
+
+impl ExplainSummary {
+    fn of(explain: &Document) -> Self {
+        let mut indexes = Vec::new();
+        collect_strings(&Bson::Document(explain.clone()), "indexName", &mut indexes);
+        indexes.sort();
+        indexes.dedup();
+        ExplainSummary {
+            indexes,
+            docs_examined: sum_ints(explain, "totalDocsExamined"),
+            keys_examined: sum_ints(explain, "totalKeysExamined"),
+            execution_time_ms: sum_ints(explain, "executionTimeMillis"),
+        }
+    }
+}
+
+/// Collect the values of all the string fields named `key` in `value`, at any depth.
+fn collect_strings(value: &Bson, key: &str, out: &mut Vec<String>) {
+    match value {
+        Bson::Document(d) => for (k, v) in d.iter() {
+            match v {
+                Bson::String(s) if k == key => out.push(s.clone()),
+                v => collect_strings(v, key, out),
+            }
+        },
+        Bson::Array(a) => a.iter().for_each(|v| collect_strings(v, key, out)),
+        _ => {}
+    }
+}
+
+/// Sum of the values of the top-most numeric fields named `key` in `doc`: aggregations on
+/// sharded collections report one of them per shard.
+fn sum_ints(doc: &Document, key: &str) -> Option<i64> {
+    fn walk(value: &Bson, key: &str, found: &mut Option<i64>) {
+        match value {
+            Bson::Document(d) => for (k, v) in d.iter() {
+                let n = match v {
+                    Bson::Int32(n) if k == key => Some(*n as i64),
+                    Bson::Int64(n) if k == key => Some(*n),
+                    Bson::Double(n) if k == key => Some(*n as i64),
+                    _ => None,
+                };
+                match n {
+                    Some(n) => *found = Some(found.unwrap_or(0) + n),
+                    None => walk(v, key, found),
+                }
+            },
+            Bson::Array(a) => a.iter().for_each(|v| walk(v, key, found)),
+            _ => {}
+        }
+    }
+    let mut found = None;
+    walk(&Bson::Document(doc.clone()), key, &mut found);
+    found
+}
+
+fn projected(stages: &[Document]) -> Vec<Projected> {
+    stages.iter().cloned().map(Projected::from).collect()
+}
+
+/// Explain the aggregation `executed` on `collection`, reporting the route's pipeline along with
+/// the plan.
+pub async fn explain_aggregate(db: &Database, collection: &str, pipeline: &BuiltPipeline, executed: Vec<Document>) -> Result<ExplainReport, MongoError> {
+    let mut aggregate = doc!{"aggregate": collection, "pipeline": executed.clone(), "cursor": {}};
+    if let Some(ref collation) = pipeline.collation {
+        aggregate.insert("collation", mongodb::bson::to_document(collation)?);
+    }
+    let explain = db.run_command(doc!{"explain": aggregate, "verbosity": "executionStats"}, None).await?;
+    Ok(ExplainReport {
+        collection: collection.to_owned(),
+        fetch: projected(&pipeline.fetch),
+        count: projected(&pipeline.count),
+        executed: projected(&executed),
+        summary: ExplainSummary::of(&explain),
+        explain: explain.into(),
+    })
+}
+
+/// Explain the lookup of a single document matching `filter` on `collection`.
+pub async fn explain_find(db: &Database, collection: &str, filter: Document, projection: Option<Document>) -> Result<ExplainReport, MongoError> {
+    let mut find = doc!{"find": collection, "filter": filter.clone(), "limit": 1};
+    if let Some(projection) = projection {
+        find.insert("projection", projection);
+    }
+    let explain = db.run_command(doc!{"explain": find, "verbosity": "executionStats"}, None).await?;
+    Ok(ExplainReport {
+        collection: collection.to_owned(),
+        fetch: projected(&[doc!{"$match": filter}]),
+        count: vec![],
+        executed: vec![],
+        summary: ExplainSummary::of(&explain),
+        explain: explain.into(),
+    })
+}
@@ -0,0 +1,129 @@
+use std::marker::PhantomData;
+
+use bruss_data::{Area, Route, Stop, Trip};
+use chrono::SecondsFormat;
+use mongodb::bson::{Bson, Document};
+use rocket::form::{self, FromFormField, ValueField};
+use serde::{Deserialize, Serialize};
+
+/// Types whose documents can be projected on a subset of their fields.
+pub trait Projectable {
+    /// Names of the fields that can be selected by clients.
+    const FIELDS: &'static [&'static str];
+}
+
+impl Projectable for Area {
+    const FIELDS: &'static [&'static str] = &["id", "label", "type"];
+}
+
+impl Projectable for Route {
+    const FIELDS: &'static [&'static str] = &["id", "type", "area", "area_ty", "color", "name", "code"];
+}
+
+impl Projectable for Stop {
+    const FIELDS: &'static [&'static str] = &["id", "code", "description", "position", "altitude", "name", "town", "type", "wheelchair_boarding"];
+}
+
+impl Projectable for Trip {
+    const FIELDS: &'static [&'static str] = &["id", "delay", "direction", "next_stop", "last_stop", "bus_id", "route", "headsign", "path", "times", "type"];
+}
+
+/// `fields` query parameter: a comma-separated list of fields of `T` to be returned instead of
+/// the whole documents, validated against `T::FIELDS`.
+#[derive(Debug)]
+pub struct Fields<T: Projectable> {
+    fields: Vec<String>,
+    _type: PhantomData<fn() -> T>,
+}
+
+impl<T: Projectable> Fields<T> {
+    /// Mongodb `$project` specification including the selected fields, nested under `prefix` if
+    /// given.
+    pub fn projection(&self, prefix: Option<&str>) -> Document {
+        let mut d = Document::new();
+        for f in self.fields.iter() {
+            match prefix {
+                Some(p) => d.insert(format!("{}.{}", p, f), 1),
+                None => d.insert(f, 1),
+            };
+        }
+        d
+    }
+}
+
+impl<'v, T: Projectable> FromFormField<'v> for Fields<T> {
+    fn from_value(field: ValueField<'v>) -> form::Result<'v, Self> {
+        let mut fields = Vec::new();
+        for f in field.value.split(',').map(str::trim).filter(|f| !f.is_empty()) {
+            if !T::FIELDS.contains(&f) {
+                return Err(form::Error::validation(format!("unknown field `{}`, expected one of: {}", f, T::FIELDS.join(", ")))
+                    .with_value(field.value)
+                    .into());
+            }
+            if !fields.iter().any(|s| s == f) {
+                fields.push(f.to_owned());
+            }
+        }
+        if fields.is_empty() {
+            return Err(form::Error::validation("no field selected").with_value(field.value).into());
+        }
+        Ok(Fields { fields, _type: PhantomData })
+    }
+}
+
+/// Partial document, returned by queries with a projection.
+///
+/// It's serialized with the same conventions as the full `bruss_data` types, rather than as
+/// extended JSON: dates become RFC 3339 strings and numbers stay plain numbers.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(transparent)]
+pub struct Projected(Document);
+
+impl From<Document> for Projected {
+    fn from(value: Document) -> Self {
+        Projected(value)
+    }
+}
+
+impl Projected {
+    pub fn document(&self) -> &Document {
+        &self.0
+    }
+
+    pub fn into_document(self) -> Document {
+        self.0
+    }
+
+    /// `value` as plain JSON, with the conventions of the `bruss_data` types.
+    pub(crate) fn plain(value: &Bson) -> serde_json::Value {
+        match value {
+            Bson::Document(d) => serde_json::Value::Object(d.iter()
+                .map(|(k, v)| (k.clone(), Self::plain(v)))
+                .collect()),
+            Bson::Array(a) => serde_json::Value::Array(a.iter().map(Self::plain).collect()),
+            Bson::DateTime(dt) => serde_json::Value::String(dt.to_chrono().to_rfc3339_opts(SecondsFormat::AutoSi, true)),
+            Bson::ObjectId(id) => serde_json::Value::String(id.to_hex()),
+            other => other.clone().into_relaxed_extjson(),
+        }
+    }
+}
+
+impl Serialize for Projected {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where S: serde::Serializer {
+        let Self(inner) = self;
+        serde_json::Value::Object(inner.iter()
+            .map(|(k, v)| (k.clone(), Self::plain(v)))
+            .collect()
+        ).serialize(serializer)
+    }
+}
+
+/// Either a whole document of type `T`, or a partial one if the client selected a subset of
+/// its fields.
+#[derive(Serialize)]
+#[serde(untagged)]
+pub enum Sparse<T> {
+    Full(T),
+    Partial(Projected),
+}
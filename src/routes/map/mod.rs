@@ -7,6 +7,10 @@ pub mod trip;
 pub mod path;
 pub mod segment;
 pub mod pipeline;
+pub mod fields;
+pub mod sort;
+pub mod search;
+pub mod explain;
 
 // pub use route::{get_route,get_route_opt};
 // pub use stop::{get_stop,get_stop_opt};
@@ -49,53 +53,66 @@ impl<T> FromStringFormField<T> where T: FromStr + ToString {
 
 macro_rules! gen_generic_getters {
     ($type:ident, $query:ty, $id_type:ident) => {
-        #[get("/<id>")]
+        #[get("/<id>?<fields>")]
         pub async fn get(
-            db: rocket_db_pools::Connection<crate::BrussData>,
+            db: super::query::DBInterface,
             id: Result<super::params::Id<$id_type>, <super::params::Id<$id_type> as rocket::request::FromParam<'_>>::Error>,
-        ) -> crate::response::ApiResponse<$type> {
-            super::query::UniformQueryable::<$type>::query_single(&super::query::DBInterface(db), Pipeline::from(id?.to_doc()).build()).await.into()
+            fields: rocket::form::Result<'_, Option<super::fields::Fields<$type>>>,
+        ) -> crate::response::ApiResponse<super::fields::Sparse<$type>> {
+            super::query::SparseQueryable::<$type, $type>::query_single_sparse(&db, Pipeline::from(id?.to_doc()).project(fields?).build()).await.into()
         }
 
-        #[get("/?<limit>&<skip>&<query..>")]
+        #[get("/?<limit>&<skip>&<envelope>&<fields>&<sort>&<count>&<query..>")]
         pub async fn get_opts(
-            db: rocket_db_pools::Connection<crate::BrussData>, 
+            db: super::query::DBInterface, 
             query: rocket::form::Result<'_, rocket::form::Strict<$query>>,
             limit: Option<u32>,
             skip: Option<u32>,
-        ) -> crate::response::ApiResponse<Vec<$type>> {
-            super::query::UniformQueryable::<$type>::query(
-                &super::query::DBInterface(db), 
+            envelope: Option<bool>,
+            fields: rocket::form::Result<'_, Option<super::fields::Fields<$type>>>,
+            sort: rocket::form::Result<'_, Option<super::sort::Sort<$query>>>,
+            count: rocket::form::Result<'_, Option<super::pipeline::CountMode>>,
+        ) -> crate::response::ApiResponse<Vec<super::fields::Sparse<$type>>> {
+            crate::response::ApiResponse::from(super::query::SparseQueryable::<$type, $type>::query_sparse(
+                &db, 
                 Pipeline::from(query?.into_inner())
                     .limit(limit)
                     .skip(skip)
-            ).await.into()
+                    .sort_by(sort?)
+                    .count_mode(count?)
+                    .project(fields?)
+            ).await).envelope(envelope)
         }
     };
 }
 
 macro_rules! gen_area_getters {
     ($type:ident, $query:ty, $id_type:ident) => {
-        #[get("/<area_type>/<id>?<limit>")]
+        #[get("/<area_type>/<id>?<limit>&<fields>")]
         pub async fn get(
-            db: rocket_db_pools::Connection<crate::BrussData>, 
+            db: super::query::DBInterface, 
             area_type: Result<super::params::Id<super::FromStringFormField<AreaType>>, <super::params::Id<super::FromStringFormField<AreaType>> as rocket::request::FromParam<'_>>::Error>,
             id: Result<super::params::Id<$id_type>, <super::params::Id<$id_type> as rocket::request::FromParam<'_>>::Error>,
-            limit: Option<u32>
-        ) -> crate::response::ApiResponse<$type> {
+            limit: Option<u32>,
+            fields: rocket::form::Result<'_, Option<super::fields::Fields<$type>>>,
+        ) -> crate::response::ApiResponse<super::fields::Sparse<$type>> {
             let mut d = id?.to_doc();
             d.insert("type", area_type?.value().into_bson());
-            UniformQueryable::<$type>::query_single(&DBInterface(db), Pipeline::from(d).limit(limit)).await.into()
+            super::query::SparseQueryable::<$type, $type>::query_single_sparse(&db, Pipeline::from(d).limit(limit).project(fields?)).await.into()
         }
 
-        #[get("/?<skip>&<limit>&<query..>")]
+        #[get("/?<skip>&<limit>&<envelope>&<fields>&<sort>&<count>&<query..>")]
         pub async fn get_opts(
-            db: Connection<BrussData>, 
+            db: DBInterface, 
             query: rocket::form::Result<'_, Strict<$query>>,
             skip: Option<u32>,
             limit: Option<u32>,
-        ) -> ApiResponse<Vec<$type>> {
-            UniformQueryable::<$type>::query(&DBInterface(db), Pipeline::from(query?.into_inner()).skip(skip).limit(limit)).await.into()
+            envelope: Option<bool>,
+            fields: rocket::form::Result<'_, Option<super::fields::Fields<$type>>>,
+            sort: rocket::form::Result<'_, Option<super::sort::Sort<$query>>>,
+            count: rocket::form::Result<'_, Option<super::pipeline::CountMode>>,
+        ) -> ApiResponse<Vec<super::fields::Sparse<$type>>> {
+            ApiResponse::from(super::query::SparseQueryable::<$type, $type>::query_sparse(&db, Pipeline::from(query?.into_inner()).skip(skip).limit(limit).sort_by(sort?).count_mode(count?).project(fields?)).await).envelope(envelope)
         }
     };
 }
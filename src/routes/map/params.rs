@@ -11,30 +11,59 @@ pub struct Id<T: Serialize> {
     inner: T
 }
 
+/// Error in parsing a path parameter: it reports which parameter is invalid, its raw value and
+/// the reason why it could not be parsed.
 #[derive(Debug)]
-pub struct ParamError<T: std::error::Error>(T);
+pub struct ParamError<T: std::error::Error> {
+    name: &'static str,
+    value: String,
+    inner: T,
+}
+
+impl<T: std::error::Error> ParamError<T> {
+    pub fn new(name: &'static str, value: &str, inner: T) -> Self {
+        ParamError { name, value: value.to_owned(), inner }
+    }
 
-impl<T: std::error::Error> From<T> for ParamError<T> {
-    fn from(value: T) -> Self {
-        ParamError(value)
+    pub fn name(&self) -> &'static str {
+        self.name
     }
+
+    pub fn value(&self) -> &str {
+        &self.value
+    }
+
+    pub fn reason(&self) -> String {
+        self.inner.to_string()
+    }
+}
+
+/// Name of the path parameter a type is parsed from, reported in parameter errors.
+pub trait NamedParam {
+    const NAME: &'static str;
+}
+
+impl NamedParam for AreaType {
+    const NAME: &'static str = "area_type";
 }
 
 impl<'a> FromParam<'a> for Id<u16> {
     type Error = ParamError<ParseIntError>;
 
     fn from_param(param: &'a str) -> Result<Self, Self::Error> {
-        Ok(Id { inner: param.parse::<u16>()? })
+        param.parse::<u16>()
+            .map(|v| Id { inner: v })
+            .map_err(|e| ParamError::new("id", param, e))
     }
 }
 
-impl<'a, T> FromParam<'a> for Id<FromStringFormField<T>> where T: FromStr + Serialize, T::Err: std::error::Error {
+impl<'a, T> FromParam<'a> for Id<FromStringFormField<T>> where T: FromStr + Serialize + NamedParam, T::Err: std::error::Error {
     type Error = ParamError<T::Err>;
 
     fn from_param(param: &'a str) -> Result<Self, Self::Error> {
         param.parse()
             .map(|v| Id { inner: FromStringFormField { inner: v } })
-            .map_err(|e| ParamError(e))
+            .map_err(|e| ParamError::new(T::NAME, param, e))
     }
 }
 
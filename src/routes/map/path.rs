@@ -1,14 +1,24 @@
-use bruss_data::Path;
+use bruss_data::{BrussType, Path};
 use lazy_static::lazy_static;
-use crate::db::BrussData;
 use crate::response::ApiResponse;
 use mongodb::bson::doc;
-use rocket_db_pools::Connection;
+use mongodb::IndexModel;
+use crate::indexes::Indexed;
 use super::{pipeline::Pipeline, query::{DBInterface, UniformQueryable}};
 
 #[get("/<paths>")]
-pub async fn get(db: Connection<BrussData>, paths: &str) -> ApiResponse<Vec<Path>> {
-    UniformQueryable::<Path>::query(&DBInterface(db), Pipeline::from(doc!{"id": {"$in": paths.split(",").collect::<Vec<&str>>()}})).await.into()
+pub async fn get(db: DBInterface, paths: &str) -> ApiResponse<Vec<Path>> {
+    UniformQueryable::<Path>::query(&db, Pipeline::from(doc!{"id": {"$in": paths.split(",").collect::<Vec<&str>>()}})).await.into()
+}
+
+impl Indexed for Path {
+    fn collection() -> String {
+        Path::TYPE.collection().to_string()
+    }
+
+    fn indexes() -> Vec<IndexModel> {
+        vec![IndexModel::builder().keys(doc!{"id": 1}).build()]
+    }
 }
 
 lazy_static!{
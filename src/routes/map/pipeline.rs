@@ -2,8 +2,36 @@ use std::fmt::Display;
 
 use bruss_config::CONFIGS;
 use mongodb::bson::{doc, Document};
+use mongodb::options::Collation;
+use rocket::form::{self, FromFormField, ValueField};
 
-use super::query::DBQuery;
+use super::fields::{Fields, Projectable};
+use super::sort::{Sort, Sortable};
+use super::query::{DBQuery, QueryError};
+
+/// How the total number of results of a list is computed, selected by the `count` query
+/// parameter.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CountMode {
+    /// Don't count: the `X-Total-Count` header and the last page link are omitted.
+    None,
+    /// Count the matching documents, in the same round-trip as the fetch.
+    #[default]
+    Exact,
+    /// Use the collection metadata when the list is not filtered, otherwise count exactly.
+    Estimated,
+}
+
+impl<'v> FromFormField<'v> for CountMode {
+    fn from_value(field: ValueField<'v>) -> form::Result<'v, Self> {
+        match field.value {
+            "none" => Ok(CountMode::None),
+            "exact" => Ok(CountMode::Exact),
+            "estimated" => Ok(CountMode::Estimated),
+            _ => Err(form::Error::validation("expected one of `none`, `exact`, `estimated`").with_value(field.value).into()),
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct Pipeline {
@@ -12,6 +40,10 @@ pub struct Pipeline {
     skip: i64,
     pre_sort: Document,
     sort: Document,
+    collation: Option<Collation>,
+    projection: Option<Document>,
+    count_mode: CountMode,
+    cached: bool,
 }
 
 #[allow(dead_code)]
@@ -23,6 +55,10 @@ impl Pipeline {
             limit: Self::default_limit(),
             pre_sort: doc!{},
             sort: doc!{"_id": 1},
+            collation: None,
+            projection: None,
+            count_mode: CountMode::default(),
+            cached: true,
         }
     }
 
@@ -62,8 +98,40 @@ impl Pipeline {
         self
     }
 
+    /// Sort by the fields selected by the client, if any, with a locale-aware collation.
+    pub fn sort_by<Q: Sortable>(mut self, sort: Option<Sort<Q>>) -> Self {
+        if let Some(sort) = sort {
+            self.sort = sort.to_doc();
+            self.collation = Some(Sort::<Q>::collation());
+        }
+        self
+    }
+
+    /// Only return the fields selected by the client, if any.
+    pub fn project<T: Projectable>(mut self, fields: Option<Fields<T>>) -> Self {
+        self.projection = fields.map(|f| {
+            let mut p = doc!{"_id": 0};
+            p.extend(f.projection(None));
+            p
+        });
+        self
+    }
+
+    /// Compute the total number of results as selected by the client, exactly by default.
+    pub fn count_mode(mut self, mode: Option<CountMode>) -> Self {
+        self.count_mode = mode.unwrap_or_default();
+        self
+    }
+
+    /// Keep the results out of the query cache, for queries on free-form input such as search
+    /// terms, which would fill it with entries hardly ever asked for again.
+    pub fn uncached(mut self) -> Self {
+        self.cached = false;
+        self
+    }
+
     pub fn custom(fetch: Vec<Document>, count: Vec<Document>) -> CustomPipeline {
-        CustomPipeline { fetch, count }
+        CustomPipeline { fetch, count, skip: 0, limit: Self::default_limit(), projection: None, count_mode: CountMode::default(), cached: true }
     }
 }
 
@@ -83,6 +151,40 @@ impl From<Document> for Pipeline {
 pub(crate) struct CustomPipeline {
     fetch: Vec<Document>,
     count: Vec<Document>,
+    skip: i64,
+    limit: i64,
+    projection: Option<Document>,
+    count_mode: CountMode,
+    cached: bool,
+}
+
+impl CustomPipeline {
+    /// Record the `$skip` and `$limit` values used by the fetch stages, so that they can be
+    /// reported back to the client along with the results.
+    pub fn paged(mut self, skip: i64, limit: i64) -> Self {
+        self.skip = skip;
+        self.limit = limit;
+        self
+    }
+
+    /// Mark the results as partial documents, projected by the `$project` stage `projection`
+    /// already included in the fetch stages.
+    pub fn projected(mut self, projection: Option<Document>) -> Self {
+        self.projection = projection;
+        self
+    }
+
+    /// Compute the total number of results as selected by the client, exactly by default.
+    pub fn count_mode(mut self, mode: Option<CountMode>) -> Self {
+        self.count_mode = mode.unwrap_or_default();
+        self
+    }
+
+    /// Keep the results out of the query cache, as [`Pipeline::uncached`].
+    pub fn uncached(mut self) -> Self {
+        self.cached = false;
+        self
+    }
 }
 
 impl Display for CustomPipeline {
@@ -98,14 +200,57 @@ pub(crate) struct BuiltPipeline {
     pub count: Vec<Document>,
     pub fetch: Vec<Document>,
     pub query: Option<Document>,
+    pub skip: i64,
+    pub limit: i64,
+    /// `$project` specification of the fetched documents, if they are partial.
+    pub projection: Option<Document>,
+    /// Collation of the fetch stages, if they sort on client-selected fields.
+    pub collation: Option<Collation>,
+    pub count_mode: CountMode,
+    /// Whether the results may be kept in the query cache.
+    pub cached: bool,
 }
 
 impl BuiltPipeline {
-    pub fn query(self) -> Document {
-        if self.query.is_none() {
-            panic!("custom pipelines cannot be used to query a single document");
+    /// Filter of the pipeline, to look up a single document with. Custom pipelines have none.
+    pub fn query(self) -> Result<Document, QueryError> {
+        self.query.ok_or_else(|| QueryError::Unsupported("custom pipelines have no filter to look up a single document with".to_owned()))
+    }
+
+    /// Pipeline fetching only the first result of this one, without counting.
+    pub fn first(mut self) -> Self {
+        self.fetch.push(doc!{"$limit": 1});
+        self.limit = 1;
+        self.count_mode = CountMode::None;
+        self
+    }
+
+    /// Whether the pipeline fetches from the whole collection, so that its size is also the
+    /// total number of results.
+    pub fn is_unfiltered(&self) -> bool {
+        self.query.as_ref().is_some_and(Document::is_empty)
+    }
+
+    /// Single pipeline returning both the fetched documents and their count, as a document
+    /// `{data: [...], count: [{count: n}]}`.
+    ///
+    /// The stages shared by the fetch and count pipelines run once, before the `$facet`, along
+    /// with the `$sort` following them, which doesn't change what is counted: this lets them use
+    /// the indexes, which stages inside a `$facet` cannot. Only the paging, projection and
+    /// counting stages are left inside.
+    pub fn facet(&self) -> Vec<Document> {
+        let mut shared = self.fetch.iter()
+            .zip(self.count.iter())
+            .take_while(|(f, c)| f == c)
+            .count();
+        let count = self.count[shared..].to_vec();
+        while self.fetch.get(shared).is_some_and(|s| s.contains_key("$sort")) {
+            shared += 1;
         }
-        self.query.unwrap()
+        let data = self.fetch[shared..].to_vec();
+        let mut stages = self.fetch[..shared].to_vec();
+        stages.push(doc!{"$facet": {"data": data, "count": count}});
+        stages
     }
 }
 
@@ -125,12 +270,20 @@ impl From<Pipeline> for BuiltPipeline {
         }
         fetch.push(doc!{"$skip": value.skip});
         fetch.push(doc!{"$limit": value.limit});
+        if let Some(ref projection) = value.projection {
+            fetch.push(doc!{"$project": projection.clone()});
+        }
         count.push(doc!{"$count": "count"});
-        info!("  Generated pipeline: {:?}", fetch);
         BuiltPipeline {
             count,
             fetch,
             query: Some(value.find),
+            skip: value.skip,
+            limit: value.limit,
+            projection: value.projection,
+            collation: value.collation,
+            count_mode: value.count_mode,
+            cached: value.cached,
         }
     }
 }
@@ -141,6 +294,45 @@ impl From<CustomPipeline> for BuiltPipeline {
             count: value.count,
             fetch: value.fetch,
             query: None,
+            skip: value.skip,
+            limit: value.limit,
+            projection: value.projection,
+            collation: None,
+            count_mode: value.count_mode,
+            cached: value.cached,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use mongodb::bson::doc;
+
+    use super::{BuiltPipeline, Pipeline};
+
+    #[test]
+    fn facet_runs_the_match_and_sort_first() {
+        let pipeline = Pipeline::new(doc!{"type": "u"})
+            .sort(doc!{"code": 1, "id": 1})
+            .skip(Some(20))
+            .limit(Some(10))
+            .build();
+        assert_eq!(pipeline.facet(), [
+            doc!{"$match": {"type": "u"}},
+            doc!{"$sort": {"code": 1, "id": 1}},
+            doc!{"$facet": {
+                "data": [{"$skip": 20_i64}, {"$limit": 10_i64}],
+                "count": [{"$count": "count"}],
+            }},
+        ]);
+
+        let custom = BuiltPipeline::from(Pipeline::custom(
+            vec![doc!{"$match": {"id": 1}}, doc!{"$project": {"id": 1}}],
+            vec![doc!{"$match": {"id": 1}}, doc!{"$count": "count"}],
+        ));
+        assert_eq!(custom.facet(), [
+            doc!{"$match": {"id": 1}},
+            doc!{"$facet": {"data": [{"$project": {"id": 1}}], "count": [{"$count": "count"}]}},
+        ]);
+    }
+}
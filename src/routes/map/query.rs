@@ -1,13 +1,141 @@
-use futures::{StreamExt, TryStreamExt};
-use mongodb::{bson::Document, Collection};
+use std::error::Error as StdError;
+use std::fmt::Display;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use mongodb::{bson::{Bson, Document}, error::ErrorKind, options::{AggregateOptions, FindOneOptions}};
+use rocket::request::{FromRequest, Outcome};
+use rocket::Request;
 use rocket_db_pools::Connection;
 use bruss_config::CONFIGS;
 use bruss_data::{BrussType, Schedule};
 use serde::{de::DeserializeOwned, Deserialize};
+use chrono::Utc;
 use tokio::time::Instant;
 use crate::db::BrussData;
+use crate::deadline::{self, Deadline};
+use crate::query_cache::{self, QueryCache};
+use crate::request_id::RequestId;
+use crate::slow_queries::{self, SlowQuery};
+use crate::storage::{Backend, MemoryStore, Storage};
 use mongodb::error::Error as MongoError;
-use super::{pipeline::{BuiltPipeline, Pipeline}, trip::TripCross};
+use super::{explain::{self, Explain}, fields::{Projected, Sparse}, pipeline::{BuiltPipeline, CountMode, Pipeline}, trip::TripCross};
+
+/// Time the client waits for the database past the deadline of a request, so that the database
+/// can report the expired `maxTimeMS` itself.
+const CLIENT_TIMEOUT_GRACE: Duration = Duration::from_millis(200);
+
+/// Failure of a query made through the [`Queryable`] traits.
+#[derive(Debug)]
+pub enum QueryError {
+    /// The database failed to run the query, or didn't run it within the deadline.
+    Database(MongoError),
+    /// A document returned by the database doesn't match the type it was read as.
+    Deserialize {
+        collection: String,
+        /// Id of the document, if it has one.
+        id: Option<String>,
+        source: mongodb::bson::de::Error,
+    },
+    /// The pipeline cannot be used for the requested operation.
+    Unsupported(String),
+}
+
+impl QueryError {
+    /// Whether the database could not be reached at all, as opposed to failing the query.
+    fn is_unavailable(&self) -> bool {
+        match self {
+            Self::Database(e) => matches!(*e.kind, ErrorKind::ServerSelection { .. } | ErrorKind::ConnectionPoolCleared { .. })
+                || matches!(*e.kind, ErrorKind::Io(_)) && !deadline::is_exceeded(e),
+            _ => false,
+        }
+    }
+
+    pub(crate) fn is_timeout(&self) -> bool {
+        matches!(self, Self::Database(e) if deadline::is_exceeded(e))
+    }
+
+    pub fn status(&self) -> u16 {
+        if self.is_timeout() {
+            504
+        } else if self.is_unavailable() {
+            503
+        } else {
+            500
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            _ if self.is_timeout() => "query.timeout",
+            _ if self.is_unavailable() => "query.unavailable",
+            Self::Database(_) => "query.failed",
+            Self::Deserialize { .. } => "query.malformed_document",
+            Self::Unsupported(_) => "query.unsupported",
+        }
+    }
+
+    pub fn detail(&self) -> String {
+        match self {
+            _ if self.is_timeout() => "the query took longer than allowed for this endpoint; try narrowing it down".to_owned(),
+            _ if self.is_unavailable() => "the database is currently unreachable".to_owned(),
+            _ => "an internal error occurred while querying the database".to_owned(),
+        }
+    }
+
+    /// Seconds after which the client may retry the request, for transient failures.
+    pub fn retry_after(&self) -> Option<u64> {
+        self.is_unavailable().then_some(5)
+    }
+}
+
+impl Display for QueryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Database(e) => write!(f, "database error: {}", e),
+            Self::Deserialize { collection, id, source } => write!(f, "cannot read document {} of `{}`: {}",
+                id.as_deref().unwrap_or("without id"), collection, source),
+            Self::Unsupported(what) => write!(f, "unsupported operation: {}", what),
+        }
+    }
+}
+
+impl StdError for QueryError {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match self {
+            Self::Database(e) => Some(e),
+            Self::Deserialize { source, .. } => Some(source),
+            Self::Unsupported(_) => None,
+        }
+    }
+}
+
+impl From<MongoError> for QueryError {
+    fn from(value: MongoError) -> Self {
+        QueryError::Database(value)
+    }
+}
+
+/// Id of `doc`, to point at it in errors: its `id`, the one of the trip it's about, or its `_id`.
+fn document_id(doc: &Document) -> Option<String> {
+    doc.get("id")
+        .or_else(|| doc.get_document("trip").ok().and_then(|t| t.get("id")))
+        .or_else(|| doc.get("_id"))
+        .map(|id| match id {
+            Bson::String(s) => s.clone(),
+            id => id.to_string(),
+        })
+}
+
+/// Read `doc`, from the collection of `X`, as a `T`.
+pub(crate) fn deserialize<T: DeserializeOwned, X: BrussType>(doc: &Document) -> Result<T, QueryError> {
+    mongodb::bson::from_document(doc.clone()).map_err(|source| QueryError::Deserialize {
+        collection: X::TYPE.collection().to_string(),
+        id: document_id(doc),
+        source,
+    })
+}
 
 /// Allow struct to be converted to a mongodb query.
 pub trait DBQuery {
@@ -24,21 +152,188 @@ impl DBQuery for RawDBQuery {
 }
 
 /// Interface for routes, to query the database.
-pub struct DBInterface(pub Connection<BrussData>);
+///
+/// Queries are run on MongoDB, or on the [`MemoryStore`] if one is managed by Rocket.
+///
+/// Carries the id of the request it's serving, so that database operations can be correlated
+/// with it in logs, the trace of the pipelines run for it, whether they should be explained
+/// rather than run, the deadline they must be run within and the cache of their results.
+pub struct DBInterface(pub Backend, pub RequestId, pub QueryTrace, pub Explain, pub Deadline, pub Option<QueryCache>);
+
+/// Pipelines run while serving a request, kept to be reported along with server errors.
+#[derive(Default, Clone, Debug)]
+pub struct QueryTrace(Arc<Mutex<Vec<String>>>);
+
+impl QueryTrace {
+    /// Get the trace of `request`.
+    pub fn of(request: &Request<'_>) -> Self {
+        request.local_cache(QueryTrace::default).clone()
+    }
+
+    pub fn record(&self, pipeline: String) {
+        if let Ok(mut p) = self.0.lock() {
+            p.push(pipeline);
+        }
+    }
+
+    pub fn pipelines(&self) -> Vec<String> {
+        self.0.lock().map(|p| p.clone()).unwrap_or_default()
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for DBInterface {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let id = RequestId::of(request);
+        let backend = match request.rocket().state::<MemoryStore>() {
+            Some(store) => Backend::Memory(store.clone()),
+            None => match request.guard::<Connection<BrussData>>().await {
+                Outcome::Success(c) => Backend::Mongo(c.database(CONFIGS.db.get_db())),
+                Outcome::Error((s, e)) => {
+                    error!("cannot get a database connection: {:?}", e);
+                    return Outcome::Error((s, ()));
+                }
+                Outcome::Forward(s) => return Outcome::Forward(s),
+            },
+        };
+        Outcome::Success(DBInterface(
+            backend,
+            id,
+            QueryTrace::of(request),
+            Explain::of(request),
+            Deadline::of(request),
+            request.rocket().state::<QueryCache>().cloned(),
+        ))
+    }
+}
 
 /// Interface for a database interface that can be used to obtain a specific collection of data
 /// implementing the BrussType trait.
 pub trait Collectable {
-    // fn get_coll<T: BrussType>(&self) -> Collection<T>;
-    fn get_coll_raw<T: BrussType, O>(&self) -> Collection<O>;
+    /// Storage the queries are run on.
+    fn storage(&self) -> &Backend;
+
+    /// Id of the request the queries are made for.
+    fn request_id(&self) -> &RequestId;
+
+    /// Trace of the pipelines run for the request.
+    fn trace(&self) -> &QueryTrace;
+
+    /// Query plans requested by the client, if any.
+    fn explain(&self) -> &Explain;
+
+    /// Deadline of the queries of the request.
+    fn deadline(&self) -> &Deadline;
+
+    /// Cache of the queries on static collections, if available.
+    fn cache(&self) -> Option<&QueryCache>;
+
+    /// Run `query` on the collection of `X` within the deadline of the request, recording it as
+    /// slow if it takes too long. `description` is the pipeline being run, for the records.
+    ///
+    /// The database is expected to enforce the deadline through `maxTimeMS` already: the client
+    /// side timeout only fires, a little later, if the database can't be reached in time.
+    async fn timed<X: BrussType, R>(&self, description: String, query: impl Future<Output = Result<R, QueryError>>) -> Result<R, QueryError> {
+        let start = Instant::now();
+        let result = match tokio::time::timeout(self.deadline().remaining()? + CLIENT_TIMEOUT_GRACE, query).await {
+            Ok(r) => r,
+            Err(_) => Err(QueryError::Database(deadline::exceeded())),
+        };
+        let elapsed = start.elapsed();
+        let timed_out = result.as_ref().is_err_and(QueryError::is_timeout);
+        if timed_out || slow_queries::is_slow(elapsed) {
+            slow_queries::record(self.storage().mongo().ok().cloned(), SlowQuery {
+                request_id: self.request_id().to_string(),
+                time: Utc::now(),
+                endpoint: self.deadline().endpoint().to_owned(),
+                collection: X::TYPE.collection().to_string(),
+                pipeline: description,
+                duration_ms: elapsed.as_millis() as u64,
+                timed_out,
+            });
+        }
+        result
+    }
+
+    /// Run the `fetch` stages on the collection of `X`.
+    async fn fetch<X: BrussType>(&self, fetch: Vec<Document>, options: AggregateOptions) -> Result<Vec<Document>, QueryError> {
+        self.storage().aggregate(&X::TYPE.collection(), fetch, options).await
+    }
+
+    /// Run `pipeline` on the collection of `X`, counting the results as selected by its
+    /// `count_mode`.
+    async fn run<X: BrussType>(&self, pipeline: BuiltPipeline) -> Result<(Vec<Document>, Option<usize>), QueryError> {
+        let options = AggregateOptions::builder()
+            .collation(pipeline.collation.clone())
+            .max_time(self.deadline().remaining()?)
+            .build();
+        match pipeline.count_mode {
+            CountMode::None => {
+                let fetch = pipeline.fetch;
+                let description = format!("{:?}", fetch);
+                let data = self.timed::<X, _>(description, self.fetch::<X>(fetch, options)).await?;
+                Ok((data, None))
+            }
+            CountMode::Estimated if pipeline.is_unfiltered() => {
+                let fetch = pipeline.fetch;
+                let description = format!("{:?}", fetch);
+                let (data, total) = self.timed::<X, _>(description, async {
+                    futures::try_join!(
+                        self.fetch::<X>(fetch, options),
+                        self.storage().estimated_count(&X::TYPE.collection()),
+                    )
+                }).await?;
+                Ok((data, Some(total as usize)))
+            }
+            CountMode::Exact | CountMode::Estimated => {
+                let facet = pipeline.facet();
+                let description = format!("{:?}", facet);
+                let facet = self.timed::<X, _>(description, self.fetch::<X>(facet, options)).await?
+                    .first()
+                    .map(deserialize::<FacetResult, X>)
+                    .transpose()?
+                    .unwrap_or_default();
+                Ok((facet.data, Some(facet.count.first().map(|c| c.count).unwrap_or(0) as usize)))
+            }
+        }
+    }
+
+    /// Look up the first document of the collection of `X` matching `query`.
+    async fn run_single<X: BrussType>(&self, query: Document, projection: Option<Document>) -> Result<Option<Document>, QueryError> {
+        let options = FindOneOptions::builder()
+            .projection(projection)
+            .max_time(self.deadline().remaining()?)
+            .build();
+        let description = format!("find_one {:?}", query);
+        self.timed::<X, _>(description, self.storage().find_one(&X::TYPE.collection(), query, options)).await
+    }
 }
 
 impl Collectable for DBInterface {
-    // fn get_coll<T: BrussType>(&self) -> Collection<T> {
-    //     T::get_coll(&self.0.database(CONFIGS.db.get_db()))
-    // }
-    fn get_coll_raw<T: BrussType, O>(&self) -> Collection<O> {
-        self.0.database(CONFIGS.db.get_db()).collection::<O>(T::TYPE.collection())
+    fn storage(&self) -> &Backend {
+        &self.0
+    }
+
+    fn request_id(&self) -> &RequestId {
+        &self.1
+    }
+
+    fn trace(&self) -> &QueryTrace {
+        &self.2
+    }
+
+    fn explain(&self) -> &Explain {
+        &self.3
+    }
+
+    fn deadline(&self) -> &Deadline {
+        &self.4
+    }
+
+    fn cache(&self) -> Option<&QueryCache> {
+        self.5.as_ref()
     }
 }
 
@@ -54,59 +349,77 @@ where
     T: DeserializeOwned + Sync + Unpin + Send,
     X: BrussType + Sync + Unpin + Send,
 {
-    async fn query(&self, pipeline: impl Into<BuiltPipeline>) -> Result<QueryResult<T>, MongoError> {
+    async fn query(&self, pipeline: impl Into<BuiltPipeline>) -> Result<QueryResult<T>, QueryError> {
         let pipeline: BuiltPipeline = pipeline.into();
-        let start = Instant::now();
-        let count = match self.get_coll_raw::<X, Vec<i64>>()
-            .aggregate(pipeline.count, None)
-            .await
-        {
-            Ok(c) => {
-                let res: Vec<CountResult> = c.map(|i| mongodb::bson::from_document::<CountResult>(i.unwrap())).try_collect().await.unwrap();
-                if res.is_empty() {
-                    0
-                } else {
-                    res[0].count
-                }
-            },
-            Err(e) => return Err(e)
-        } as usize;
-        let elapsed = start.elapsed();
-        if elapsed.as_secs_f32() > 0.5 {
-            log::warn!("count stage took {:?}", start.elapsed());
+        let (skip, limit) = (pipeline.skip as usize, pipeline.limit as usize);
+        info!("Generated pipeline: {:?}", pipeline.fetch);
+        self.trace().record(format!("{}: fetch {:?}, count {:?}", X::TYPE.collection(), pipeline.fetch, pipeline.count));
+        if self.explain().enabled() {
+            let executed = match pipeline.count_mode {
+                CountMode::None => pipeline.fetch.clone(),
+                CountMode::Estimated if pipeline.is_unfiltered() => pipeline.fetch.clone(),
+                CountMode::Exact | CountMode::Estimated => pipeline.facet(),
+            };
+            let report = explain::explain_aggregate(self.storage().mongo()?, &X::TYPE.collection(), &pipeline, executed).await?;
+            self.explain().record(report);
+            return Ok(QueryResult { data: vec![], total: None, skip, limit });
         }
-        let start = Instant::now();
-        let r = match self.get_coll_raw::<X, T>()
-            .aggregate(pipeline.fetch, None)
-            .await
-        {
-            Ok(found) => {
-                found.map(|i| mongodb::bson::from_document(i.unwrap()))
-                    .try_collect()
-                    .await
-                    .map(|result| QueryResult {
-                        data: result,
-                        total: count,
-                    })
-                    .map_err(MongoError::from)
+        let result = match self.cache().filter(|_| pipeline.cached && query_cache::is_cached::<X>()) {
+            Some(cache) => {
+                let key = QueryCache::key::<X>(&pipeline);
+                match cache.get(&key) {
+                    Some(hit) => hit,
+                    None => {
+                        let result = Arc::new(self.run::<X>(pipeline).await?);
+                        cache.insert(key, result.clone());
+                        result
+                    }
+                }
             }
-            Err(e) => Err(e)
+            None => Arc::new(self.run::<X>(pipeline).await?),
         };
-        let elapsed = start.elapsed();
-        if elapsed.as_secs_f32() > 0.5 {
-            log::warn!("fetch stage took {:?}", start.elapsed());
-        }
-        r
+        let (ref docs, total) = *result;
+        let data = docs.iter()
+            .map(deserialize::<T, X>)
+            .collect::<Result<Vec<T>, _>>()?;
+        Ok(QueryResult { data, total, skip, limit })
     }
 
-    async fn query_single(&self, pipeline: impl Into<BuiltPipeline>) -> Result<Option<T>, MongoError> {
-        self.get_coll_raw::<X, T>()
-            .find_one(pipeline.into().query(), None)
-            .await
+    /// Look up the first result of `pipeline`. Custom pipelines have no single filter to look
+    /// up, so they are run whole, limited to their first result.
+    async fn query_single(&self, pipeline: impl Into<BuiltPipeline>) -> Result<Option<T>, QueryError> {
+        let pipeline: BuiltPipeline = pipeline.into();
+        if pipeline.query.is_none() {
+            let result = Queryable::<T, X>::query(self, pipeline.first()).await?;
+            return Ok(result.data.into_iter().next());
+        }
+        self.trace().record(format!("{}: find_one {:?}", X::TYPE.collection(), pipeline.query));
+        let (projection, cached) = (pipeline.projection.clone(), pipeline.cached);
+        let query = pipeline.query()?;
+        if self.explain().enabled() {
+            let report = explain::explain_find(self.storage().mongo()?, &X::TYPE.collection(), query, projection).await?;
+            self.explain().record(report);
+            return Ok(None);
+        }
+        let doc = match self.cache().filter(|_| cached && query_cache::is_cached::<X>()) {
+            Some(cache) => {
+                let key = QueryCache::key_single::<X>(&query, &projection);
+                match cache.get(&key) {
+                    Some(hit) => hit.0.first().cloned(),
+                    None => {
+                        let doc = self.run_single::<X>(query, projection).await?;
+                        cache.insert(key, Arc::new((doc.iter().cloned().collect(), None)));
+                        doc
+                    }
+                }
+            }
+            None => self.run_single::<X>(query, projection).await?,
+        };
+        doc.as_ref().map(deserialize::<T, X>).transpose()
     }
 
     #[allow(dead_code)]
-    async fn query_db<Q: DBQuery>(&self, query: Q) -> Result<QueryResult<T>, MongoError> {
+    async fn query_db<Q: DBQuery>(&self, query: Q) -> Result<QueryResult<T>, QueryError> {
         Self::query(self, Pipeline::from(query)).await
     }
 }
@@ -122,15 +435,42 @@ where
 /// that an object that implements `Queryable<T>` can be used directly instead of using a trait
 /// object.
 pub trait UniformQueryable<T>: Queryable<T, T> where T: BrussType + Sync + Unpin + Send {
-    async fn query(&self, pipeline: impl Into<BuiltPipeline>) -> Result<QueryResult<T>, MongoError> {
+    async fn query(&self, pipeline: impl Into<BuiltPipeline>) -> Result<QueryResult<T>, QueryError> {
         Queryable::query(self, pipeline).await
     }
 
-    async fn query_single(&self, pipeline: impl Into<BuiltPipeline>) -> Result<Option<T>, MongoError> {
+    async fn query_single(&self, pipeline: impl Into<BuiltPipeline>) -> Result<Option<T>, QueryError> {
         Queryable::query_single(self, pipeline).await
     }
 }
 
+/// Trait for querying the database when the client may have selected only a subset of the
+/// fields of `T`: if the pipeline has a projection, the results are returned as partial
+/// documents instead of being deserialized into `T`.
+pub trait SparseQueryable<T, X>: Queryable<T, X> + Queryable<Projected, X>
+where
+    T: DeserializeOwned + Sync + Unpin + Send,
+    X: BrussType + Sync + Unpin + Send,
+{
+    async fn query_sparse(&self, pipeline: impl Into<BuiltPipeline>) -> Result<QueryResult<Sparse<T>>, QueryError> {
+        let pipeline: BuiltPipeline = pipeline.into();
+        if pipeline.projection.is_some() {
+            Queryable::<Projected, X>::query(self, pipeline).await.map(|r| r.map(Sparse::Partial))
+        } else {
+            Queryable::<T, X>::query(self, pipeline).await.map(|r| r.map(Sparse::Full))
+        }
+    }
+
+    async fn query_single_sparse(&self, pipeline: impl Into<BuiltPipeline>) -> Result<Option<Sparse<T>>, QueryError> {
+        let pipeline: BuiltPipeline = pipeline.into();
+        if pipeline.projection.is_some() {
+            Queryable::<Projected, X>::query_single(self, pipeline).await.map(|r| r.map(Sparse::Partial))
+        } else {
+            Queryable::<T, X>::query_single(self, pipeline).await.map(|r| r.map(Sparse::Full))
+        }
+    }
+}
+
 /// Reflective implementation of the `Queryable` trait for any type `T` implementing BrussType.
 impl<T: BrussType + Sync + Unpin + Send> Queryable<T, T> for DBInterface {}
 impl<T: BrussType + Sync + Unpin + Send> UniformQueryable<T> for DBInterface {}
@@ -140,14 +480,40 @@ impl<T: BrussType + Sync + Unpin + Send> UniformQueryable<T> for DBInterface {}
 /// stop.
 impl Queryable<TripCross, Schedule> for DBInterface {}
 
+/// Partial documents can be queried from any collection.
+impl<X: BrussType + Sync + Unpin + Send> Queryable<Projected, X> for DBInterface {}
+impl<T: BrussType + Sync + Unpin + Send> SparseQueryable<T, T> for DBInterface {}
+impl SparseQueryable<TripCross, Schedule> for DBInterface {}
+
 #[derive(Deserialize)]
 struct CountResult {
     count: i64,
 }
 
+/// Output of the `$facet` stage built by `BuiltPipeline::facet`.
+#[derive(Deserialize, Default)]
+struct FacetResult {
+    data: Vec<Document>,
+    count: Vec<CountResult>,
+}
+
 #[derive(Debug)]
 pub struct QueryResult<T> {
     pub data: Vec<T>,
-    pub total: usize,
+    /// Total number of results, unless the client opted out of counting them.
+    pub total: Option<usize>,
+    pub skip: usize,
+    pub limit: usize,
+}
+
+impl<T> QueryResult<T> {
+    pub fn map<U>(self, f: impl FnMut(T) -> U) -> QueryResult<U> {
+        QueryResult {
+            data: self.data.into_iter().map(f).collect(),
+            total: self.total,
+            skip: self.skip,
+            limit: self.limit,
+        }
+    }
 }
 
@@ -1,14 +1,14 @@
-use bruss_data::{Route, Schedule};
+use bruss_data::{BrussType, Route, Schedule, Trip};
 use lazy_static::lazy_static;
 use tt::AreaType;
-use crate::{db::BrussData, routes::map::{query::Queryable, trip::TripCross}};
+use crate::routes::map::{query::SparseQueryable, trip::{TripCross, TripCursor}};
 use mongodb::bson::{doc, Document};
-use rocket_db_pools::Connection;
-use super::{gen_generic_getters, params::{Id,ParamQuery}, query::{DBInterface, DBQuery}, trip::MultiTripQuery, FromStringFormField};
-use crate::response::ApiResponse;
+use mongodb::IndexModel;
+use super::{gen_generic_getters, fields::{Fields, Sparse}, params::{Id,ParamQuery}, query::{DBInterface, DBQuery}, sort::Sortable, trip::MultiTripQuery, FromStringFormField};
+use crate::{indexes::Indexed, response::ApiResponse};
 use rocket::form::Strict;
 use rocket::request::FromParam;
-use super::pipeline::Pipeline;
+use super::pipeline::{CountMode, Pipeline};
 
 #[derive(FromForm,Debug)]
 pub struct RouteQuery {
@@ -21,7 +21,6 @@ pub struct RouteQuery {
 impl DBQuery for RouteQuery {
     fn to_doc(self) -> Document {
         let mut d = Document::new();
-        error!("{self:?}");
         let RouteQuery { ty, area, id } = self;
         // if let Some(id) = id { d.insert("id", id as i32); }
         if let Some(ty) = ty.into_inner() { d.insert("area_ty", ty.into_bson()); }
@@ -31,23 +30,47 @@ impl DBQuery for RouteQuery {
     }
 }
 
+impl Sortable for RouteQuery {
+    const SORT_FIELDS: &'static [&'static str] = &["id", "code", "name", "area", "type"];
+}
+
+impl Indexed for RouteQuery {
+    fn collection() -> String {
+        Route::TYPE.collection().to_string()
+    }
+
+    fn indexes() -> Vec<IndexModel> {
+        vec![
+            IndexModel::builder().keys(doc!{"id": 1}).build(),
+            IndexModel::builder().keys(doc!{"area_ty": 1, "area": 1}).build(),
+        ]
+    }
+}
+
 gen_generic_getters!(Route, RouteQuery, u16);
 
-#[get("/<id>/trips?<limit>&<skip>&<query..>")]
+#[get("/<id>/trips?<limit>&<skip>&<envelope>&<fields>&<count>&<query..>")]
 async fn get_trips(
-    db: Connection<BrussData>,
+    db: DBInterface,
     id: Result<Id<u16>, <Id<u16> as FromParam<'_>>::Error>, 
     query: rocket::form::Result<'_, Strict<MultiTripQuery>>,
     limit: Option<u32>,
     skip: Option<u32>,
-) -> ApiResponse<Vec<TripCross>> {
+    envelope: Option<bool>,
+    fields: rocket::form::Result<'_, Option<Fields<Trip>>>,
+    count: rocket::form::Result<'_, Option<CountMode>>,
+) -> ApiResponse<Vec<Sparse<TripCross>>> {
     let id = id?.value();
 
     let pipeline = query?
         .into_inner()
-        .into_pipeline_route(id as u16, skip, limit);
+        .into_pipeline_route(id as u16, skip, limit, fields?)
+        .count_mode(count?);
+
+    let result = SparseQueryable::<TripCross, Schedule>::query_sparse(&db, pipeline).await?;
+    let cursor = TripCursor::after(&result, false);
 
-    Queryable::<TripCross, Schedule>::query(&DBInterface(db), pipeline).await.into()
+    ApiResponse::from(result).next_cursor(cursor).envelope(envelope)
 }
 
 lazy_static!{
@@ -0,0 +1,267 @@
+use bruss_data::{Area, BrussType, Route, Stop};
+use lazy_static::lazy_static;
+use mongodb::bson::{doc, Document};
+use rocket::form::{self, FromFormField, ValueField};
+use serde::{Deserialize, Serialize};
+
+use crate::response::ApiResponse;
+use super::pipeline::{CountMode, CustomPipeline, Pipeline};
+use super::query::{DBInterface, Queryable, QueryError};
+
+/// Kind of entity a suggestion refers to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    Stop,
+    Route,
+    Area,
+}
+
+impl Kind {
+    const ALL: [Kind; 3] = [Kind::Stop, Kind::Route, Kind::Area];
+
+    fn name(&self) -> &'static str {
+        match self {
+            Kind::Stop => "stop",
+            Kind::Route => "route",
+            Kind::Area => "area",
+        }
+    }
+
+    /// Fields matched against the search terms, and whether they must match from their start
+    /// (as route codes) rather than from the start of any word.
+    fn fields(&self) -> &'static [(&'static str, bool)] {
+        match self {
+            Kind::Stop => &[("name", false), ("town", false)],
+            Kind::Route => &[("code", true), ("name", false)],
+            Kind::Area => &[("label", false)],
+        }
+    }
+
+    /// `$project` stage giving every kind of document the shape of a `Candidate`, along with the
+    /// `rank` of its match: 0 for the labels starting with the search terms, 1 for those with a
+    /// word starting with them and 2 for the rest, as they are scored by [`SearchPrefix::score`].
+    fn project_stage(&self, terms: &SearchPrefix) -> Document {
+        let (label, detail, area_type) = match self {
+            Kind::Stop => ("$name", Some("$town"), "$type"),
+            // the `type` of routes is their GTFS route type
+            Kind::Route => ("$code", Some("$name"), "$area_ty"),
+            Kind::Area => ("$label", None, "$type"),
+        };
+        let matching = |anchored: bool| doc!{
+            "$regexMatch": {"input": {"$ifNull": [label, ""]}, "regex": terms.pattern(anchored), "options": "i"},
+        };
+        let mut projection = doc!{
+            "_id": 0,
+            "id": 1,
+            "label": label,
+            "area_type": area_type,
+            "rank": {"$cond": [matching(true), 0, {"$cond": [matching(false), 1, 2]}]},
+            "len": {"$strLenCP": {"$ifNull": [label, ""]}},
+        };
+        if let Some(detail) = detail {
+            projection.insert("detail", detail);
+        }
+        doc!{"$project": projection}
+    }
+
+    /// Pipeline of the `limit` best candidates of this kind: the best ranked matches come first
+    /// and, among them, the shortest labels, as they are the closest to what has been typed so far.
+    fn pipeline(&self, terms: &SearchPrefix, limit: i64) -> CustomPipeline {
+        let conds = self.fields().iter()
+            .map(|(f, anchored)| doc!{*f: {"$regex": terms.pattern(*anchored), "$options": "i"}})
+            .collect::<Vec<Document>>();
+        let match_stage = doc!{"$match": {"$or": conds}};
+        let fetch = vec![
+            match_stage.clone(),
+            self.project_stage(terms),
+            doc!{"$sort": {"rank": 1, "len": 1, "id": 1}},
+            doc!{"$limit": limit},
+        ];
+        let count = vec![match_stage, doc!{"$count": "count"}];
+        // suggestions are never paged, so there's no point in counting them
+        Pipeline::custom(fetch, count)
+            .paged(0, limit)
+            .count_mode(Some(CountMode::None))
+            .uncached()
+    }
+}
+
+/// `kinds` query parameter: comma-separated list of the kinds of entities to suggest.
+pub struct Kinds(Vec<Kind>);
+
+impl<'v> FromFormField<'v> for Kinds {
+    fn from_value(field: ValueField<'v>) -> form::Result<'v, Self> {
+        let mut kinds = Vec::new();
+        for k in field.value.split(',').map(str::trim).filter(|k| !k.is_empty()) {
+            match Kind::ALL.iter().find(|kind| kind.name() == k) {
+                Some(kind) if !kinds.contains(kind) => kinds.push(*kind),
+                Some(_) => {}
+                None => return Err(form::Error::validation(format!("unknown kind `{}`, expected one of: stop, route, area", k))
+                    .with_value(field.value)
+                    .into()),
+            }
+        }
+        if kinds.is_empty() {
+            return Err(form::Error::validation("no kind selected").with_value(field.value).into());
+        }
+        Ok(Kinds(kinds))
+    }
+}
+
+/// Fold `c` to its lowercase, unaccented form.
+fn fold_char(c: char) -> char {
+    match c.to_lowercase().next().unwrap_or(c) {
+        'à' | 'á' | 'â' | 'ä' => 'a',
+        'è' | 'é' | 'ê' | 'ë' => 'e',
+        'ì' | 'í' | 'î' | 'ï' => 'i',
+        'ò' | 'ó' | 'ô' | 'ö' => 'o',
+        'ù' | 'ú' | 'û' | 'ü' => 'u',
+        c => c,
+    }
+}
+
+pub(crate) fn fold(s: &str) -> String {
+    s.chars().map(fold_char).collect()
+}
+
+/// `q` query parameter: the text typed so far, matched as a prefix ignoring case and accents.
+pub struct SearchPrefix(String);
+
+impl SearchPrefix {
+    /// Regular expression matching the prefix at the start of the field or, unless `anchored`,
+    /// at the start of any of its words.
+    fn pattern(&self, anchored: bool) -> String {
+        let mut p = String::from(if anchored { "^" } else { "(?:^|[\\s/'(-])" });
+        for c in self.0.chars() {
+            match c {
+                'a' => p.push_str("[aàáâä]"),
+                'e' => p.push_str("[eèéêë]"),
+                'i' => p.push_str("[iìíîï]"),
+                'o' => p.push_str("[oòóôö]"),
+                'u' => p.push_str("[uùúûü]"),
+                c if c.is_alphanumeric() || c == ' ' => p.push(c),
+                c => {
+                    p.push('\\');
+                    p.push(c);
+                }
+            }
+        }
+        p
+    }
+
+    /// Relevance of a candidate: exact matches first, then prefixes of the label, of one of its
+    /// words and lastly of the detail, favouring the labels closer in length to the prefix.
+    fn score(&self, label: &str, detail: Option<&str>) -> f64 {
+        let label = fold(label);
+        let closeness = self.0.chars().count() as f64 / label.chars().count().max(1) as f64;
+        let word_prefix = |s: &str| s.split(|c: char| c.is_whitespace() || "/'(-".contains(c))
+            .any(|w| w.starts_with(&self.0));
+        if label == self.0 {
+            1.0
+        } else if label.starts_with(&self.0) {
+            0.6 + 0.3 * closeness
+        } else if word_prefix(&label) {
+            0.4 + 0.2 * closeness
+        } else if detail.map(fold).is_some_and(|d| d.starts_with(&self.0) || word_prefix(&d)) {
+            0.2
+        } else {
+            0.1
+        }
+    }
+}
+
+impl<'v> FromFormField<'v> for SearchPrefix {
+    fn from_value(field: ValueField<'v>) -> form::Result<'v, Self> {
+        let q = fold(field.value.trim());
+        if q.is_empty() {
+            return Err(form::Error::validation("search terms cannot be empty").into());
+        }
+        if q.len() > 64 {
+            return Err(form::Error::validation("search terms cannot be longer than 64 bytes").with_value(field.value).into());
+        }
+        Ok(SearchPrefix(q.split_whitespace().collect::<Vec<_>>().join(" ")))
+    }
+}
+
+/// Document of any kind, projected to the fields needed for a suggestion.
+#[derive(Deserialize)]
+struct Candidate {
+    id: u16,
+    #[serde(default)]
+    label: Option<String>,
+    #[serde(default)]
+    detail: Option<String>,
+    #[serde(default)]
+    area_type: Option<String>,
+}
+
+impl Queryable<Candidate, Stop> for DBInterface {}
+impl Queryable<Candidate, Route> for DBInterface {}
+impl Queryable<Candidate, Area> for DBInterface {}
+
+#[derive(Serialize)]
+pub struct Suggestion {
+    kind: Kind,
+    id: u16,
+    label: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<String>,
+    area_type: Option<String>,
+    score: f64,
+}
+
+impl Suggestion {
+    fn new(kind: Kind, terms: &SearchPrefix, c: Candidate) -> Self {
+        let label = c.label.unwrap_or_default();
+        let score = terms.score(&label, c.detail.as_deref());
+        Suggestion { kind, id: c.id, label, detail: c.detail, area_type: c.area_type, score }
+    }
+}
+
+/// Candidates of kind `X`, if it was requested.
+async fn candidates<X>(db: &DBInterface, kind: Kind, kinds: &[Kind], terms: &SearchPrefix, limit: i64) -> Result<Vec<Suggestion>, QueryError>
+where
+    X: BrussType + Sync + Unpin + Send,
+    DBInterface: Queryable<Candidate, X>,
+{
+    if !kinds.contains(&kind) {
+        return Ok(vec![]);
+    }
+    let result = Queryable::<Candidate, X>::query(db, kind.pipeline(terms, limit)).await?;
+    Ok(result.data.into_iter().map(|c| Suggestion::new(kind, terms, c)).collect())
+}
+
+/// Suggestions of stops, routes and areas for a search box, matching what has been typed so far.
+/// The collections are queried concurrently, and the results merged by score.
+#[get("/?<q>&<kinds>&<limit>")]
+async fn search(
+    db: DBInterface,
+    q: form::Result<'_, SearchPrefix>,
+    kinds: form::Result<'_, Option<Kinds>>,
+    limit: Option<u32>,
+) -> ApiResponse<Vec<Suggestion>> {
+    let terms = q?;
+    let kinds = kinds?.map(|k| k.0).unwrap_or_else(|| Kind::ALL.to_vec());
+    let limit = limit.map(|l| l.clamp(1, 50) as i64).unwrap_or(10);
+
+    let (stops, routes, areas) = futures::try_join!(
+        candidates::<Stop>(&db, Kind::Stop, &kinds, &terms, limit),
+        candidates::<Route>(&db, Kind::Route, &kinds, &terms, limit),
+        candidates::<Area>(&db, Kind::Area, &kinds, &terms, limit),
+    )?;
+
+    let mut suggestions = stops.into_iter()
+        .chain(routes)
+        .chain(areas)
+        .collect::<Vec<Suggestion>>();
+    suggestions.sort_by(|a, b| b.score.total_cmp(&a.score).then_with(|| a.label.len().cmp(&b.label.len())));
+    suggestions.truncate(limit as usize);
+
+    let total = suggestions.len();
+    ApiResponse::Ok(suggestions, Some(total))
+}
+
+lazy_static!{
+    pub static ref ROUTES: Vec<rocket::Route> = routes![search];
+}
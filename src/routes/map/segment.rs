@@ -1,13 +1,12 @@
-use bruss_data::{PolySegment, Segment};
+use bruss_data::{BrussType, PolySegment, Segment};
 use lazy_static::lazy_static;
 use rocket::request::FromParam;
 use tt::AreaType;
-use crate::db::BrussData;
 use mongodb::bson::{Document,doc};
-use rocket_db_pools::Connection;
-use super::{params::{Id, ParamError, ParamQuery}, pipeline::Pipeline, query::{DBInterface, UniformQueryable, QueryResult}, FromStringFormField};
+use mongodb::IndexModel;
+use super::{params::{Id, ParamError, ParamQuery}, pipeline::{CountMode, Pipeline}, query::{DBInterface, UniformQueryable, QueryResult}, FromStringFormField};
 use serde::{Serialize,Deserialize};
-use crate::response::ApiResponse;
+use crate::{indexes::Indexed, response::{ApiResponse, Paging}};
 use std::{error::Error as StdError, fmt::Display, num::ParseIntError};
 
 
@@ -20,29 +19,6 @@ enum FormatSelect {
     Coords,
 }
 
-#[derive(Debug)]
-struct FormatSelectParseError;
-
-impl Display for FormatSelectParseError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{:?}", self)
-    }
-}
-
-impl StdError for FormatSelectParseError {}
-
-impl<'a> FromParam<'a> for FormatSelect {
-    type Error = ParamError<FormatSelectParseError>;
-
-    fn from_param(param: &'a str) -> Result<Self, Self::Error> {
-        match param {
-            "poly" => Ok(Self::Polyline),
-            "coords" => Ok(Self::Coords),
-            _ => Err(ParamError::from(FormatSelectParseError))
-        }
-    }
-}
-
 struct StopPairs(Vec<(u16, u16)>);
 
 impl StopPairs {
@@ -65,26 +41,36 @@ impl StopPairs {
     }
 }
 
+/// Segments are looked up by the pairs of stops they connect.
+impl Indexed for Segment {
+    fn collection() -> String {
+        Segment::TYPE.collection().to_string()
+    }
+
+    fn indexes() -> Vec<IndexModel> {
+        vec![IndexModel::builder().keys(doc!{"type": 1, "from": 1, "to": 1}).build()]
+    }
+}
+
 #[derive(Debug)]
 enum StopPairsParseError {
-    InvalidPair,
-    ParseInt
+    /// The pair isn't in the form `from-to`.
+    InvalidPair(String),
+    /// A stop id of the pair isn't a valid id.
+    ParseInt(String, ParseIntError),
 }
 
 impl Display for StopPairsParseError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{:?}", self)
+        match self {
+            Self::InvalidPair(p) => write!(f, "invalid pair `{}`: expected `<from stop id>-<to stop id>`", p),
+            Self::ParseInt(p, e) => write!(f, "invalid stop id in pair `{}`: {}", p, e),
+        }
     }
 }
 
 impl StdError for StopPairsParseError {}
 
-impl From<ParseIntError> for StopPairsParseError {
-    fn from(_value: ParseIntError) -> Self {
-        Self::ParseInt
-    }
-}
-
 impl<'a> FromParam<'a> for StopPairs {
     type Error = ParamError<StopPairsParseError>;
 
@@ -96,15 +82,15 @@ impl<'a> FromParam<'a> for StopPairs {
             }
             let ss = p.split("-").collect::<Vec<&str>>();
             if ss.len() != 2 {
-                return Err(StopPairsParseError::InvalidPair.into())
+                return Err(ParamError::new("pairs", param, StopPairsParseError::InvalidPair(p.to_owned())))
             }
             let s0 = match ss[0].parse() {
                 Ok(v) => v,
-                Err(e) => return Err(<ParseIntError as Into<StopPairsParseError>>::into(e).into()),
+                Err(e) => return Err(ParamError::new("pairs", param, StopPairsParseError::ParseInt(p.to_owned(), e))),
             };
             let s1 = match ss[1].parse() {
                 Ok(v) => v,
-                Err(e) => return Err(<ParseIntError as Into<StopPairsParseError>>::into(e).into()),
+                Err(e) => return Err(ParamError::new("pairs", param, StopPairsParseError::ParseInt(p.to_owned(), e))),
             };
             pairs.push((s0, s1))
         }
@@ -113,22 +99,30 @@ impl<'a> FromParam<'a> for StopPairs {
 }
 
 
-#[get("/<area_type>/<pairs>?<format>")]
+#[get("/<area_type>/<pairs>?<format>&<limit>&<skip>&<envelope>&<count>")]
 async fn get<'a>(
-    db: Connection<BrussData>,
+    db: DBInterface,
     area_type: Result<Id<FromStringFormField<AreaType>>, <Id<FromStringFormField<AreaType>> as FromParam<'_>>::Error>,
     pairs: Result<StopPairs, ParamError<StopPairsParseError>>,
-    format: Option<FormatSelect>
+    format: rocket::form::Result<'_, Option<FormatSelect>>,
+    limit: Option<u32>,
+    skip: Option<u32>,
+    envelope: Option<bool>,
+    count: rocket::form::Result<'_, Option<CountMode>>,
 ) -> ApiResponse<SegmentFormatWrapper> {
-    let fmt = format.unwrap_or_default();
+    let fmt = format?.unwrap_or_default();
 
-    let pipeline= Pipeline::from(pairs?.to_doc(area_type?.value()));
+    let pipeline= Pipeline::from(pairs?.to_doc(area_type?.value()))
+        .limit(limit)
+        .skip(skip)
+        .count_mode(count?);
     
     let w: SegmentFormatWrapper = (
-        UniformQueryable::<Segment>::query(&DBInterface(db), pipeline.build()).await?,
+        UniformQueryable::<Segment>::query(&db, pipeline.build()).await?,
         fmt
     ).into();
-    w.into()
+    let r: ApiResponse<SegmentFormatWrapper> = w.into();
+    r.envelope(envelope)
 }
 
 struct SegmentFormatWrapper(QueryResult<Segment>, FormatSelect);
@@ -158,8 +152,8 @@ impl From<(QueryResult<Segment>, FormatSelect)> for SegmentFormatWrapper {
 
 impl Into<ApiResponse<SegmentFormatWrapper>> for SegmentFormatWrapper {
     fn into(self) -> ApiResponse<SegmentFormatWrapper> {
-        let c = self.0.data.len();
-        ApiResponse::Ok(self, Some(c))
+        let paging = Paging::from(&self.0);
+        ApiResponse::Page(self, paging)
     }
 }
 
@@ -0,0 +1,104 @@
+use std::marker::PhantomData;
+
+use mongodb::bson::Document;
+use mongodb::options::Collation;
+use rocket::form::{self, FromFormField, ValueField};
+
+/// Query types whose results can be sorted by the client.
+pub trait Sortable {
+    /// Names of the fields that can be used in the `sort` query parameter.
+    const SORT_FIELDS: &'static [&'static str];
+}
+
+/// `sort` query parameter: a comma-separated list of fields, each optionally prefixed by `-` for
+/// descending order (e.g. `sort=name,-id`), validated against `Q::SORT_FIELDS`.
+#[derive(Debug)]
+pub struct Sort<Q: Sortable> {
+    keys: Vec<(String, i32)>,
+    _query: PhantomData<fn() -> Q>,
+}
+
+impl<Q: Sortable> Sort<Q> {
+    /// Mongodb `$sort` specification. `_id` is always appended as the last key, so that the
+    /// order is total and stable across pages.
+    pub fn to_doc(&self) -> Document {
+        let mut d = Document::new();
+        for (k, dir) in self.keys.iter() {
+            d.insert(k, dir);
+        }
+        if !d.contains_key("_id") {
+            d.insert("_id", 1);
+        }
+        d
+    }
+
+    /// Collation used when sorting on client-selected fields: names are compared following the
+    /// italian rules, and the digits in codes by their numeric value (`5` before `13`).
+    pub fn collation() -> Collation {
+        Collation::builder()
+            .locale("it")
+            .numeric_ordering(true)
+            .build()
+    }
+}
+
+impl<'v, Q: Sortable> FromFormField<'v> for Sort<Q> {
+    fn from_value(field: ValueField<'v>) -> form::Result<'v, Self> {
+        let mut keys: Vec<(String, i32)> = Vec::new();
+        for f in field.value.split(',').map(str::trim).filter(|f| !f.is_empty()) {
+            let (name, dir) = match f.strip_prefix('-') {
+                Some(name) => (name, -1),
+                None => (f.strip_prefix('+').unwrap_or(f), 1),
+            };
+            if !Q::SORT_FIELDS.contains(&name) {
+                return Err(form::Error::validation(format!("cannot sort by `{}`, expected one of: {}", name, Q::SORT_FIELDS.join(", ")))
+                    .with_value(field.value)
+                    .into());
+            }
+            if keys.iter().any(|(k, _)| k == name) {
+                return Err(form::Error::validation(format!("field `{}` is repeated", name))
+                    .with_value(field.value)
+                    .into());
+            }
+            keys.push((name.to_owned(), dir));
+        }
+        if keys.is_empty() {
+            return Err(form::Error::validation("no sort field selected").with_value(field.value).into());
+        }
+        Ok(Sort { keys, _query: PhantomData })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mongodb::bson::doc;
+    use rocket::form::{FromFormField, ValueField};
+
+    use super::{Sort, Sortable};
+
+    struct Routes;
+
+    impl Sortable for Routes {
+        const SORT_FIELDS: &'static [&'static str] = &["id", "code", "name"];
+    }
+
+    fn parse(value: &str) -> Result<Sort<Routes>, String> {
+        Sort::<Routes>::from_value(ValueField::from_value(value)).map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn sort_keys() {
+        assert_eq!(parse("code").unwrap().to_doc(), doc!{"code": 1, "_id": 1});
+        assert_eq!(parse("name,-id").unwrap().to_doc(), doc!{"name": 1, "id": -1, "_id": 1});
+        assert_eq!(parse(" +code , -name ,").unwrap().to_doc(), doc!{"code": 1, "name": -1, "_id": 1});
+    }
+
+    #[test]
+    fn invalid_sorts() {
+        for value in ["", ",", "color", "-color", "--code", "+-code", "code:desc", "Code"] {
+            assert!(parse(value).is_err(), "{:?}", value);
+        }
+        assert!(parse("color").unwrap_err().contains("expected one of: id, code, name"));
+        assert!(parse("code,-code").unwrap_err().contains("`code` is repeated"));
+    }
+}
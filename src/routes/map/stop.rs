@@ -1,13 +1,12 @@
-use bruss_config::CONFIGS;
-use bruss_data::{Route, Schedule, Stop};
+use bruss_data::{BrussType, Route, Schedule, Stop, Trip};
 use lazy_static::lazy_static;
+use mongodb::{options::{IndexOptions, TextIndexVersion}, IndexModel};
 use tt::AreaType;
-use crate::db::BrussData;
-use super::{gen_area_getters, params::{Id, ParamQuery}, pipeline::Pipeline, query::{DBInterface, DBQuery, Queryable, UniformQueryable}, trip::{MultiTripQuery, TripCross}, FromStringFormField};
-use mongodb::bson::{doc, Document};
-use rocket_db_pools::Connection;
-use crate::response::ApiResponse;
-use rocket::{request::FromParam,form::Strict};
+use serde::Serialize;
+use super::{gen_area_getters, fields::{Fields, Projected, Sparse}, params::{Id, ParamQuery}, pipeline::{CountMode, Pipeline}, query::{deserialize, DBInterface, DBQuery, Queryable, QueryError, QueryResult, SparseQueryable, UniformQueryable}, sort::Sortable, trip::{MultiTripQuery, TripCross, TripCursor}, FromStringFormField};
+use mongodb::bson::{doc, Bson, Document};
+use crate::{indexes::Indexed, response::ApiResponse, storage::Storage};
+use rocket::{request::FromParam, form::{self, Strict}};
 
 
 #[derive(FromForm)]
@@ -25,48 +24,281 @@ impl DBQuery for StopQuery {
     }
 }
 
+impl Sortable for StopQuery {
+    const SORT_FIELDS: &'static [&'static str] = &["id", "code", "name", "town", "type"];
+}
+
+impl Indexed for StopQuery {
+    fn collection() -> String {
+        Stop::TYPE.collection().to_string()
+    }
+
+    /// Besides the lookups by area type and id, the text index of the stop search: stemming is
+    /// disabled, as stop names are mostly proper nouns, and the version 3 text index already
+    /// folds case and diacritics.
+    fn indexes() -> Vec<IndexModel> {
+        let search = IndexOptions::builder()
+            .name(SEARCH_INDEX.to_owned())
+            .weights(doc!{"name": 10, "code": 5, "town": 2})
+            .default_language("none".to_owned())
+            .text_index_version(TextIndexVersion::V3)
+            .build();
+        vec![
+            IndexModel::builder().keys(doc!{"type": 1, "id": 1}).build(),
+            // bounding boxes of the nearby stops; positions are `[lat, lon]`, which a 2dsphere
+            // index would read the other way around
+            IndexModel::builder().keys(doc!{"type": 1, "position.0": 1, "position.1": 1}).build(),
+            IndexModel::builder()
+                .keys(doc!{"name": "text", "town": "text", "code": "text"})
+                .options(search)
+                .build(),
+        ]
+    }
+}
+
 gen_area_getters!(Stop, StopQuery, u16);
 
+/// Name of the text index backing the stop search.
+const SEARCH_INDEX: &str = "stop_search";
+
+/// `q` query parameter of the stop search: the words to look for, at least one.
+pub struct SearchTerms(String);
+
+impl<'v> form::FromFormField<'v> for SearchTerms {
+    fn from_value(field: form::ValueField<'v>) -> form::Result<'v, Self> {
+        let q = field.value.trim();
+        if q.is_empty() {
+            return Err(form::Error::validation("search terms cannot be empty").into());
+        }
+        if q.len() > 128 {
+            return Err(form::Error::validation("search terms cannot be longer than 128 bytes").with_value(field.value).into());
+        }
+        Ok(SearchTerms(q.to_owned()))
+    }
+}
+
+/// Search stops by name, town and code. Matching ignores case and accents, and results are
+/// ranked by relevance, names weighting more than codes and towns.
+#[get("/search?<q>&<limit>&<skip>&<envelope>&<count>&<query..>")]
+async fn search(
+    db: DBInterface,
+    q: form::Result<'_, SearchTerms>,
+    query: form::Result<'_, Strict<StopQuery>>,
+    limit: Option<u32>,
+    skip: Option<u32>,
+    envelope: Option<bool>,
+    count: form::Result<'_, Option<CountMode>>,
+) -> ApiResponse<Vec<Stop>> {
+    let mut find = query?.into_inner().to_doc();
+    find.insert("$text", doc!{"$search": q?.0});
+
+    let pipeline = Pipeline::new(find)
+        .sort(doc!{"score": {"$meta": "textScore"}, "_id": 1})
+        .limit(limit)
+        .skip(skip)
+        .count_mode(count?)
+        .uncached();
+    ApiResponse::from(UniformQueryable::<Stop>::query(&db, pipeline).await).envelope(envelope)
+}
+
+/// Mean radius of the Earth, in meters.
+const EARTH_RADIUS_M: f64 = 6_371_008.8;
+/// Meters in a degree of latitude.
+const DEGREE_M: f64 = 111_195.;
+/// Radius of `/near` unless requested otherwise, in meters.
+const DEFAULT_RADIUS_M: f64 = 500.;
+/// Largest radius of `/near`, in meters, so that the stops of a whole area aren't ranked at once.
+const MAX_RADIUS_M: f64 = 5_000.;
+
+/// Value of `field`, a number between `min` and `max`.
+fn bounded<'v>(field: &form::ValueField<'v>, min: f64, max: f64) -> form::Result<'v, f64> {
+    match field.value.trim().parse::<f64>() {
+        Ok(v) if (min..=max).contains(&v) => Ok(v),
+        Ok(_) => Err(form::Error::validation(format!("expected a number between {} and {}", min, max)).with_value(field.value).into()),
+        Err(e) => Err(form::Error::validation(format!("invalid number: {}", e)).with_value(field.value).into()),
+    }
+}
 
-#[get("/<area_type>/<id>/trips?<limit>&<skip>&<query..>")]
+/// `lat` query parameter of `/near`, in degrees.
+pub struct Latitude(f64);
+
+impl<'v> form::FromFormField<'v> for Latitude {
+    fn from_value(field: form::ValueField<'v>) -> form::Result<'v, Self> {
+        bounded(&field, -90., 90.).map(Latitude)
+    }
+}
+
+/// `lon` query parameter of `/near`, in degrees.
+pub struct Longitude(f64);
+
+impl<'v> form::FromFormField<'v> for Longitude {
+    fn from_value(field: form::ValueField<'v>) -> form::Result<'v, Self> {
+        bounded(&field, -180., 180.).map(Longitude)
+    }
+}
+
+/// `radius` query parameter of `/near`, in meters.
+pub struct Radius(f64);
+
+impl<'v> form::FromFormField<'v> for Radius {
+    fn from_value(field: form::ValueField<'v>) -> form::Result<'v, Self> {
+        bounded(&field, 0., MAX_RADIUS_M).map(Radius)
+    }
+}
+
+/// Great-circle distance, in meters, between two `[lat, lon]` positions in degrees.
+fn haversine((lat1, lon1): (f64, f64), (lat2, lon2): (f64, f64)) -> f64 {
+    let (phi1, phi2) = (lat1.to_radians(), lat2.to_radians());
+    let d_phi = (lat2 - lat1).to_radians();
+    let d_lambda = (lon2 - lon1).to_radians();
+    let a = (d_phi / 2.).sin().powi(2) + phi1.cos() * phi2.cos() * (d_lambda / 2.).sin().powi(2);
+    2. * EARTH_RADIUS_M * a.sqrt().asin()
+}
+
+/// `[lat, lon]` position of a stop document.
+fn position(doc: &Document) -> Option<(f64, f64)> {
+    let position = doc.get_array("position").ok()?;
+    let coord = |v: &Bson| match v {
+        Bson::Double(d) => Some(*d),
+        Bson::Int32(i) => Some(*i as f64),
+        Bson::Int64(i) => Some(*i as f64),
+        _ => None,
+    };
+    Some((coord(position.first()?)?, coord(position.get(1)?)?))
+}
+
+/// Stop within the radius of a `/near` request, along with its distance from the center.
+#[derive(Serialize)]
+pub struct NearStop {
+    #[serde(flatten)]
+    stop: Stop,
+    distance_m: u32,
+}
+
+/// Stops within `radius` meters (500 by default) of `lat`, `lon`, closest first. Candidates are
+/// first narrowed down to a bounding box through the position index, then ranked by their
+/// great-circle distance.
+#[get("/near?<lat>&<lon>&<radius>&<limit>&<skip>&<envelope>&<count>&<query..>")]
+async fn near(
+    db: DBInterface,
+    lat: form::Result<'_, Latitude>,
+    lon: form::Result<'_, Longitude>,
+    radius: form::Result<'_, Option<Radius>>,
+    query: form::Result<'_, Strict<StopQuery>>,
+    limit: Option<u32>,
+    skip: Option<u32>,
+    envelope: Option<bool>,
+    count: form::Result<'_, Option<CountMode>>,
+) -> ApiResponse<Vec<NearStop>> {
+    let center = (lat?.0, lon?.0);
+    let radius = radius?.map_or(DEFAULT_RADIUS_M, |r| r.0);
+    let count = count?;
+
+    let d_lat = radius / DEGREE_M;
+    // degrees of longitude shrink towards the poles
+    let d_lon = (radius / (DEGREE_M * center.0.to_radians().cos().max(1e-6))).min(180.);
+    let mut find = query?.into_inner().to_doc();
+    find.insert("position.0", doc!{"$gte": center.0 - d_lat, "$lte": center.0 + d_lat});
+    find.insert("position.1", doc!{"$gte": center.1 - d_lon, "$lte": center.1 + d_lon});
+
+    // every center makes a key of its own, which would only crowd the other queries out of the cache
+    let pipeline = Pipeline::custom(vec![doc!{"$match": find}], vec![])
+        .count_mode(Some(CountMode::None))
+        .uncached();
+    let candidates = Queryable::<Projected, Stop>::query(&db, pipeline).await?;
+    let mut within = candidates.data.into_iter()
+        .filter_map(|p| {
+            let distance = haversine(center, position(p.document())?);
+            (distance <= radius).then_some((distance, p))
+        })
+        .collect::<Vec<_>>();
+    // ties are broken by id, so that the order is stable across pages
+    within.sort_by(|(d1, p1), (d2, p2)| d1.total_cmp(d2)
+        .then_with(|| p1.document().get_i32("id").ok().cmp(&p2.document().get_i32("id").ok())));
+
+    let total = within.len();
+    let skip = skip.unwrap_or(0) as usize;
+    let limit = limit.filter(|l| *l <= 100).map_or(Pipeline::default_limit() as usize, |l| l as usize);
+    let data = within.into_iter()
+        .skip(skip)
+        .take(limit)
+        .map(|(distance, p)| Ok(NearStop {
+            stop: deserialize::<Stop, Stop>(p.document())?,
+            distance_m: distance.round() as u32,
+        }))
+        .collect::<Result<Vec<_>, QueryError>>()?;
+    let total = match count {
+        Some(CountMode::None) => None,
+        _ => Some(total),
+    };
+    ApiResponse::from(QueryResult { data, total, skip, limit }).envelope(envelope)
+}
+
+
+#[get("/<area_type>/<id>/trips?<limit>&<skip>&<envelope>&<fields>&<count>&<query..>")]
 async fn get_trips(
-    db: Connection<BrussData>,
+    db: DBInterface,
     area_type: Result<Id<FromStringFormField<AreaType>>, <Id<FromStringFormField<AreaType>> as FromParam<'_>>::Error>, 
     id: Result<Id<u16>, <Id<u16> as FromParam<'_>>::Error>, 
     query: rocket::form::Result<'_, Strict<MultiTripQuery>>,
     limit: Option<u32>,
     skip: Option<u32>,
-) -> ApiResponse<Vec<TripCross>> {
+    envelope: Option<bool>,
+    fields: rocket::form::Result<'_, Option<Fields<Trip>>>,
+    count: rocket::form::Result<'_, Option<CountMode>>,
+) -> ApiResponse<Vec<Sparse<TripCross>>> {
     let id = id?.value();
 
     let pipeline = query?
         .into_inner()
-        .into_pipeline_stop(id as u16, area_type?.value().into_inner(), skip, limit);
+        .into_pipeline_stop(id as u16, area_type?.value().into_inner(), skip, limit, fields?)
+        .count_mode(count?);
+
+    let result = SparseQueryable::<TripCross, Schedule>::query_sparse(&db, pipeline).await?;
+    let cursor = TripCursor::after(&result, true);
 
-    Queryable::<TripCross, Schedule>::query(&DBInterface(db), pipeline).await.into()
+    ApiResponse::from(result).next_cursor(cursor).envelope(envelope)
 }
 
-#[get("/<area_type>/<id>/routes?<limit>&<skip>")]
+#[get("/<area_type>/<id>/routes?<limit>&<skip>&<envelope>&<count>")]
 async fn get_routes(
-    db: Connection<BrussData>,
+    db: DBInterface,
     area_type: Result<Id<FromStringFormField<AreaType>>, <Id<FromStringFormField<AreaType>> as FromParam<'_>>::Error>, 
     id: Result<Id<u16>, <Id<u16> as FromParam<'_>>::Error>,
     limit: Option<u32>,
     skip: Option<u32>,
+    envelope: Option<bool>,
+    count: form::Result<'_, Option<CountMode>>,
 ) -> ApiResponse<Vec<Route>> {
     let id = id?.value();
     let ty: &str = area_type?.value().into_inner().into();
     
-    let route_ids = db
-        .database(CONFIGS.db.get_db())
-        .collection::<i32>("trips")
-        .distinct("route", doc!{ "type": ty, "$or": [ { format!("times.{}", id): { "$exists": true } }, { format!("times.{}", id): { "$exists": true } } ] }, None)
+    let route_ids = Storage::distinct(&db.0, &Trip::TYPE.collection(), "route", doc!{ "type": ty, "$or": [ { format!("times.{}", id): { "$exists": true } }, { format!("times.{}", id): { "$exists": true } } ] })
         .await?;
         
-    UniformQueryable::<Route>::query(&DBInterface(db), Pipeline::new(doc!{"id": {"$in": route_ids}}).limit(limit).skip(skip)).await.into()
+    ApiResponse::from(UniformQueryable::<Route>::query(&db, Pipeline::new(doc!{"id": {"$in": route_ids}}).limit(limit).skip(skip).count_mode(count?)).await).envelope(envelope)
 }
 
 lazy_static!{
-    pub static ref ROUTES: Vec<rocket::Route> = routes![get, get_opts, get_trips, get_routes];
+    pub static ref ROUTES: Vec<rocket::Route> = routes![get, get_opts, get_trips, get_routes, search, near];
 }
 
+#[cfg(test)]
+mod tests {
+    use super::haversine;
+
+    #[test]
+    fn haversine_distances() {
+        let dante = (46.0725, 11.1196);
+        assert_eq!(haversine(dante, dante), 0.);
+        // a degree of latitude, and half the circumference along the equator
+        assert!((haversine((0., 0.), (1., 0.)) - 111_195.08).abs() < 0.01);
+        assert!((haversine((0., 0.), (0., 180.)) - 20_015_114.44).abs() < 0.01);
+        // Milan to Venice
+        assert!((haversine((45.4642, 9.19), (45.4384, 12.3271)) - 244_710.9).abs() < 0.1);
+
+        let povo = (46.0670, 11.1500);
+        assert_eq!(haversine(dante, povo), haversine(povo, dante));
+        assert!((haversine(dante, povo) - 2_423.6).abs() < 0.1);
+    }
+}
@@ -1,14 +1,19 @@
-use bruss_data::{Direction, Trip};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use bruss_data::{BrussType, Direction, Schedule, Trip};
 use chrono::{DateTime, Local, TimeDelta, Utc};
 use lazy_static::lazy_static;
 use rocket::form::FromForm;
 use serde::{Deserialize, Serialize};
 use tt::AreaType;
 use mongodb::bson::{doc, Document};
-use super::query::DBQuery;
+use mongodb::IndexModel;
+use super::fields::{Fields, Sparse};
+use super::query::{DBQuery, QueryResult};
+use super::sort::Sortable;
 use super::pipeline::{CustomPipeline, Pipeline};
 
 use super::{gen_generic_getters, FromStringFormField};
+use crate::indexes::Indexed;
 
 struct ParsableTime(DateTime<Utc>);
 
@@ -45,10 +50,73 @@ impl<'r> rocket::form::FromFormField<'r> for ParsableTime {
     }
 }
 
+/// Position in a list of trips, right after the trip `id` departing (or arriving at the stop)
+/// at `time`.
+///
+/// Clients only see it as an opaque string, returned along with each full page.
+pub struct TripCursor {
+    time: DateTime<Utc>,
+    id: String,
+}
+
+impl TripCursor {
+    fn encode(&self) -> String {
+        URL_SAFE_NO_PAD.encode(format!("{}:{}", self.time.timestamp_millis(), self.id))
+    }
+
+    fn decode(value: &str) -> Option<Self> {
+        let raw = String::from_utf8(URL_SAFE_NO_PAD.decode(value).ok()?).ok()?;
+        let (millis, id) = raw.split_once(':')?;
+        Some(TripCursor {
+            time: DateTime::from_timestamp_millis(millis.parse().ok()?)?,
+            id: id.to_owned(),
+        })
+    }
+
+    /// `$match` stage keeping only the trips sorted after the cursor, by `key` and then by id.
+    fn match_stage(&self, key: &str) -> Document {
+        doc!{"$match": {"$or": [
+            {key: {"$gt": self.time}},
+            {key: self.time, "id": {"$gt": &self.id}},
+        ]}}
+    }
+
+    /// Cursor of the page following `result`, if it is full. `at_stop` tells whether the trips
+    /// are sorted by arrival at a stop rather than by departure.
+    pub fn after(result: &QueryResult<Sparse<TripCross>>, at_stop: bool) -> Option<String> {
+        if result.limit == 0 || result.data.len() < result.limit {
+            return None;
+        }
+        let cursor = match result.data.last()? {
+            Sparse::Full(t) => TripCursor {
+                time: if at_stop { t.arrival_at_stop.as_ref()?.0 } else { t.departure },
+                id: t.trip.id.clone(),
+            },
+            Sparse::Partial(p) => {
+                let doc = p.document();
+                TripCursor {
+                    time: doc.get_datetime(if at_stop { "arrival_at_stop" } else { "departure" }).ok()?.to_chrono(),
+                    id: doc.get_document("trip").ok()?.get_str("id").ok()?.to_owned(),
+                }
+            }
+        };
+        Some(cursor.encode())
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> rocket::form::FromFormField<'r> for TripCursor {
+    fn from_value(field: rocket::form::ValueField<'r>) -> rocket::form::Result<'r, Self> {
+        TripCursor::decode(field.value.trim())
+            .ok_or_else(|| rocket::form::Error::validation("invalid cursor").into())
+    }
+}
+
 #[derive(FromForm)]
 pub struct MultiTripQuery {
     time: Option<ParsableTime>,
     direction: Option<FromStringFormField<Direction>>,
+    cursor: Option<TripCursor>,
 }
 
 #[derive(FromForm, Debug)]
@@ -63,6 +131,21 @@ impl DBQuery for TripQuerySingle {
     }
 }
 
+impl Sortable for TripQuerySingle {
+    const SORT_FIELDS: &'static [&'static str] = &["id", "route", "headsign", "direction", "type"];
+}
+
+impl Indexed for TripQuerySingle {
+    fn collection() -> String {
+        Trip::TYPE.collection().to_string()
+    }
+
+    /// Also used by the `$lookup` stage of the trip pipelines.
+    fn indexes() -> Vec<IndexModel> {
+        vec![IndexModel::builder().keys(doc!{"id": 1}).build()]
+    }
+}
+
 #[derive(Deserialize, Serialize)]
 pub struct TripCross {
     trip: Trip,
@@ -74,9 +157,32 @@ pub struct TripCross {
 #[derive(Serialize, Deserialize)]
 struct DateTimeUtcWrapper(#[serde(deserialize_with = "bson::serde_helpers::deserialize_chrono_datetime_from_bson_datetime")] DateTime<Utc>);
 
+/// `$project` stage of the trip pipelines: `trip` is restricted to the selected `fields`, if any.
+/// The trip id is always kept, as it's needed to build the cursor of the next page.
+fn project_stage(fields: Option<&Fields<Trip>>, arrival_at_stop: bool) -> Document {
+    let mut projection = doc!{"_id": 0};
+    match fields {
+        Some(f) => {
+            projection.extend(f.projection(Some("trip")));
+            projection.insert("trip.id", 1);
+        }
+        None => { projection.insert("trip", 1); }
+    }
+    projection.insert("departure", 1);
+    if arrival_at_stop {
+        projection.insert("arrival_at_stop", 1);
+    }
+    projection
+}
+
 impl MultiTripQuery {
-    pub fn into_pipeline_route(self, route: u16, skip: Option<u32>, limit: Option<u32>) -> CustomPipeline {
-        let Self { time, direction } = self;
+    /// Trips in both directions, from `time` or from now on.
+    pub fn at(time: Option<DateTime<Utc>>) -> Self {
+        MultiTripQuery { time: time.map(ParsableTime), direction: None, cursor: None }
+    }
+
+    pub fn into_pipeline_route(self, route: u16, skip: Option<u32>, limit: Option<u32>, fields: Option<Fields<Trip>>) -> CustomPipeline {
+        let Self { time, direction, cursor } = self;
         let time = match time {
             Some(t) => t.into(),
             None => Utc::now(),
@@ -94,15 +200,19 @@ impl MultiTripQuery {
         }
 
         let limit = limit.map(|v| v as i64).unwrap_or_else(Pipeline::default_limit);
-        let skip = skip.map(|v| v as i64).unwrap_or(0);
+        // a cursor already points at the start of the page
+        let skip = if cursor.is_some() { 0 } else { skip.map(|v| v as i64).unwrap_or(0) };
 
         let match_stage = doc!{"$match": {"$and": conds}};
-        let sort_stage = doc!{"$sort": {"departure": 1}};
+        let cursor_stage = cursor.map(|c| c.match_stage("departure"));
+        // ties are broken by id, so that the order is stable across pages
+        let sort_stage = doc!{"$sort": {"departure": 1, "id": 1}};
         let lookup_stage = doc!{"$lookup": {"from": "trips","localField": "id","foreignField": "id","as": "trip"}};
         let unwind_stage = doc!{"$unwind": "$trip"};
         let skip_stage = doc!{"$skip": skip};
         let limit_stage = doc!{"$limit": limit};
-        let project_stage = doc!{"$project": {"_id": 0,"trip": 1,"departure": 1}};
+        let projection = project_stage(fields.as_ref(), false);
+        let project_stage = doc!{"$project": projection.clone()};
         let count_stage = doc!{"$count": "count"};
 
         // for counting we only need the match and the count stage:
@@ -115,21 +225,25 @@ impl MultiTripQuery {
         ];
 
         // include all stages except for the count stage
-        let fetch = vec![
-            match_stage,
-            sort_stage,
-            lookup_stage,
-            unwind_stage,
-            skip_stage,
-            limit_stage,
-            project_stage
-        ];
+        let fetch = [match_stage].into_iter()
+            .chain(cursor_stage)
+            .chain([
+                sort_stage,
+                lookup_stage,
+                unwind_stage,
+                skip_stage,
+                limit_stage,
+                project_stage
+            ])
+            .collect();
 
         Pipeline::custom(fetch, count)
+            .paged(skip, limit)
+            .projected(fields.map(|_| projection))
     }
 
-    pub fn into_pipeline_stop(self, stop: u16, area_type: AreaType, skip: Option<u32>, limit: Option<u32>) -> CustomPipeline {
-        let Self { time, direction } = self;
+    pub fn into_pipeline_stop(self, stop: u16, area_type: AreaType, skip: Option<u32>, limit: Option<u32>, fields: Option<Fields<Trip>>) -> CustomPipeline {
+        let Self { time, direction, cursor } = self;
 
         let time = match time {
             Some(t) => t.into(),
@@ -147,7 +261,8 @@ impl MultiTripQuery {
             conds.push(doc!{"hints.direction": direction.into_bson()});
         }
 
-        let skip = skip.map(|v| v as i64).unwrap_or(0);
+        // a cursor already points at the start of the page
+        let skip = if cursor.is_some() { 0 } else { skip.map(|v| v as i64).unwrap_or(0) };
         let limit = limit.map(|v| v as i64).unwrap_or_else(Pipeline::default_limit);
 
         // we first filter out the great majority of trips using static conditions
@@ -158,13 +273,16 @@ impl MultiTripQuery {
         // then we match based on the arrival time at the stop
         // we use a 20 minutes buffer to account for delays
         let match_arrival_stage = doc!{"$match": {"arrival_at_stop": {"$gte": time - TimeDelta::minutes(20)}}};
-        // we sort by the arrival time at the stop
-        let sort_stage = doc!{"$sort": {"arrival_at_stop": 1}};
+        // when paging by cursor, we skip everything up to the last trip of the previous page
+        let cursor_stage = cursor.map(|c| c.match_stage("arrival_at_stop"));
+        // we sort by the arrival time at the stop, breaking ties by id for a stable order
+        let sort_stage = doc!{"$sort": {"arrival_at_stop": 1, "id": 1}};
         let lookup_stage = doc!{"$lookup": {"from": "trips","localField": "id","foreignField": "id","as": "trip"}};
         let unwind_stage = doc!{"$unwind": "$trip"};
         let skip_stage = doc!{"$skip": skip};
         let limit_stage = doc!{"$limit": limit};
-        let project_stage = doc!{"$project": {"_id": 0,"trip": 1,"departure": 1, "arrival_at_stop": 1}};
+        let projection = project_stage(fields.as_ref(), true);
+        let project_stage = doc!{"$project": projection.clone()};
         let count_stage = doc!{"$count": "count"};
 
         let count = vec![
@@ -175,20 +293,38 @@ impl MultiTripQuery {
             count_stage,
         ];
 
-        let fetch = vec![
-            match_stage,
-            heuristic_match_stage,
-            set_stage,
-            match_arrival_stage,
-            sort_stage,
-            lookup_stage,
-            unwind_stage,
-            skip_stage,
-            limit_stage,
-            project_stage
-        ];
+        let fetch = [match_stage, heuristic_match_stage, set_stage, match_arrival_stage].into_iter()
+            .chain(cursor_stage)
+            .chain([
+                sort_stage,
+                lookup_stage,
+                unwind_stage,
+                skip_stage,
+                limit_stage,
+                project_stage
+            ])
+            .collect();
 
         Pipeline::custom(fetch, count)
+            .paged(skip, limit)
+            .projected(fields.map(|_| projection))
+    }
+}
+
+impl Indexed for MultiTripQuery {
+    fn collection() -> String {
+        Schedule::TYPE.collection().to_string()
+    }
+
+    /// Trips of a route are matched by route and sorted by departure; trips at a stop are
+    /// matched by area type and departure, and by the stop being in `hints.times`, whose keys
+    /// are stop ids, hence the wildcard index.
+    fn indexes() -> Vec<IndexModel> {
+        vec![
+            IndexModel::builder().keys(doc!{"hints.route": 1, "departure": 1, "id": 1}).build(),
+            IndexModel::builder().keys(doc!{"hints.type": 1, "departure": 1}).build(),
+            IndexModel::builder().keys(doc!{"hints.times.$**": 1}).build(),
+        ]
     }
 }
 
@@ -198,3 +334,51 @@ gen_generic_getters!(Trip, TripQuerySingle, String);
 lazy_static!{
     pub static ref ROUTES: Vec<rocket::Route> = routes![get, get_opts];
 }
+
+#[cfg(test)]
+mod tests {
+    use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+    use chrono::{TimeZone, Utc};
+    use mongodb::bson::doc;
+
+    use super::TripCursor;
+
+    #[test]
+    fn cursor_round_trip() {
+        let time = Utc.with_ymd_and_hms(2024, 3, 1, 8, 10, 0).unwrap();
+        // ids may contain the separator themselves
+        for id in ["t1", "0003:12_ab", ""] {
+            let encoded = TripCursor { time, id: id.to_owned() }.encode();
+            assert!(encoded.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'), "{}", encoded);
+            let decoded = TripCursor::decode(&encoded).expect("valid cursor");
+            assert_eq!(decoded.time, time);
+            assert_eq!(decoded.id, id);
+        }
+    }
+
+    #[test]
+    fn malformed_cursors() {
+        let encode = |raw: &str| URL_SAFE_NO_PAD.encode(raw);
+        for cursor in [
+            String::new(),
+            "not base64!".to_owned(),
+            URL_SAFE_NO_PAD.encode([0xff, 0xfe, b':', b'a']),
+            encode("1709280600000"),
+            encode("yesterday:t1"),
+            encode(&format!("{}:t1", i64::MAX)),
+        ] {
+            assert!(TripCursor::decode(&cursor).is_none(), "{:?}", cursor);
+        }
+    }
+
+    #[test]
+    fn cursor_match_stage() {
+        let time = Utc.with_ymd_and_hms(2024, 3, 1, 8, 10, 0).unwrap();
+        let cursor = TripCursor { time, id: "t2".to_owned() };
+        // later trips, and trips at the same time with a greater id
+        assert_eq!(cursor.match_stage("departure"), doc!{"$match": {"$or": [
+            {"departure": {"$gt": time}},
+            {"departure": time, "id": {"$gt": "t2"}},
+        ]}});
+    }
+}
@@ -1,5 +1,6 @@
 pub mod tracking;
 pub mod map;
+pub mod graphql;
 
 // pub static TRACKING_ROUTES: Vec<Route> = routes![];
 // pub const MAP_ROUTES: Vec<Route> = routes![map::get_areas];
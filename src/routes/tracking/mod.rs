@@ -1,3 +1,3 @@
 mod trip;
 
-pub use trip::ROUTES;
+pub use trip::{ROUTES, TripTracking, TripUpdate};
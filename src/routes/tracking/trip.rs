@@ -7,26 +7,31 @@ use chrono::{DateTime, Utc};
 use futures::stream::TryStreamExt;
 use futures::stream::StreamExt;
 use lazy_static::lazy_static;
-use mongodb::options::ReplaceOptions;
+use mongodb::options::{FindOptions, IndexOptions, ReplaceOptions};
+use mongodb::error::Error as MongoError;
+use mongodb::{Database, IndexModel};
 use rocket::request::FromParam;
-use rocket_db_pools::Connection;
 use serde::{Serialize,Deserialize};
 use mongodb::bson::{doc, Document};
 use tt::{AreaType, ParallelRequester, TTTrip};
-use crate::{db::BrussData, response::ApiResponse, routes::map::query::{DBInterface, DBQuery}};
+use crate::{config::API_CONFIG, indexes::Indexed, response::{ApiError, ApiResponse}, routes::map::query::{DBInterface, DBQuery}, upstream::UpstreamError};
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TripTracking {
-    id: String,
-    delay: i32,
-    last_stop: Option<u16>,
-    next_stop: Option<u16>,
-    area: Option<AreaType>,
-    bus_id: Option<u16>,
-    last_event: Option<DateTime<Utc>>,
+    pub(crate) id: String,
+    pub(crate) delay: i32,
+    pub(crate) last_stop: Option<u16>,
+    pub(crate) next_stop: Option<u16>,
+    pub(crate) area: Option<AreaType>,
+    pub(crate) bus_id: Option<u16>,
+    pub(crate) last_event: Option<DateTime<Utc>>,
 }
 
 impl TripTracking {
+    pub fn trip_id(&self) -> &str {
+        &self.id
+    }
+
     #[allow(dead_code)]
     fn error(id: String) -> Self {
         Self {
@@ -74,53 +79,118 @@ impl DBQuery for TripIds {
     }
 }
 
+/// Tracking of a trip, as cached in `trip_updates`.
 #[derive(Debug, Serialize, Deserialize)]
-struct TripUpdate {
+pub struct TripUpdate {
     #[serde(flatten)]
     tracking: TripTracking,
-    #[serde(with = "chrono::serde::ts_seconds")]
+    /// Stored as a date, so that the TTL index can expire it.
+    #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
     updated: DateTime<Utc>,
 }
 
+impl Indexed for TripUpdate {
+    fn collection() -> String {
+        "trip_updates".to_owned()
+    }
+
+    /// Updates are looked up by id and freshness, and expire after `trip_updates_ttl`.
+    fn indexes() -> Vec<IndexModel> {
+        let ttl = IndexOptions::builder()
+            .expire_after(Duration::from_secs(API_CONFIG.trip_updates_ttl))
+            .build();
+        vec![
+            IndexModel::builder().keys(doc!{"id": 1}).build(),
+            IndexModel::builder().keys(doc!{"updated": 1}).options(ttl).build(),
+        ]
+    }
+}
+
+/// Options of a lookup run within what is left of the deadline of the request of `db`.
+fn within(db: &DBInterface) -> Result<FindOptions, ApiError> {
+    Ok(FindOptions::builder().max_time(db.4.remaining()?).build())
+}
+
 impl TripUpdate {
-    async fn get_by_ids(db: DBInterface, id: Vec<String>) -> Result<Vec<Self>, mongodb::error::Error> {
+    /// Convert the updates cached before `updated` was stored as a date, as integer seconds, so
+    /// that the TTL index expires them and the freshness filter matches them again. Returns the
+    /// number of documents converted.
+    pub(crate) async fn convert_legacy(db: &Database) -> Result<u64, MongoError> {
+        let to_date = vec![doc!{"$set": {"updated": {"$toDate": {"$multiply": [{"$toLong": "$updated"}, 1000]}}}}];
+        let result = db.collection::<Document>(&Self::collection())
+            .update_many(Self::legacy(), to_date, None)
+            .await?;
+        Ok(result.modified_count)
+    }
+
+    /// Number of updates [`convert_legacy`](Self::convert_legacy) would convert.
+    pub(crate) async fn count_legacy(db: &Database) -> Result<u64, MongoError> {
+        db.collection::<Document>(&Self::collection())
+            .count_documents(Self::legacy(), None)
+            .await
+    }
+
+    /// Filter of the updates whose `updated` time is still stored as integer seconds.
+    fn legacy() -> Document {
+        doc!{"updated": {"$type": "number"}}
+    }
+
+    pub(crate) async fn get_by_ids(db: &DBInterface, id: Vec<String>) -> Result<Vec<Self>, ApiError> {
         let now = Utc::now();
         // sanitize id vec:
         let id = id.into_iter().collect::<HashSet<_>>().into_iter().collect::<Vec<_>>();
 
-        let coll = db.0.database(CONFIGS.db.get_db())
-            .collection::<TripUpdate>("trip_updates");
+        // realtime data is cached in MongoDB, whatever the storage of the static dataset
+        let database = db.0.mongo()?;
+        let coll = database
+            .collection::<TripUpdate>(&TripUpdate::collection());
         let cached: HashMap<String, TripUpdate> = coll
-            .find(doc!{"id": doc!{"$in": &id}, "updated": doc!{"$gt": (now - Duration::from_secs(CONFIGS.api.max_rt_age)).timestamp()}}, None)
+            .find(doc!{"id": doc!{"$in": &id}, "updated": doc!{"$gt": bson::DateTime::from_chrono(now - Duration::from_secs(CONFIGS.api.max_rt_age))}}, within(db)?)
             .await?
             .map(|r| r.map(|d| (d.tracking.id.clone(), d)))
             .try_collect()
             .await?;
 
+        // the upstream calls are logged within the span of the request, which carries its id
         let cli = CONFIGS.tt.client();
         let id_len = id.len();
         let p_requester = ParallelRequester::<TTTrip>::new(cli, CONFIGS.routing.parallel_downloads.unwrap_or(1));
-        for i in id.into_iter()
+        let missing = id.into_iter()
             .filter(|i| !cached.contains_key(i))
-        {
-            p_requester.request_one(i).await
-        }
-
+            .collect::<Vec<_>>();
+        info!("requesting {} trips from upstream ({} cached): {}", missing.len(), cached.len(), missing.join(","));
+        // upstream gets whatever is left of the deadline of the request, and issuing the
+        // requests already waits for the first ones to complete
+        let budget = db.4.remaining()?;
+        let start = std::time::Instant::now();
+        let gathered = match tokio::time::timeout(budget, async {
+            for i in missing.into_iter() {
+                p_requester.request_one(i).await
+            }
+            p_requester.gather().await
+        }).await {
+            Ok(r) => r.map_err(|e| UpstreamError::from_tt(&e, Some(start.elapsed()))),
+            Err(_) => Err(UpstreamError::deadline_exceeded(start.elapsed())),
+        };
 
-        let tt_updates = p_requester.gather().await
-            .map_err(mongodb::error::Error::custom)?
+        let tt_updates = gathered
+            .map_err(|e| {
+                error!("upstream request failed: {}", e);
+                e
+            })?
             .into_iter()
             .map(Trip::from_tt)
             .map(|v| v.0)
             .collect::<Vec<Trip>>();
+        info!("upstream returned {} trips in {:?}", tt_updates.len(), start.elapsed());
 
         let routes = tt_updates.iter()
             .map(|t| t.route)
             .collect::<HashSet<u16>>();
 
         // get needed routes (one usually) from db
-        let areas: HashMap<u16, AreaType> = Route::get_coll(&db.0.database(CONFIGS.db.get_db()))
-            .find(doc!{"id": doc!{"$in": routes.iter().map(|u| *u as i32).collect::<Vec<i32>>()}}, None)
+        let areas: HashMap<u16, AreaType> = Route::get_coll(database)
+            .find(doc!{"id": doc!{"$in": routes.iter().map(|u| *u as i32).collect::<Vec<i32>>()}}, within(db)?)
             .await?
             .map(|r| r.map(|r| (r.id, r.area_ty)))
             .try_collect()
@@ -152,10 +222,8 @@ impl From<TripUpdate> for TripTracking {
 }
 
 #[get("/trip/<trip_ids>")]
-pub async fn get_trip(db: Connection<BrussData>, trip_ids: TripIds) -> ApiResponse<Vec<TripTracking>> {
-    let db = DBInterface(db);
-
-    let trips = TripUpdate::get_by_ids(db, trip_ids.0).await?;
+pub async fn get_trip(db: DBInterface, trip_ids: TripIds) -> ApiResponse<Vec<TripTracking>> {
+    let trips = TripUpdate::get_by_ids(&db, trip_ids.0).await?;
     let tot = trips.len();
     
     ApiResponse::Ok(trips.into_iter().map(|t| t.into()).collect(), Some(tot))
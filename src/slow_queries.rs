@@ -0,0 +1,63 @@
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use mongodb::Database;
+use rocket::{Orbit, Rocket};
+use serde::Serialize;
+use tracing::Instrument;
+
+use crate::config::API_CONFIG;
+use crate::db;
+
+/// Record of a query which took longer than `slow_query_ms`, or exceeded its deadline.
+#[derive(Serialize, Debug)]
+pub struct SlowQuery {
+    pub request_id: String,
+    #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
+    pub time: DateTime<Utc>,
+    pub endpoint: String,
+    pub collection: String,
+    /// Pipeline run, as shown by `Debug`.
+    pub pipeline: String,
+    pub duration_ms: u64,
+    pub timed_out: bool,
+}
+
+/// Whether a query taking `duration` is slow.
+pub fn is_slow(duration: Duration) -> bool {
+    duration.as_millis() >= API_CONFIG.slow_query_ms as u128
+}
+
+/// Record `query` in the logs and, if `slow_query_collection` is configured, in `db` when the
+/// queries are run on MongoDB.
+///
+/// Storing is done in the background, so that responses aren't delayed by it.
+pub fn record(db: Option<Database>, query: SlowQuery) {
+    warn!("slow query on {} for {}: {} ms{}\n  pipeline: {}",
+        query.collection,
+        query.endpoint,
+        query.duration_ms,
+        if query.timed_out { " (deadline exceeded)" } else { "" },
+        query.pipeline,
+    );
+
+    let (coll, db) = match (&API_CONFIG.slow_query_collection, db) {
+        (Some(c), Some(db)) => (c, db),
+        _ => return,
+    };
+    tokio::spawn(async move {
+        let r = db.collection::<SlowQuery>(coll)
+            .insert_one(&query, None)
+            .await;
+        if let Err(e) = r {
+            warn!("cannot store slow query: {}", e);
+        }
+    }.in_current_span());
+}
+
+/// Create the capped collection for slow queries, if configured and not already existing.
+pub async fn create_collection(rocket: &Rocket<Orbit>) {
+    if let Some(ref coll) = API_CONFIG.slow_query_collection {
+        db::create_capped_collection(rocket, coll, API_CONFIG.slow_query_collection_size, "slow queries").await;
+    }
+}
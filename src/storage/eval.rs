@@ -0,0 +1,1096 @@
+use std::cmp::Ordering;
+use std::fmt::Display;
+use std::iter::Peekable;
+use std::str::Chars;
+
+use mongodb::bson::{doc, Bson, Document};
+use mongodb::options::Collation;
+use regex::{Regex, RegexBuilder};
+
+use crate::routes::map::query::QueryError;
+use crate::routes::map::search::fold;
+
+/// Document flowing through a pipeline, along with its `$text` relevance.
+#[derive(Clone, Debug)]
+pub struct Row {
+    pub doc: Document,
+    score: f64,
+}
+
+impl Row {
+    pub fn new(doc: Document) -> Self {
+        Row { doc, score: 0. }
+    }
+}
+
+/// Collections `$lookup` stages can join with.
+pub trait Collections {
+    fn collection(&self, name: &str) -> Vec<Row>;
+}
+
+fn unsupported(what: impl Display) -> QueryError {
+    QueryError::Unsupported(format!("{} is not supported by the in-memory storage", what))
+}
+
+fn invalid(what: impl Display) -> QueryError {
+    QueryError::Unsupported(format!("invalid {}", what))
+}
+
+/// Run the aggregation `stages` on `rows`, comparing strings according to `collation`.
+pub fn run(collections: &impl Collections, mut rows: Vec<Row>, stages: &[Document], collation: Option<&Collation>) -> Result<Vec<Row>, QueryError> {
+    for stage in stages {
+        let (name, spec) = match stage.iter().next() {
+            Some(s) if stage.len() == 1 => s,
+            _ => return Err(invalid(format!("stage {}", stage))),
+        };
+        let as_doc = || spec.as_document().ok_or_else(|| invalid(format!("`{}` stage", name)));
+        rows = match name.as_str() {
+            "$match" => filter(rows, as_doc()?)?,
+            "$sort" => sort(rows, as_doc()?, collation)?,
+            "$skip" => rows.into_iter().skip(count_arg(name, spec)?).collect(),
+            "$limit" => rows.into_iter().take(count_arg(name, spec)?).collect(),
+            "$project" => project(rows, as_doc()?)?,
+            "$addFields" | "$set" => add_fields(rows, as_doc()?)?,
+            "$lookup" => lookup(collections, rows, as_doc()?)?,
+            "$unwind" => unwind(rows, spec)?,
+            "$count" => {
+                let output = spec.as_str().ok_or_else(|| invalid("`$count` stage"))?;
+                match rows.len() {
+                    0 => vec![],
+                    n => vec![Row::new(doc!{output: n as i64})],
+                }
+            }
+            "$facet" => {
+                let mut out = Document::new();
+                for (field, stages) in as_doc()?.iter() {
+                    let stages = stages.as_array()
+                        .and_then(|s| s.iter().map(|s| s.as_document().cloned()).collect::<Option<Vec<_>>>())
+                        .ok_or_else(|| invalid("`$facet` stage"))?;
+                    let result = run(collections, rows.clone(), &stages, collation)?;
+                    out.insert(field, result.into_iter().map(|r| Bson::Document(r.doc)).collect::<Vec<_>>());
+                }
+                vec![Row::new(out)]
+            }
+            _ => return Err(unsupported(format!("stage `{}`", name))),
+        };
+    }
+    Ok(rows)
+}
+
+fn count_arg(stage: &str, spec: &Bson) -> Result<usize, QueryError> {
+    number(spec)
+        .filter(|n| *n >= 0.)
+        .map(|n| n as usize)
+        .ok_or_else(|| invalid(format!("`{}` stage", stage)))
+}
+
+fn number(value: &Bson) -> Option<f64> {
+    match *value {
+        Bson::Int32(i) => Some(i as f64),
+        Bson::Int64(i) => Some(i as f64),
+        Bson::Double(d) => Some(d),
+        _ => None,
+    }
+}
+
+/// Values at `path` in `doc`, going through the arrays met along the way.
+fn resolve<'a>(doc: &'a Document, path: &str) -> Vec<&'a Bson> {
+    fn walk<'a>(value: &'a Bson, parts: &[&str], out: &mut Vec<&'a Bson>) {
+        let Some((part, rest)) = parts.split_first() else {
+            out.push(value);
+            return;
+        };
+        match value {
+            Bson::Document(d) => if let Some(v) = d.get(*part) {
+                walk(v, rest, out);
+            },
+            Bson::Array(a) => match part.parse::<usize>() {
+                Ok(i) => if let Some(v) = a.get(i) {
+                    walk(v, rest, out);
+                },
+                Err(_) => for v in a.iter().filter(|v| matches!(v, Bson::Document(_))) {
+                    walk(v, parts, out);
+                },
+            },
+            _ => {}
+        }
+    }
+    let parts = path.split('.').collect::<Vec<_>>();
+    let mut out = Vec::new();
+    if let Some(v) = doc.get(parts[0]) {
+        walk(v, &parts[1..], &mut out);
+    }
+    out
+}
+
+/// Values a query condition on `path` is checked against: the values found, and the elements of
+/// those which are arrays.
+fn candidates<'a>(doc: &'a Document, path: &str) -> Vec<&'a Bson> {
+    let mut out = Vec::new();
+    for v in resolve(doc, path) {
+        out.push(v);
+        if let Bson::Array(a) = v {
+            out.extend(a.iter());
+        }
+    }
+    out
+}
+
+/// Values of `path` in `doc`, as returned by `distinct`: arrays are replaced by their elements.
+pub fn values<'a>(doc: &'a Document, path: &str) -> Vec<&'a Bson> {
+    resolve(doc, path).into_iter()
+        .flat_map(|v| match v {
+            Bson::Array(a) => a.iter().collect(),
+            v => vec![v],
+        })
+        .collect()
+}
+
+/// Position of the type of `value` in the BSON comparison order.
+fn type_rank(value: &Bson) -> u8 {
+    match value {
+        Bson::MinKey => 0,
+        Bson::Null | Bson::Undefined => 1,
+        Bson::Int32(_) | Bson::Int64(_) | Bson::Double(_) | Bson::Decimal128(_) => 2,
+        Bson::String(_) | Bson::Symbol(_) => 3,
+        Bson::Document(_) => 4,
+        Bson::Array(_) => 5,
+        Bson::Binary(_) => 6,
+        Bson::ObjectId(_) => 7,
+        Bson::Boolean(_) => 8,
+        Bson::DateTime(_) => 9,
+        Bson::Timestamp(_) => 10,
+        Bson::RegularExpression(_) => 11,
+        Bson::MaxKey => 13,
+        _ => 12,
+    }
+}
+
+/// Compare strings by their letters first, then by case, reading digits as numbers if the
+/// collation asks so.
+fn compare_strings(a: &str, b: &str, collation: Option<&Collation>) -> Ordering {
+    let Some(collation) = collation else {
+        return a.cmp(b);
+    };
+    let numeric = collation.numeric_ordering == Some(true);
+    let (fa, fb) = (fold(a), fold(b));
+    let primary = if numeric { natural(&fa, &fb) } else { fa.cmp(&fb) };
+    primary.then_with(|| a.cmp(b))
+}
+
+/// Compare strings reading their runs of digits as numbers.
+fn natural(a: &str, b: &str) -> Ordering {
+    let (mut a, mut b) = (a.chars().peekable(), b.chars().peekable());
+    loop {
+        match (a.peek().copied(), b.peek().copied()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                let (x, y) = (digits(&mut a), digits(&mut b));
+                let ord = x.len().cmp(&y.len()).then_with(|| x.cmp(&y));
+                if ord != Ordering::Equal {
+                    return ord;
+                }
+            }
+            (Some(x), Some(y)) => {
+                if x != y {
+                    return x.cmp(&y);
+                }
+                a.next();
+                b.next();
+            }
+        }
+    }
+}
+
+/// Number at the start of `chars`, without leading zeros.
+fn digits(chars: &mut Peekable<Chars<'_>>) -> String {
+    let mut n = String::new();
+    while let Some(d) = chars.next_if(char::is_ascii_digit) {
+        n.push(d);
+    }
+    n.trim_start_matches('0').to_owned()
+}
+
+/// Compare two values in the BSON comparison order.
+fn compare(a: &Bson, b: &Bson, collation: Option<&Collation>) -> Ordering {
+    let rank = type_rank(a).cmp(&type_rank(b));
+    if rank != Ordering::Equal {
+        return rank;
+    }
+    match (a, b) {
+        (Bson::String(x), Bson::String(y)) => compare_strings(x, y, collation),
+        (Bson::Document(x), Bson::Document(y)) => x.iter()
+            .zip(y.iter())
+            .map(|((kx, vx), (ky, vy))| kx.cmp(ky).then_with(|| compare(vx, vy, collation)))
+            .find(|o| *o != Ordering::Equal)
+            .unwrap_or_else(|| x.len().cmp(&y.len())),
+        (Bson::Array(x), Bson::Array(y)) => x.iter()
+            .zip(y.iter())
+            .map(|(vx, vy)| compare(vx, vy, collation))
+            .find(|o| *o != Ordering::Equal)
+            .unwrap_or_else(|| x.len().cmp(&y.len())),
+        (Bson::ObjectId(x), Bson::ObjectId(y)) => x.bytes().cmp(&y.bytes()),
+        (Bson::Boolean(x), Bson::Boolean(y)) => x.cmp(y),
+        (Bson::DateTime(x), Bson::DateTime(y)) => x.timestamp_millis().cmp(&y.timestamp_millis()),
+        (Bson::Timestamp(x), Bson::Timestamp(y)) => (x.time, x.increment).cmp(&(y.time, y.increment)),
+        _ => match (number(a), number(b)) {
+            (Some(x), Some(y)) => x.partial_cmp(&y).unwrap_or(Ordering::Equal),
+            _ => Ordering::Equal,
+        },
+    }
+}
+
+pub fn equal(a: &Bson, b: &Bson) -> bool {
+    compare(a, b, None) == Ordering::Equal
+}
+
+fn truthy(value: &Option<Bson>) -> bool {
+    match value {
+        None | Some(Bson::Null) | Some(Bson::Undefined) | Some(Bson::Boolean(false)) => false,
+        Some(v) => !number(v).is_some_and(|n| n == 0.),
+    }
+}
+
+fn regex(pattern: &str, options: &str) -> Result<Regex, QueryError> {
+    RegexBuilder::new(pattern)
+        .case_insensitive(options.contains('i'))
+        .multi_line(options.contains('m'))
+        .dot_matches_new_line(options.contains('s'))
+        .ignore_whitespace(options.contains('x'))
+        .build()
+        .map_err(|e| invalid(format!("regular expression `{}`: {}", pattern, e)))
+}
+
+fn matches_regex(values: &[&Bson], re: &Regex) -> bool {
+    values.iter().any(|v| matches!(v, Bson::String(s) if re.is_match(s)))
+}
+
+/// Whether any of `values`, found at a path, equals `value`. A `null` also matches a missing
+/// path.
+fn matches_eq(values: &[&Bson], value: &Bson) -> Result<bool, QueryError> {
+    Ok(match value {
+        Bson::Null => values.is_empty() || values.iter().any(|v| matches!(v, Bson::Null)),
+        Bson::RegularExpression(r) => matches_regex(values, &regex(&r.pattern, &r.options)?),
+        value => values.iter().any(|v| equal(v, value)),
+    })
+}
+
+/// Whether the values found at a path satisfy the operator `op` with `arg`, `cond` being the
+/// whole condition it's part of.
+fn matches_op(values: &[&Bson], op: &str, arg: &Bson, cond: &Document) -> Result<bool, QueryError> {
+    let compared = |accept: fn(Ordering) -> bool| values.iter()
+        .any(|v| type_rank(v) == type_rank(arg) && accept(compare(v, arg, None)));
+    Ok(match op {
+        "$eq" => matches_eq(values, arg)?,
+        "$ne" => !matches_eq(values, arg)?,
+        "$gt" => compared(|o| o == Ordering::Greater),
+        "$gte" => compared(|o| o != Ordering::Less),
+        "$lt" => compared(|o| o == Ordering::Less),
+        "$lte" => compared(|o| o != Ordering::Greater),
+        "$in" | "$nin" => {
+            let options = arg.as_array().ok_or_else(|| invalid(format!("`{}` condition", op)))?;
+            let mut found = false;
+            for option in options {
+                if matches_eq(values, option)? {
+                    found = true;
+                    break;
+                }
+            }
+            found == (op == "$in")
+        }
+        "$exists" => truthy(&Some(arg.clone())) != values.is_empty(),
+        "$regex" => {
+            let re = match arg {
+                Bson::String(p) => regex(p, cond.get_str("$options").unwrap_or(""))?,
+                Bson::RegularExpression(r) => regex(&r.pattern, &r.options)?,
+                _ => return Err(invalid("`$regex` condition")),
+            };
+            matches_regex(values, &re)
+        }
+        // read along with `$regex`
+        "$options" => true,
+        _ => return Err(unsupported(format!("query operator `{}`", op))),
+    })
+}
+
+/// Whether `row` matches the query `filter`. `$text` conditions are checked separately, as they
+/// score the documents.
+fn matches(row: &Row, filter: &Document) -> Result<bool, QueryError> {
+    for (key, cond) in filter.iter() {
+        let matched = match key.as_str() {
+            "$text" => true,
+            "$and" | "$or" | "$nor" => {
+                let filters = cond.as_array()
+                    .and_then(|f| f.iter().map(Bson::as_document).collect::<Option<Vec<_>>>())
+                    .ok_or_else(|| invalid(format!("`{}` condition", key)))?;
+                let mut results = Vec::with_capacity(filters.len());
+                for f in filters {
+                    results.push(matches(row, f)?);
+                }
+                match key.as_str() {
+                    "$and" => results.iter().all(|r| *r),
+                    "$or" => results.iter().any(|r| *r),
+                    _ => !results.iter().any(|r| *r),
+                }
+            }
+            "$expr" => truthy(&eval(row, cond)?),
+            k if k.starts_with('$') => return Err(unsupported(format!("query operator `{}`", k))),
+            path => {
+                let values = candidates(&row.doc, path);
+                match cond {
+                    Bson::Document(ops) if ops.keys().next().is_some_and(|k| k.starts_with('$')) => {
+                        let mut all = true;
+                        for (op, arg) in ops.iter() {
+                            if !matches_op(&values, op, arg, ops)? {
+                                all = false;
+                                break;
+                            }
+                        }
+                        all
+                    }
+                    value => matches_eq(&values, value)?,
+                }
+            }
+        };
+        if !matched {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Relevance of `doc` for the `$text` condition `spec`: the number of search terms found among
+/// the words of its strings.
+fn text_score(doc: &Document, spec: &Bson) -> Result<f64, QueryError> {
+    fn words(value: &Bson, out: &mut Vec<String>) {
+        match value {
+            Bson::String(s) => out.extend(fold(s).split(|c: char| !c.is_alphanumeric()).filter(|w| !w.is_empty()).map(str::to_owned)),
+            Bson::Document(d) => d.values().for_each(|v| words(v, out)),
+            Bson::Array(a) => a.iter().for_each(|v| words(v, out)),
+            _ => {}
+        }
+    }
+    let search = spec.as_document()
+        .and_then(|s| s.get_str("$search").ok())
+        .ok_or_else(|| invalid("`$text` condition"))?;
+    if search.contains('"') || search.split_whitespace().any(|t| t.starts_with('-')) {
+        return Err(unsupported("`$text` phrase or negation"));
+    }
+    let mut doc_words = Vec::new();
+    words(&Bson::Document(doc.clone()), &mut doc_words);
+    Ok(search.split_whitespace()
+        .map(fold)
+        .filter(|t| doc_words.contains(t))
+        .count() as f64)
+}
+
+fn filter(rows: Vec<Row>, filter: &Document) -> Result<Vec<Row>, QueryError> {
+    let mut out = Vec::new();
+    for mut row in rows {
+        if let Some(text) = filter.get("$text") {
+            row.score = text_score(&row.doc, text)?;
+            if row.score == 0. {
+                continue;
+            }
+        }
+        if matches(&row, filter)? {
+            out.push(row);
+        }
+    }
+    Ok(out)
+}
+
+enum SortKey {
+    Path(String, bool),
+    TextScore,
+}
+
+/// Value `row` is sorted by for `path`: the lowest of its values in ascending order, the
+/// highest in descending order.
+fn sort_value(doc: &Document, path: &str, ascending: bool, collation: Option<&Collation>) -> Bson {
+    let found = values(doc, path);
+    let best = if ascending {
+        found.into_iter().min_by(|a, b| compare(a, b, collation))
+    } else {
+        found.into_iter().max_by(|a, b| compare(a, b, collation))
+    };
+    best.cloned().unwrap_or(Bson::Null)
+}
+
+fn sort(mut rows: Vec<Row>, spec: &Document, collation: Option<&Collation>) -> Result<Vec<Row>, QueryError> {
+    let keys = spec.iter()
+        .map(|(path, dir)| match dir {
+            Bson::Document(d) if d.get_str("$meta").is_ok_and(|m| m == "textScore") => Ok(SortKey::TextScore),
+            dir => match number(dir) {
+                Some(n) => Ok(SortKey::Path(path.clone(), n > 0.)),
+                None => Err(invalid(format!("sort order of `{}`", path))),
+            },
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    rows.sort_by(|a, b| {
+        keys.iter()
+            .map(|key| match key {
+                SortKey::TextScore => b.score.partial_cmp(&a.score).unwrap_or(Ordering::Equal),
+                SortKey::Path(path, ascending) => {
+                    let ord = compare(
+                        &sort_value(&a.doc, path, *ascending, collation),
+                        &sort_value(&b.doc, path, *ascending, collation),
+                        collation,
+                    );
+                    if *ascending { ord } else { ord.reverse() }
+                }
+            })
+            .find(|o| *o != Ordering::Equal)
+            .unwrap_or(Ordering::Equal)
+    });
+    Ok(rows)
+}
+
+/// Set `path` of `doc` to `value`, creating the documents along it.
+fn set_path(doc: &mut Document, path: &str, value: Bson) {
+    match path.split_once('.') {
+        None => {
+            doc.insert(path, value);
+        }
+        Some((first, rest)) => {
+            if !matches!(doc.get(first), Some(Bson::Document(_))) {
+                doc.insert(first, Document::new());
+            }
+            if let Some(Bson::Document(d)) = doc.get_mut(first) {
+                set_path(d, rest, value);
+            }
+        }
+    }
+}
+
+fn remove_path(doc: &mut Document, path: &str) {
+    match path.split_once('.') {
+        None => {
+            doc.remove(path);
+        }
+        Some((first, rest)) => match doc.get_mut(first) {
+            Some(Bson::Document(d)) => remove_path(d, rest),
+            Some(Bson::Array(a)) => for v in a.iter_mut() {
+                if let Bson::Document(d) = v {
+                    remove_path(d, rest);
+                }
+            },
+            _ => {}
+        },
+    }
+}
+
+/// Copy `path` of `src` into `out`, along with the documents and arrays of documents leading to
+/// it.
+fn include_path(src: &Document, out: &mut Document, path: &str) {
+    let (first, rest) = match path.split_once('.') {
+        Some((first, rest)) => (first, Some(rest)),
+        None => (path, None),
+    };
+    let (Some(value), Some(rest)) = (src.get(first), rest) else {
+        if let Some(value) = src.get(first) {
+            out.insert(first, value.clone());
+        }
+        return;
+    };
+    match value {
+        Bson::Document(d) => {
+            if !matches!(out.get(first), Some(Bson::Document(_))) {
+                out.insert(first, Document::new());
+            }
+            if let Some(Bson::Document(o)) = out.get_mut(first) {
+                include_path(d, o, rest);
+            }
+        }
+        Bson::Array(a) => {
+            let docs = a.iter().filter_map(Bson::as_document).collect::<Vec<_>>();
+            if !matches!(out.get(first), Some(Bson::Array(o)) if o.len() == docs.len()) {
+                out.insert(first, vec![Bson::Document(Document::new()); docs.len()]);
+            }
+            if let Some(Bson::Array(o)) = out.get_mut(first) {
+                for (d, o) in docs.into_iter().zip(o.iter_mut()) {
+                    if let Bson::Document(o) = o {
+                        include_path(d, o, rest);
+                    }
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Flatten the nested field specifications of `spec` into dotted paths.
+fn flatten(spec: &Document, prefix: &str, out: &mut Vec<(String, Bson)>) {
+    for (key, value) in spec.iter() {
+        let path = format!("{}{}", prefix, key);
+        match value {
+            Bson::Document(d) if d.keys().next().is_some_and(|k| !k.starts_with('$')) => flatten(d, &format!("{}.", path), out),
+            v => out.push((path, v.clone())),
+        }
+    }
+}
+
+/// Whether a `$project` value includes or excludes the field, rather than computing it.
+fn flag(value: &Bson) -> Option<bool> {
+    match value {
+        Bson::Boolean(b) => Some(*b),
+        v => number(v).map(|n| n != 0.),
+    }
+}
+
+fn project(rows: Vec<Row>, spec: &Document) -> Result<Vec<Row>, QueryError> {
+    let mut fields = Vec::new();
+    flatten(spec, "", &mut fields);
+    let exclusion = fields.iter().all(|(path, v)| path == "_id" || flag(v) == Some(false));
+    let id = fields.iter().find(|(path, _)| path == "_id").map(|(_, v)| v.clone());
+
+    let mut out = Vec::with_capacity(rows.len());
+    for row in rows {
+        let doc = if exclusion {
+            let mut doc = row.doc.clone();
+            for (path, _) in fields.iter() {
+                remove_path(&mut doc, path);
+            }
+            doc
+        } else {
+            let mut doc = Document::new();
+            match id {
+                None => include_path(&row.doc, &mut doc, "_id"),
+                Some(ref v) if flag(v) == Some(true) => include_path(&row.doc, &mut doc, "_id"),
+                _ => {}
+            }
+            for (path, value) in fields.iter() {
+                match flag(value) {
+                    Some(true) if path != "_id" => include_path(&row.doc, &mut doc, path),
+                    Some(_) => {}
+                    None => if let Some(v) = eval(&row, value)? {
+                        set_path(&mut doc, path, v);
+                    },
+                }
+            }
+            doc
+        };
+        out.push(Row { doc, score: row.score });
+    }
+    Ok(out)
+}
+
+fn add_fields(rows: Vec<Row>, spec: &Document) -> Result<Vec<Row>, QueryError> {
+    let mut fields = Vec::new();
+    flatten(spec, "", &mut fields);
+    let mut out = Vec::with_capacity(rows.len());
+    for mut row in rows {
+        // every expression sees the document as it was before the stage
+        let mut computed = Vec::with_capacity(fields.len());
+        for (path, expr) in fields.iter() {
+            computed.push((path, eval(&row, expr)?));
+        }
+        for (path, value) in computed {
+            if let Some(value) = value {
+                set_path(&mut row.doc, path, value);
+            }
+        }
+        out.push(row);
+    }
+    Ok(out)
+}
+
+fn lookup(collections: &impl Collections, rows: Vec<Row>, spec: &Document) -> Result<Vec<Row>, QueryError> {
+    let arg = |name: &str| spec.get_str(name).map_err(|_| unsupported(format!("`$lookup` without `{}`", name)));
+    let (from, local, foreign, into) = (arg("from")?, arg("localField")?, arg("foreignField")?, arg("as")?);
+    let foreign_rows = collections.collection(from);
+    let keys = |doc: &Document, path: &str| {
+        let c = candidates(doc, path).into_iter().cloned().collect::<Vec<_>>();
+        if c.is_empty() { vec![Bson::Null] } else { c }
+    };
+    let mut out = Vec::with_capacity(rows.len());
+    for mut row in rows {
+        let local_keys = keys(&row.doc, local);
+        let joined = foreign_rows.iter()
+            .filter(|f| keys(&f.doc, foreign).iter().any(|k| local_keys.iter().any(|l| equal(k, l))))
+            .map(|f| Bson::Document(f.doc.clone()))
+            .collect::<Vec<_>>();
+        set_path(&mut row.doc, into, Bson::Array(joined));
+        out.push(row);
+    }
+    Ok(out)
+}
+
+fn unwind(rows: Vec<Row>, spec: &Bson) -> Result<Vec<Row>, QueryError> {
+    let (path, preserve) = match spec {
+        Bson::String(p) => (p.as_str(), false),
+        Bson::Document(d) => {
+            if d.contains_key("includeArrayIndex") {
+                return Err(unsupported("`$unwind` with `includeArrayIndex`"));
+            }
+            let path = d.get_str("path").map_err(|_| invalid("`$unwind` stage"))?;
+            (path, d.get_bool("preserveNullAndEmptyArrays").unwrap_or(false))
+        }
+        _ => return Err(invalid("`$unwind` stage")),
+    };
+    let path = path.strip_prefix('$').ok_or_else(|| invalid("`$unwind` path"))?;
+    let mut out = Vec::with_capacity(rows.len());
+    for row in rows {
+        let value = resolve(&row.doc, path).first().map(|v| (*v).clone());
+        match value {
+            Some(Bson::Array(a)) if !a.is_empty() => for v in a {
+                let mut r = row.clone();
+                set_path(&mut r.doc, path, v);
+                out.push(r);
+            },
+            Some(Bson::Array(_)) | Some(Bson::Null) | None => if preserve {
+                out.push(row);
+            },
+            Some(_) => out.push(row),
+        }
+    }
+    Ok(out)
+}
+
+/// Value of the field at `path` for expressions: arrays along the path give the arrays of the
+/// values of their documents.
+fn field(value: &Bson, parts: &[&str]) -> Option<Bson> {
+    let Some((part, rest)) = parts.split_first() else {
+        return Some(value.clone());
+    };
+    match value {
+        Bson::Document(d) => d.get(*part).and_then(|v| field(v, rest)),
+        Bson::Array(a) => Some(Bson::Array(a.iter()
+            .filter(|v| matches!(v, Bson::Document(_)))
+            .filter_map(|v| field(v, parts))
+            .collect())),
+        _ => None,
+    }
+}
+
+/// Evaluate the aggregation expression `expr` on `row`. `None` stands for a missing value.
+fn eval(row: &Row, expr: &Bson) -> Result<Option<Bson>, QueryError> {
+    match expr {
+        Bson::String(s) if s.starts_with("$$") => Err(unsupported(format!("variable `{}`", s))),
+        Bson::String(s) if s.starts_with('$') => {
+            let parts = s[1..].split('.').collect::<Vec<_>>();
+            Ok(row.doc.get(parts[0]).and_then(|v| field(v, &parts[1..])))
+        }
+        Bson::Document(d) if d.len() == 1 && d.keys().next().is_some_and(|k| k.starts_with('$')) => {
+            let (op, arg) = d.iter().next().expect("one operator");
+            operator(row, op, arg)
+        }
+        Bson::Document(d) => {
+            let mut out = Document::new();
+            for (k, v) in d.iter() {
+                if let Some(v) = eval(row, v)? {
+                    out.insert(k, v);
+                }
+            }
+            Ok(Some(Bson::Document(out)))
+        }
+        Bson::Array(a) => {
+            let mut out = Vec::with_capacity(a.len());
+            for v in a {
+                out.push(eval(row, v)?.unwrap_or(Bson::Null));
+            }
+            Ok(Some(Bson::Array(out)))
+        }
+        v => Ok(Some(v.clone())),
+    }
+}
+
+fn is_null(value: &Option<Bson>) -> bool {
+    matches!(value, None | Some(Bson::Null) | Some(Bson::Undefined))
+}
+
+/// Sum or product of `args`: dates can only be added to, and any missing argument makes the
+/// result `null`.
+fn arithmetic(op: &str, args: Vec<Option<Bson>>) -> Result<Option<Bson>, QueryError> {
+    if args.iter().any(is_null) {
+        return Ok(Some(Bson::Null));
+    }
+    let adding = op == "$add";
+    let mut date = None;
+    let mut result = if adding { 0. } else { 1. };
+    let mut integer = true;
+    for arg in args.into_iter().flatten() {
+        match arg {
+            Bson::DateTime(d) if adding && date.is_none() => date = Some(d),
+            Bson::Double(d) => {
+                integer = false;
+                result = if adding { result + d } else { result * d };
+            }
+            v => match number(&v) {
+                Some(n) => result = if adding { result + n } else { result * n },
+                None => return Err(invalid(format!("`{}` argument {}", op, v))),
+            },
+        }
+    }
+    Ok(Some(match date {
+        Some(d) => Bson::DateTime(mongodb::bson::DateTime::from_millis(d.timestamp_millis() + result.round() as i64)),
+        None if integer => Bson::Int64(result as i64),
+        None => Bson::Double(result),
+    }))
+}
+
+fn operator(row: &Row, op: &str, arg: &Bson) -> Result<Option<Bson>, QueryError> {
+    let args = || -> Result<Vec<Option<Bson>>, QueryError> {
+        match arg {
+            Bson::Array(a) => a.iter().map(|v| eval(row, v)).collect(),
+            v => Ok(vec![eval(row, v)?]),
+        }
+    };
+    let pair = || -> Result<(Bson, Bson), QueryError> {
+        match &args()?[..] {
+            [a, b] => Ok((a.clone().unwrap_or(Bson::Null), b.clone().unwrap_or(Bson::Null))),
+            _ => Err(invalid(format!("`{}` arguments", op))),
+        }
+    };
+    Ok(match op {
+        "$literal" => Some(arg.clone()),
+        "$meta" if arg.as_str() == Some("textScore") => Some(Bson::Double(row.score)),
+        "$add" | "$multiply" => arithmetic(op, args()?)?,
+        "$arrayElemAt" => {
+            let (array, index) = pair()?;
+            match (array, number(&index)) {
+                (Bson::Array(a), Some(i)) => {
+                    let i = if i < 0. { a.len() as i64 + i as i64 } else { i as i64 };
+                    usize::try_from(i).ok().and_then(|i| a.get(i).cloned())
+                }
+                (Bson::Null, _) => Some(Bson::Null),
+                _ => return Err(invalid("`$arrayElemAt` arguments")),
+            }
+        }
+        "$ifNull" => {
+            let args = args()?;
+            let fallback = args.last().cloned().flatten();
+            args.into_iter().find(|a| !is_null(a)).flatten().or(fallback)
+        }
+        "$strLenCP" => match args()?.pop().flatten() {
+            Some(Bson::String(s)) => Some(Bson::Int32(s.chars().count() as i32)),
+            _ => return Err(invalid("`$strLenCP` argument")),
+        },
+        "$eq" | "$ne" | "$gt" | "$gte" | "$lt" | "$lte" => {
+            let (a, b) = pair()?;
+            let ord = compare(&a, &b, None);
+            Some(Bson::Boolean(match op {
+                "$eq" => ord == Ordering::Equal,
+                "$ne" => ord != Ordering::Equal,
+                "$gt" => ord == Ordering::Greater,
+                "$gte" => ord != Ordering::Less,
+                "$lt" => ord == Ordering::Less,
+                _ => ord != Ordering::Greater,
+            }))
+        }
+        "$cond" => {
+            let (cond, then, otherwise) = match arg {
+                Bson::Array(a) if a.len() == 3 => (&a[0], &a[1], &a[2]),
+                Bson::Document(d) => match (d.get("if"), d.get("then"), d.get("else")) {
+                    (Some(c), Some(t), Some(e)) => (c, t, e),
+                    _ => return Err(invalid("`$cond` arguments")),
+                },
+                _ => return Err(invalid("`$cond` arguments")),
+            };
+            if truthy(&eval(row, cond)?) { eval(row, then)? } else { eval(row, otherwise)? }
+        }
+        "$regexMatch" => {
+            let spec = arg.as_document().ok_or_else(|| invalid("`$regexMatch` arguments"))?;
+            let re = match spec.get("regex") {
+                Some(Bson::String(p)) => regex(p, spec.get_str("options").unwrap_or(""))?,
+                Some(Bson::RegularExpression(r)) => regex(&r.pattern, &r.options)?,
+                _ => return Err(invalid("`$regexMatch` regex")),
+            };
+            match spec.get("input").map(|i| eval(row, i)).transpose()?.flatten() {
+                Some(Bson::String(s)) => Some(Bson::Boolean(re.is_match(&s))),
+                i if is_null(&i) => Some(Bson::Boolean(false)),
+                _ => return Err(invalid("`$regexMatch` input")),
+            }
+        }
+        "$and" => Some(Bson::Boolean(args()?.iter().all(truthy))),
+        "$or" => Some(Bson::Boolean(args()?.iter().any(truthy))),
+        "$not" => Some(Bson::Boolean(!args()?.first().is_some_and(truthy))),
+        _ => return Err(unsupported(format!("expression operator `{}`", op))),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use mongodb::bson::{self, doc, Bson, Document};
+    use mongodb::options::Collation;
+
+    use super::*;
+
+    impl Collections for HashMap<&'static str, Vec<Document>> {
+        fn collection(&self, name: &str) -> Vec<Row> {
+            self.get(name)
+                .map(|docs| docs.iter().cloned().map(Row::new).collect())
+                .unwrap_or_default()
+        }
+    }
+
+    fn stops() -> Vec<Document> {
+        vec![
+            doc!{"id": 1, "name": "Piazza Dante", "town": "Trento", "code": "10", "tags": ["a", "b"], "position": [46.07, 11.12]},
+            doc!{"id": 2, "name": "Povo Polo", "town": "Trento", "code": "2", "tags": ["b"], "position": [46.06, 11.15]},
+            doc!{"id": 3, "name": "Àla", "town": "Ala", "code": "100", "position": [45.76, 11.0]},
+        ]
+    }
+
+    fn run_with(collections: &HashMap<&'static str, Vec<Document>>, docs: Vec<Document>, stages: Vec<Document>, collation: Option<&Collation>) -> Result<Vec<Document>, QueryError> {
+        let rows = run(collections, docs.into_iter().map(Row::new).collect(), &stages, collation)?;
+        Ok(rows.into_iter().map(|r| r.doc).collect())
+    }
+
+    fn run_on(docs: Vec<Document>, stages: Vec<Document>) -> Result<Vec<Document>, QueryError> {
+        run_with(&HashMap::new(), docs, stages, None)
+    }
+
+    /// Ids of the stops matching `filter`, in their order.
+    fn matching(filter: Document) -> Vec<i32> {
+        ids(run_on(stops(), vec![doc!{"$match": filter}]).unwrap())
+    }
+
+    fn ids(docs: Vec<Document>) -> Vec<i32> {
+        docs.iter().map(|d| d.get_i32("id").unwrap()).collect()
+    }
+
+    fn assert_unsupported<T: std::fmt::Debug>(result: Result<T, QueryError>, what: &str) {
+        match result {
+            Err(QueryError::Unsupported(m)) => assert!(m.contains(what), "`{}` doesn't mention `{}`", m, what),
+            r => panic!("expected an error about `{}`, got {:?}", what, r.map_err(|e| e.to_string())),
+        }
+    }
+
+    fn value(doc: Document, expr: Bson) -> Result<Option<Bson>, QueryError> {
+        eval(&Row::new(doc), &expr)
+    }
+
+    #[test]
+    fn match_equality() {
+        assert_eq!(matching(doc!{"town": "Trento"}), [1, 2]);
+        assert_eq!(matching(doc!{"town": "Trento", "id": 2}), [2]);
+        // arrays match any of their elements, and can be indexed
+        assert_eq!(matching(doc!{"tags": "a"}), [1]);
+        assert_eq!(matching(doc!{"position.0": 46.06}), [2]);
+        // `null` also matches missing fields
+        assert_eq!(matching(doc!{"tags": Bson::Null}), [3]);
+    }
+
+    #[test]
+    fn match_comparisons() {
+        assert_eq!(matching(doc!{"id": {"$eq": 2}}), [2]);
+        assert_eq!(matching(doc!{"id": {"$ne": 2}}), [1, 3]);
+        assert_eq!(matching(doc!{"id": {"$gt": 1}}), [2, 3]);
+        assert_eq!(matching(doc!{"id": {"$gte": 2}}), [2, 3]);
+        assert_eq!(matching(doc!{"id": {"$lt": 2}}), [1]);
+        assert_eq!(matching(doc!{"id": {"$lte": 2}}), [1, 2]);
+        assert_eq!(matching(doc!{"position.1": {"$gte": 11.1, "$lte": 11.13}}), [1]);
+        // values of other types are never in range
+        assert_eq!(matching(doc!{"code": {"$gt": 1}}), Vec::<i32>::new());
+    }
+
+    #[test]
+    fn match_sets() {
+        assert_eq!(matching(doc!{"id": {"$in": [1, 3]}}), [1, 3]);
+        assert_eq!(matching(doc!{"id": {"$nin": [1, 3]}}), [2]);
+        assert_eq!(matching(doc!{"tags": {"$in": ["b"]}}), [1, 2]);
+        assert_eq!(matching(doc!{"tags": {"$exists": true}}), [1, 2]);
+        assert_eq!(matching(doc!{"tags": {"$exists": false}}), [3]);
+    }
+
+    #[test]
+    fn match_regex() {
+        assert_eq!(matching(doc!{"name": {"$regex": "^p", "$options": "i"}}), [1, 2]);
+        assert_eq!(matching(doc!{"name": {"$regex": "^p"}}), Vec::<i32>::new());
+        let literal = bson::Regex { pattern: "polo$".to_owned(), options: "i".to_owned() };
+        assert_eq!(matching(doc!{"name": literal}), [2]);
+        assert_unsupported(run_on(stops(), vec![doc!{"$match": {"name": {"$regex": "("}}}]), "regular expression");
+    }
+
+    #[test]
+    fn match_logical() {
+        assert_eq!(matching(doc!{"$or": [{"id": 1}, {"id": 3}]}), [1, 3]);
+        assert_eq!(matching(doc!{"$and": [{"town": "Trento"}, {"id": {"$gt": 1}}]}), [2]);
+        assert_eq!(matching(doc!{"$nor": [{"id": 1}]}), [2, 3]);
+        assert_eq!(matching(doc!{"$expr": {"$gt": ["$id", 1]}}), [2, 3]);
+    }
+
+    #[test]
+    fn match_text() {
+        let found = run_on(stops(), vec![
+            doc!{"$match": {"$text": {"$search": "POVO trento"}}},
+            doc!{"$sort": {"score": {"$meta": "textScore"}}},
+            doc!{"$project": {"id": 1, "score": {"$meta": "textScore"}}},
+        ]).unwrap();
+        assert_eq!(found, [doc!{"id": 2, "score": 2.}, doc!{"id": 1, "score": 1.}]);
+        // accents are ignored
+        assert_eq!(matching(doc!{"$text": {"$search": "ala"}}), [3]);
+        assert_unsupported(run_on(stops(), vec![doc!{"$match": {"$text": {"$search": "\"piazza dante\""}}}]), "phrase");
+    }
+
+    #[test]
+    fn match_unsupported_operators() {
+        assert_unsupported(run_on(stops(), vec![doc!{"$match": {"id": {"$mod": [2, 0]}}}]), "`$mod`");
+        assert_unsupported(run_on(stops(), vec![doc!{"$match": {"$where": "true"}}]), "`$where`");
+        assert_unsupported(run_on(stops(), vec![doc!{"$match": {"$or": {"id": 1}}}]), "`$or` condition");
+    }
+
+    #[test]
+    fn sort() {
+        let sorted = |spec: Document, collation: Option<&Collation>| ids(run_with(&HashMap::new(), stops(), vec![doc!{"$sort": spec}], collation).unwrap());
+        let italian = Collation::builder().locale("it").numeric_ordering(true).build();
+        assert_eq!(sorted(doc!{"id": -1}, None), [3, 2, 1]);
+        assert_eq!(sorted(doc!{"town": 1, "id": -1}, None), [3, 2, 1]);
+        // codes are compared by their numeric value, and names ignoring case and accents, only
+        // with a collation
+        assert_eq!(sorted(doc!{"code": 1}, None), [1, 3, 2]);
+        assert_eq!(sorted(doc!{"code": 1}, Some(&italian)), [2, 1, 3]);
+        assert_eq!(sorted(doc!{"name": 1}, None), [1, 2, 3]);
+        assert_eq!(sorted(doc!{"name": 1}, Some(&italian)), [3, 1, 2]);
+        // arrays sort by their lowest element ascending, and by their highest one descending;
+        // missing values come first
+        assert_eq!(sorted(doc!{"tags": 1}, None), [3, 1, 2]);
+        assert_eq!(sorted(doc!{"tags": -1}, None), [1, 2, 3]);
+        assert_unsupported(run_on(stops(), vec![doc!{"$sort": {"id": "up"}}]), "sort order of `id`");
+    }
+
+    #[test]
+    fn natural_order() {
+        assert_eq!(natural("linea 2", "linea 10"), Ordering::Less);
+        assert_eq!(natural("5/", "5"), Ordering::Greater);
+        assert_eq!(natural("a007", "a7"), Ordering::Equal);
+    }
+
+    #[test]
+    fn skip_and_limit() {
+        assert_eq!(ids(run_on(stops(), vec![doc!{"$skip": 1}, doc!{"$limit": 1}]).unwrap()), [2]);
+        assert_eq!(ids(run_on(stops(), vec![doc!{"$skip": 5}]).unwrap()), Vec::<i32>::new());
+        assert_unsupported(run_on(stops(), vec![doc!{"$limit": -1}]), "`$limit` stage");
+        assert_unsupported(run_on(stops(), vec![doc!{"$skip": "1"}]), "`$skip` stage");
+    }
+
+    #[test]
+    fn project() {
+        let projected = |spec: Document| run_on(vec![stops().remove(0)], vec![doc!{"$project": spec}]).unwrap().remove(0);
+        assert_eq!(projected(doc!{"_id": 0, "id": 1, "name": 1}), doc!{"id": 1, "name": "Piazza Dante"});
+        assert_eq!(projected(doc!{"tags": 0, "position": 0, "code": 0}), doc!{"id": 1, "name": "Piazza Dante", "town": "Trento"});
+        assert_eq!(projected(doc!{"id": 1, "label": {"$ifNull": ["$missing", "$name"]}}), doc!{"id": 1, "label": "Piazza Dante"});
+
+        let trip = doc!{"_id": 7, "id": "t", "stops": [{"id": 1, "at": 10}, {"id": 2, "at": 20}]};
+        let projected = run_on(vec![trip.clone()], vec![doc!{"$project": {"stops.id": 1}}]).unwrap();
+        assert_eq!(projected, [doc!{"_id": 7, "stops": [{"id": 1}, {"id": 2}]}]);
+        let projected = run_on(vec![trip], vec![doc!{"$project": {"stops": {"at": 0}}}]).unwrap();
+        assert_eq!(projected, [doc!{"_id": 7, "id": "t", "stops": [{"id": 1}, {"id": 2}]}]);
+    }
+
+    #[test]
+    fn add_fields() {
+        let added = run_on(stops(), vec![
+            doc!{"$match": {"id": 1}},
+            // every field is computed on the document as it was before the stage
+            doc!{"$addFields": {"next": {"$add": ["$id", 10]}, "id": 0}},
+            doc!{"$set": {"meta.len": {"$strLenCP": "$name"}}},
+        ]).unwrap().remove(0);
+        assert_eq!(added.get("id"), Some(&Bson::Int32(0)));
+        assert_eq!(added.get("next"), Some(&Bson::Int64(11)));
+        assert_eq!(added.get_document("meta").unwrap(), &doc!{"len": 12});
+    }
+
+    #[test]
+    fn lookup() {
+        let collections = HashMap::from([("routes", vec![doc!{"id": 5, "code": "5"}, doc!{"id": 6, "code": "6"}])]);
+        let trips = vec![doc!{"id": "a", "route": 5}, doc!{"id": "b", "route": 7}, doc!{"id": "c"}];
+        let joined = run_with(&collections, trips, vec![
+            doc!{"$lookup": {"from": "routes", "localField": "route", "foreignField": "id", "as": "routes"}},
+        ], None).unwrap();
+        let codes = joined.iter()
+            .map(|d| d.get_array("routes").unwrap().iter().map(|r| r.as_document().unwrap().get_str("code").unwrap()).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        assert_eq!(codes, [vec!["5"], vec![], vec![]]);
+        assert_unsupported(run_on(vec![], vec![doc!{"$lookup": {"from": "routes", "pipeline": []}}]), "without `localField`");
+    }
+
+    #[test]
+    fn unwind() {
+        let tagged = |spec: Bson| run_on(stops(), vec![doc!{"$unwind": spec}, doc!{"$project": {"id": 1, "tags": 1}}]).unwrap();
+        assert_eq!(tagged(Bson::String("$tags".to_owned())), [doc!{"id": 1, "tags": "a"}, doc!{"id": 1, "tags": "b"}, doc!{"id": 2, "tags": "b"}]);
+        let preserved = tagged(Bson::Document(doc!{"path": "$tags", "preserveNullAndEmptyArrays": true}));
+        assert_eq!(ids(preserved), [1, 1, 2, 3]);
+        assert_unsupported(run_on(stops(), vec![doc!{"$unwind": {"path": "$tags", "includeArrayIndex": "i"}}]), "includeArrayIndex");
+        assert_unsupported(run_on(stops(), vec![doc!{"$unwind": "tags"}]), "`$unwind` path");
+    }
+
+    #[test]
+    fn count() {
+        assert_eq!(run_on(stops(), vec![doc!{"$match": {"id": {"$gt": 1}}}, doc!{"$count": "n"}]).unwrap(), [doc!{"n": 2_i64}]);
+        // nothing to count gives no document at all, as in MongoDB
+        assert_eq!(run_on(stops(), vec![doc!{"$match": {"id": 0}}, doc!{"$count": "n"}]).unwrap(), Vec::<Document>::new());
+    }
+
+    #[test]
+    fn facet() {
+        let result = run_on(stops(), vec![doc!{"$facet": {
+            "page": [{"$skip": 1}, {"$limit": 1}, {"$project": {"id": 1}}],
+            "total": [{"$count": "n"}],
+        }}]).unwrap();
+        assert_eq!(result, [doc!{"page": [{"id": 2}], "total": [{"n": 3_i64}]}]);
+    }
+
+    #[test]
+    fn unsupported_stages() {
+        assert_unsupported(run_on(stops(), vec![doc!{"$group": {"_id": "$town"}}]), "stage `$group`");
+        assert_unsupported(run_on(stops(), vec![doc!{"$skip": 1, "$limit": 1}]), "invalid stage");
+    }
+
+    #[test]
+    fn expressions() {
+        let stop = stops().remove(0);
+        let v = |expr: Bson| value(stop.clone(), expr).unwrap();
+        assert_eq!(v(Bson::String("$town".to_owned())), Some(Bson::String("Trento".to_owned())));
+        assert_eq!(v(Bson::String("$missing".to_owned())), None);
+        assert_eq!(v(Bson::Document(doc!{"$literal": "$town"})), Some(Bson::String("$town".to_owned())));
+        assert_eq!(v(Bson::Document(doc!{"$arrayElemAt": ["$position", -1]})), Some(Bson::Double(11.12)));
+        assert_eq!(v(Bson::Document(doc!{"$arrayElemAt": ["$position", 2]})), None);
+        assert_eq!(v(Bson::Document(doc!{"$multiply": ["$id", 1.5]})), Some(Bson::Double(1.5)));
+        assert_eq!(v(Bson::Document(doc!{"$add": ["$id", "$missing"]})), Some(Bson::Null));
+        assert_eq!(
+            v(Bson::Document(doc!{"$add": [bson::DateTime::from_millis(1_000), {"$multiply": [1000, "$id"]}]})),
+            Some(Bson::DateTime(bson::DateTime::from_millis(2_000))),
+        );
+        assert_eq!(v(Bson::Document(doc!{"$ifNull": ["$missing", "$code"]})), Some(Bson::String("10".to_owned())));
+        assert_eq!(v(Bson::Document(doc!{"$strLenCP": "Àla"})), Some(Bson::Int32(3)));
+        assert_eq!(v(Bson::Document(doc!{"$eq": ["$id", 1]})), Some(Bson::Boolean(true)));
+        assert_eq!(v(Bson::Document(doc!{"$ne": ["$id", 1]})), Some(Bson::Boolean(false)));
+        assert_eq!(v(Bson::Document(doc!{"$lt": ["$id", 2]})), Some(Bson::Boolean(true)));
+        assert_eq!(v(Bson::Document(doc!{"$lte": ["$id", 0]})), Some(Bson::Boolean(false)));
+        assert_eq!(v(Bson::Document(doc!{"$gte": ["$id", 1]})), Some(Bson::Boolean(true)));
+        assert_eq!(v(Bson::Document(doc!{"$and": [true, "$id"]})), Some(Bson::Boolean(true)));
+        assert_eq!(v(Bson::Document(doc!{"$or": [false, 0, "$missing"]})), Some(Bson::Boolean(false)));
+        assert_eq!(v(Bson::Document(doc!{"$not": ["$missing"]})), Some(Bson::Boolean(true)));
+        assert_eq!(v(Bson::Document(doc!{"name": "$name", "gone": "$missing"})), Some(Bson::Document(doc!{"name": "Piazza Dante"})));
+        assert_eq!(v(Bson::Document(doc!{"$cond": [{"$eq": ["$id", 1]}, "one", "other"]})), Some(Bson::String("one".to_owned())));
+        assert_eq!(v(Bson::Document(doc!{"$cond": {"if": "$missing", "then": 1, "else": "$code"}})), Some(Bson::String("10".to_owned())));
+        assert_eq!(v(Bson::Document(doc!{"$regexMatch": {"input": "$name", "regex": "^piazza", "options": "i"}})), Some(Bson::Boolean(true)));
+        assert_eq!(v(Bson::Document(doc!{"$regexMatch": {"input": "$name", "regex": "^Dante"}})), Some(Bson::Boolean(false)));
+        assert_eq!(v(Bson::Document(doc!{"$regexMatch": {"input": "$missing", "regex": "."}})), Some(Bson::Boolean(false)));
+    }
+
+    #[test]
+    fn unsupported_expressions() {
+        let stop = stops().remove(0);
+        assert_unsupported(value(stop.clone(), Bson::Document(doc!{"$concat": ["$name", "$town"]})), "`$concat`");
+        assert_unsupported(value(stop.clone(), Bson::String("$$ROOT".to_owned())), "`$$ROOT`");
+        assert_unsupported(value(stop.clone(), Bson::Document(doc!{"$strLenCP": "$id"})), "`$strLenCP` argument");
+        assert_unsupported(value(stop.clone(), Bson::Document(doc!{"$add": ["$id", "$name"]})), "`$add` argument");
+        assert_unsupported(value(stop.clone(), Bson::Document(doc!{"$cond": [true, 1]})), "`$cond` arguments");
+        assert_unsupported(value(stop, Bson::Document(doc!{"$regexMatch": {"input": "$id", "regex": "1"}})), "`$regexMatch` input");
+    }
+
+    #[test]
+    fn distinct_values() {
+        let stop = stops().remove(0);
+        assert_eq!(values(&stop, "tags"), [&Bson::String("a".to_owned()), &Bson::String("b".to_owned())]);
+        assert_eq!(values(&stop, "town"), [&Bson::String("Trento".to_owned())]);
+        assert!(values(&stop, "missing").is_empty());
+    }
+}
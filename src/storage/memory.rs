@@ -0,0 +1,147 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::path::{Path as FsPath, PathBuf};
+use std::sync::Arc;
+
+use bruss_data::{Area, BrussType, Path, Route, Schedule, Segment, Stop, Trip};
+use mongodb::bson::oid::ObjectId;
+use mongodb::bson::{doc, Bson, Document};
+use mongodb::options::{AggregateOptions, FindOneOptions};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::routes::map::query::QueryError;
+use super::eval::{self, Row};
+use super::Storage;
+
+/// Failure to load a fixture file into a [`MemoryStore`].
+#[derive(Debug)]
+pub struct FixtureError {
+    pub file: PathBuf,
+    reason: String,
+}
+
+impl Display for FixtureError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "cannot load fixture {}: {}", self.file.display(), self.reason)
+    }
+}
+
+impl std::error::Error for FixtureError {}
+
+/// Read-only storage keeping every collection in memory, able to run the pipelines the routes
+/// build, so that they can be served, and tested, without a database.
+///
+/// Only the stages, operators and expressions the routes use are supported: anything else
+/// fails the query with [`QueryError::Unsupported`].
+#[derive(Clone, Default)]
+pub struct MemoryStore(Arc<HashMap<String, Vec<Document>>>);
+
+impl MemoryStore {
+    /// Store holding `collections`, by name. Documents without an `_id` get one, in their
+    /// order, as if they were inserted into MongoDB.
+    pub fn new(collections: HashMap<String, Vec<Document>>) -> Self {
+        let collections = collections.into_iter()
+            .map(|(name, docs)| {
+                let docs = docs.into_iter()
+                    .map(|mut d| {
+                        if !d.contains_key("_id") {
+                            let mut with_id = Document::new();
+                            with_id.insert("_id", ObjectId::new());
+                            with_id.extend(d);
+                            d = with_id;
+                        }
+                        d
+                    })
+                    .collect();
+                (name, docs)
+            })
+            .collect();
+        MemoryStore(Arc::new(collections))
+    }
+
+    /// Load the fixtures in `dir`: one JSON array per collection, named after it (as
+    /// `stops.json`), of areas, routes, stops, trips, schedules, segments and paths. Documents
+    /// are checked against their type, and collections without a file are left empty.
+    pub fn from_fixtures(dir: impl AsRef<FsPath>) -> Result<Self, FixtureError> {
+        let dir = dir.as_ref();
+        let mut collections = HashMap::new();
+        load::<Area>(dir, &mut collections)?;
+        load::<Route>(dir, &mut collections)?;
+        load::<Stop>(dir, &mut collections)?;
+        load::<Trip>(dir, &mut collections)?;
+        load::<Schedule>(dir, &mut collections)?;
+        load::<Segment>(dir, &mut collections)?;
+        load::<Path>(dir, &mut collections)?;
+        Ok(MemoryStore::new(collections))
+    }
+
+    fn rows(&self, collection: &str) -> Vec<Row> {
+        self.0.get(collection)
+            .map(|docs| docs.iter().cloned().map(Row::new).collect())
+            .unwrap_or_default()
+    }
+}
+
+/// Load the fixture of the collection of `X` from `dir`, if any.
+fn load<X: BrussType + DeserializeOwned + Serialize>(dir: &FsPath, collections: &mut HashMap<String, Vec<Document>>) -> Result<(), FixtureError> {
+    let collection = X::TYPE.collection().to_string();
+    let file = dir.join(format!("{}.json", collection));
+    if !file.exists() {
+        return Ok(());
+    }
+    let error = |reason: String| FixtureError { file: file.clone(), reason };
+    let json = std::fs::read_to_string(&file).map_err(|e| error(e.to_string()))?;
+    let items: Vec<X> = serde_json::from_str(&json).map_err(|e| error(e.to_string()))?;
+    // stored as MongoDB would, through the serialization of the type
+    let docs = items.iter()
+        .map(mongodb::bson::to_document)
+        .collect::<Result<Vec<Document>, _>>()
+        .map_err(|e| error(e.to_string()))?;
+    collections.insert(collection, docs);
+    Ok(())
+}
+
+impl Storage for MemoryStore {
+    async fn aggregate(&self, collection: &str, stages: Vec<Document>, options: AggregateOptions) -> Result<Vec<Document>, QueryError> {
+        let rows = eval::run(self, self.rows(collection), &stages, options.collation.as_ref())?;
+        Ok(rows.into_iter().map(|r| r.doc).collect())
+    }
+
+    async fn estimated_count(&self, collection: &str) -> Result<u64, QueryError> {
+        Ok(self.0.get(collection).map(Vec::len).unwrap_or(0) as u64)
+    }
+
+    async fn find_one(&self, collection: &str, filter: Document, options: FindOneOptions) -> Result<Option<Document>, QueryError> {
+        let mut stages = vec![doc!{"$match": filter}];
+        if let Some(sort) = options.sort {
+            stages.push(doc!{"$sort": sort});
+        }
+        stages.push(doc!{"$limit": 1});
+        if let Some(projection) = options.projection {
+            stages.push(doc!{"$project": projection});
+        }
+        let rows = eval::run(self, self.rows(collection), &stages, options.collation.as_ref())?;
+        Ok(rows.into_iter().next().map(|r| r.doc))
+    }
+
+    async fn distinct(&self, collection: &str, field: &str, filter: Document) -> Result<Vec<Bson>, QueryError> {
+        let rows = eval::run(self, self.rows(collection), &[doc!{"$match": filter}], None)?;
+        let mut values: Vec<Bson> = Vec::new();
+        for row in rows.iter() {
+            for v in eval::values(&row.doc, field) {
+                let v = v.clone();
+                if !values.iter().any(|e| eval::equal(e, &v)) {
+                    values.push(v);
+                }
+            }
+        }
+        Ok(values)
+    }
+}
+
+impl eval::Collections for MemoryStore {
+    fn collection(&self, name: &str) -> Vec<Row> {
+        self.rows(name)
+    }
+}
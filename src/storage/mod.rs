@@ -0,0 +1,99 @@
+mod eval;
+mod memory;
+
+pub use memory::{FixtureError, MemoryStore};
+
+use futures::TryStreamExt;
+use mongodb::bson::{Bson, Document};
+use mongodb::options::{AggregateOptions, FindOneOptions};
+use mongodb::Database;
+
+use crate::routes::map::query::QueryError;
+
+/// Storage the queries of the [`Queryable`](crate::routes::map::query::Queryable) traits are run
+/// on, in terms of the operations they need.
+pub trait Storage {
+    /// Run the aggregation `stages` on `collection`.
+    async fn aggregate(&self, collection: &str, stages: Vec<Document>, options: AggregateOptions) -> Result<Vec<Document>, QueryError>;
+
+    /// Number of documents of `collection`, from its metadata where available.
+    async fn estimated_count(&self, collection: &str) -> Result<u64, QueryError>;
+
+    /// First document of `collection` matching `filter`.
+    async fn find_one(&self, collection: &str, filter: Document, options: FindOneOptions) -> Result<Option<Document>, QueryError>;
+
+    /// Distinct values of `field` among the documents of `collection` matching `filter`.
+    async fn distinct(&self, collection: &str, field: &str, filter: Document) -> Result<Vec<Bson>, QueryError>;
+}
+
+impl Storage for Database {
+    async fn aggregate(&self, collection: &str, stages: Vec<Document>, options: AggregateOptions) -> Result<Vec<Document>, QueryError> {
+        Ok(self.collection::<Document>(collection)
+            .aggregate(stages, options)
+            .await?
+            .try_collect()
+            .await?)
+    }
+
+    async fn estimated_count(&self, collection: &str) -> Result<u64, QueryError> {
+        Ok(self.collection::<Document>(collection).estimated_document_count(None).await?)
+    }
+
+    async fn find_one(&self, collection: &str, filter: Document, options: FindOneOptions) -> Result<Option<Document>, QueryError> {
+        Ok(self.collection::<Document>(collection).find_one(filter, options).await?)
+    }
+
+    async fn distinct(&self, collection: &str, field: &str, filter: Document) -> Result<Vec<Bson>, QueryError> {
+        Ok(self.collection::<Document>(collection).distinct(field, filter, None).await?)
+    }
+}
+
+/// Storage backing a [`DBInterface`](crate::routes::map::query::DBInterface): MongoDB, or the
+/// [`MemoryStore`] when it's managed by Rocket, as when serving fixtures.
+#[derive(Clone)]
+pub enum Backend {
+    Mongo(Database),
+    Memory(MemoryStore),
+}
+
+impl Backend {
+    /// The MongoDB database, for the operations only it supports (explaining queries, recording
+    /// slow ones and caching realtime data).
+    pub fn mongo(&self) -> Result<&Database, QueryError> {
+        match self {
+            Backend::Mongo(db) => Ok(db),
+            Backend::Memory(_) => Err(QueryError::Unsupported("this operation needs MongoDB storage".to_owned())),
+        }
+    }
+}
+
+// `Database` has an inherent `aggregate` on the whole database, hence the qualified calls
+impl Storage for Backend {
+    async fn aggregate(&self, collection: &str, stages: Vec<Document>, options: AggregateOptions) -> Result<Vec<Document>, QueryError> {
+        match self {
+            Backend::Mongo(db) => Storage::aggregate(db, collection, stages, options).await,
+            Backend::Memory(store) => Storage::aggregate(store, collection, stages, options).await,
+        }
+    }
+
+    async fn estimated_count(&self, collection: &str) -> Result<u64, QueryError> {
+        match self {
+            Backend::Mongo(db) => Storage::estimated_count(db, collection).await,
+            Backend::Memory(store) => Storage::estimated_count(store, collection).await,
+        }
+    }
+
+    async fn find_one(&self, collection: &str, filter: Document, options: FindOneOptions) -> Result<Option<Document>, QueryError> {
+        match self {
+            Backend::Mongo(db) => Storage::find_one(db, collection, filter, options).await,
+            Backend::Memory(store) => Storage::find_one(store, collection, filter, options).await,
+        }
+    }
+
+    async fn distinct(&self, collection: &str, field: &str, filter: Document) -> Result<Vec<Bson>, QueryError> {
+        match self {
+            Backend::Mongo(db) => Storage::distinct(db, collection, field, filter).await,
+            Backend::Memory(store) => Storage::distinct(store, collection, field, filter).await,
+        }
+    }
+}
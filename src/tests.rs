@@ -1,10 +1,332 @@
 #![cfg(test)]
 
-use bruss_data::BrussType;
+use std::collections::HashMap;
+
+use bruss_data::{Area, BrussType, Path, Route, Schedule, Segment, Stop, Trip};
 use chrono::{DateTime, Local, NaiveDate, NaiveDateTime};
-use mongodb::bson::doc;
+use mongodb::bson::{doc, Document};
+use rocket::http::Status;
+use rocket::local::asynchronous::{Client, LocalResponse};
+use serde_json::{json, Value};
 use tt::{AreaType, TTTrip, TTType};
 
+use crate::batch::Sharing;
+use crate::config::API_CONFIG;
+use crate::query_cache::{DatasetVersion, QueryCache};
+use crate::storage::MemoryStore;
+
+/// Small dataset of the Trento urban area, as the fixtures a `MemoryStore` is loaded from.
+pub(crate) fn fixture() -> MemoryStore {
+    MemoryStore::new(fixture_collections())
+}
+
+fn collection<X: BrussType>(docs: Vec<Document>) -> (String, Vec<Document>) {
+    (X::TYPE.collection().to_string(), docs)
+}
+
+fn fixture_collections() -> HashMap<String, Vec<Document>> {
+    HashMap::from([
+        collection::<Area>(vec![
+            doc!{"id": 1, "label": "Urbano Trento", "type": "u"},
+            doc!{"id": 2, "label": "Extraurbano", "type": "e"},
+            doc!{"id": 3, "label": "Urbano Rovereto", "type": "u"},
+        ]),
+        collection::<Route>(vec![
+            doc!{"id": 400, "type": 3, "area": 1, "area_ty": "u", "color": "CCCCCC", "name": "P.Dante Povo", "code": "5"},
+            doc!{"id": 401, "type": 3, "area": 1, "area_ty": "u", "color": "FF0000", "name": "Mattarello Gardolo", "code": "13"},
+            doc!{"id": 402, "type": 3, "area": 1, "area_ty": "u", "color": "00FF00", "name": "P.Dante Oltrecastello", "code": "5/"},
+        ]),
+        collection::<Stop>(vec![
+            doc!{"id": 1, "code": "21545-", "description": "", "position": [46.0725, 11.1196], "altitude": 194, "name": "Piazza Dante", "town": "Trento", "type": "u", "wheelchair_boarding": true},
+            doc!{"id": 2, "code": "21705z", "description": "", "position": [46.0670, 11.1500], "altitude": 385, "name": "Povo Polo Scientifico", "town": "Trento", "type": "u", "wheelchair_boarding": true},
+            doc!{"id": 3, "code": "20105x", "description": "", "position": [46.0727, 11.1199], "altitude": 194, "name": "Trento Autostazione", "town": "Trento", "type": "e", "wheelchair_boarding": false},
+        ]),
+        collection::<Path>(vec![
+            doc!{"id": "p1", "sequence": [1, 2], "type": "u"},
+        ]),
+        collection::<Segment>(vec![
+            doc!{"from": 1, "to": 2, "type": "u", "geometry": [[46.0725, 11.1196], [46.0670, 11.1500]]},
+        ]),
+        collection::<Trip>(vec![
+            doc!{"id": "t1", "delay": 0, "direction": "f", "route": 400, "headsign": "Povo", "path": "p1", "type": "u",
+                "times": {"1": {"arrival": "08:00:00", "departure": "08:00:00"}, "2": {"arrival": "08:12:00", "departure": "08:12:00"}}},
+        ]),
+    ])
+}
+
+/// Client of the API serving `store`.
+pub(crate) async fn client_with(store: MemoryStore) -> Client {
+    Client::tracked(super::app().share(store)).await.expect("valid rocket instance")
+}
+
+/// Client of the API serving the [`fixture`] from memory.
+pub(crate) async fn client() -> Client {
+    client_with(fixture()).await
+}
+
+async fn get_json(client: &Client, uri: &str) -> (Status, Value) {
+    let response = client.get(uri).dispatch().await;
+    let status = response.status();
+    (status, response.into_json().await.expect("JSON body"))
+}
+
+fn header<'a>(response: &'a LocalResponse<'_>, name: &str) -> Option<&'a str> {
+    response.headers().get_one(name)
+}
+
+fn ids(body: &Value) -> Vec<i64> {
+    body.as_array().expect("array body").iter().map(|v| v["id"].as_i64().expect("numeric id")).collect()
+}
+
+#[rocket::async_test]
+async fn area_list_is_paged() {
+    let client = client().await;
+    let response = client.get("/api/v1/map/area?limit=2").dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(header(&response, "X-Total-Count"), Some("3"));
+    let link = header(&response, "Link").expect("Link header").to_owned();
+    assert!(link.contains("</api/v1/map/area?skip=0&limit=2>; rel=\"first\""), "{}", link);
+    assert!(link.contains("</api/v1/map/area?skip=2&limit=2>; rel=\"next\""), "{}", link);
+    assert!(link.contains("</api/v1/map/area?skip=2&limit=2>; rel=\"last\""), "{}", link);
+    assert!(!link.contains("rel=\"prev\""), "{}", link);
+    let body = response.into_json::<Value>().await.expect("JSON body");
+    assert_eq!(ids(&body), [1, 2]);
+}
+
+#[rocket::async_test]
+async fn area_by_id() {
+    let client = client().await;
+    let (status, body) = get_json(&client, "/api/v1/map/area/3").await;
+    assert_eq!(status, Status::Ok);
+    assert_eq!(body["label"], "Urbano Rovereto");
+
+    let (status, body) = get_json(&client, "/api/v1/map/area/9").await;
+    assert_eq!(status, Status::NotFound);
+    assert_eq!(body["status"], 404);
+}
+
+#[rocket::async_test]
+async fn route_list_sorted_and_projected() {
+    let client = client().await;
+    let response = client.get("/api/v1/map/route?area=1&sort=code&fields=id,code").dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(header(&response, "X-Total-Count"), Some("3"));
+    let body = response.into_json::<Value>().await.expect("JSON body");
+    // codes are compared by their numeric value
+    assert_eq!(body, json!([{"id": 400, "code": "5"}, {"id": 402, "code": "5/"}, {"id": 401, "code": "13"}]));
+}
+
+#[rocket::async_test]
+async fn invalid_sorts_are_rejected() {
+    let client = client().await;
+    for sort in ["color", "--code", "code:desc", "code,-code", ""] {
+        let (status, body) = get_json(&client, &format!("/api/v1/map/route?sort={}", sort)).await;
+        assert_eq!(status, Status::UnprocessableEntity, "{}", sort);
+        assert_eq!(body["errors"][0]["name"], "sort", "{}", sort);
+        assert_eq!(body["errors"][0]["value"], sort, "{}", sort);
+    }
+
+    let (status, body) = get_json(&client, "/api/v1/map/route?sort=-code&fields=id").await;
+    assert_eq!(status, Status::Ok);
+    assert_eq!(ids(&body), [401, 402, 400]);
+}
+
+#[rocket::async_test]
+async fn route_list_in_envelope() {
+    let client = client().await;
+    let (status, body) = get_json(&client, "/api/v1/map/route?limit=1&skip=1&envelope=true").await;
+    assert_eq!(status, Status::Ok);
+    assert_eq!(ids(&body["data"]), [401]);
+    assert_eq!(body["total"], 3);
+    assert_eq!(body["skip"], 1);
+    assert_eq!(body["limit"], 1);
+    assert_eq!(body["next"], "/api/v1/map/route?envelope=true&skip=2&limit=1");
+}
+
+#[rocket::async_test]
+async fn stop_by_area_type_and_id() {
+    let client = client().await;
+    let (status, body) = get_json(&client, "/api/v1/map/stop/u/2").await;
+    assert_eq!(status, Status::Ok);
+    assert_eq!(body["name"], "Povo Polo Scientifico");
+
+    // stop 3 is extraurban
+    let (status, _) = get_json(&client, "/api/v1/map/stop/u/3").await;
+    assert_eq!(status, Status::NotFound);
+}
+
+#[rocket::async_test]
+async fn stop_list_without_count() {
+    let client = client().await;
+    let response = client.get("/api/v1/map/stop?type=u&count=none&limit=1").dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(header(&response, "X-Total-Count"), None);
+    // a full page is assumed to be followed by another one, but the last one is unknown
+    let link = header(&response, "Link").expect("Link header").to_owned();
+    assert!(link.contains("rel=\"next\""), "{}", link);
+    assert!(!link.contains("rel=\"last\""), "{}", link);
+    let body = response.into_json::<Value>().await.expect("JSON body");
+    assert_eq!(ids(&body), [1]);
+}
+
+#[rocket::async_test]
+async fn stop_search() {
+    let client = client().await;
+    let response = client.get("/api/v1/map/stop/search?q=povo").dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(header(&response, "X-Total-Count"), Some("1"));
+    let body = response.into_json::<Value>().await.expect("JSON body");
+    assert_eq!(ids(&body), [2]);
+
+    let (status, _) = get_json(&client, "/api/v1/map/stop/search?q=%20").await;
+    assert_eq!(status, Status::UnprocessableEntity);
+}
+
+#[rocket::async_test]
+async fn path_by_ids() {
+    let client = client().await;
+    let response = client.get("/api/v1/map/path/p1,p9").dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(header(&response, "X-Total-Count"), Some("1"));
+    let body = response.into_json::<Value>().await.expect("JSON body");
+    assert_eq!(body[0]["id"], "p1");
+    assert_eq!(body[0]["sequence"], json!([1, 2]));
+}
+
+#[rocket::async_test]
+async fn segment_by_stop_pairs() {
+    let client = client().await;
+    let response = client.get("/api/v1/map/segment/u/1-2,2-1").dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(header(&response, "X-Total-Count"), Some("1"));
+    let body = response.into_json::<Value>().await.expect("JSON body");
+    assert_eq!(body[0]["from"], 1);
+    assert_eq!(body[0]["to"], 2);
+
+    let (status, _) = get_json(&client, "/api/v1/map/segment/u/1-x").await;
+    assert_eq!(status, Status::UnprocessableEntity);
+
+    let (status, body) = get_json(&client, "/api/v1/map/segment/u/1-2?format=coords").await;
+    assert_eq!(status, Status::Ok);
+    assert_eq!(body[0]["from"], 1);
+
+    let (status, body) = get_json(&client, "/api/v1/map/segment/u/1-2?format=bogus").await;
+    assert_eq!(status, Status::UnprocessableEntity);
+    assert_eq!(body["errors"][0]["name"], "format");
+    assert_eq!(body["errors"][0]["value"], "bogus");
+    assert!(body["errors"][0]["kind"].as_str().is_some_and(|k| !k.is_empty()), "{}", body);
+}
+
+#[rocket::async_test]
+async fn trip_by_id_projected() {
+    let client = client().await;
+    let (status, body) = get_json(&client, "/api/v1/map/trip/t1?fields=id,route,headsign").await;
+    assert_eq!(status, Status::Ok);
+    assert_eq!(body, json!({"id": "t1", "route": 400, "headsign": "Povo"}));
+
+    let (status, _) = get_json(&client, "/api/v1/map/trip/t1?fields=id,nope").await;
+    assert_eq!(status, Status::UnprocessableEntity);
+}
+
+#[rocket::async_test]
+async fn route_trips_follow_the_cursor() {
+    use chrono::{TimeDelta, TimeZone, Utc};
+
+    // five trips of route 401, three of them departing at the same time
+    let first = Utc.with_ymd_and_hms(2024, 3, 1, 8, 0, 0).unwrap();
+    let departures = [("t10", 0), ("t13", 10), ("t11", 10), ("t12", 10), ("t14", 20)];
+    let mut collections = fixture_collections();
+    collections.extend([
+        collection::<Trip>(departures.iter().map(|(id, _)| doc!{
+            "id": id, "delay": 0, "direction": "f", "route": 401, "headsign": "Gardolo", "path": "p1", "type": "u",
+            "times": {"1": {"arrival": "08:00:00", "departure": "08:00:00"}, "2": {"arrival": "08:12:00", "departure": "08:12:00"}},
+        }).collect()),
+        collection::<Schedule>(departures.iter().map(|(id, secs)| {
+            let departure = first + TimeDelta::seconds(*secs);
+            doc!{
+                "id": id, "departure": departure, "arrival": departure + TimeDelta::minutes(12),
+                "hints": {"route": 401, "direction": "f", "type": "u", "times": {"1": [0, 0], "2": [720, 720]}},
+            }
+        }).collect()),
+    ]);
+    let client = client_with(MemoryStore::new(collections)).await;
+
+    let mut seen = vec![];
+    let mut uri = "/api/v1/map/route/401/trips?time=2024-03-01T07:00:00Z&limit=2".to_owned();
+    loop {
+        let response = client.get(uri.as_str()).dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        let cursor = header(&response, "X-Next-Cursor").map(str::to_owned);
+        let body = response.into_json::<Value>().await.expect("JSON body");
+        let page = body.as_array().expect("array body");
+        seen.extend(page.iter().map(|t| t["trip"]["id"].as_str().expect("trip id").to_owned()));
+        match cursor {
+            Some(cursor) => {
+                assert_eq!(page.len(), 2);
+                uri = format!("/api/v1/map/route/401/trips?time=2024-03-01T07:00:00Z&limit=2&cursor={}", cursor);
+            }
+            None => break,
+        }
+    }
+    // ties on the departure are broken by id, across page boundaries too
+    assert_eq!(seen, ["t10", "t11", "t12", "t13", "t14"]);
+
+    let (status, body) = get_json(&client, "/api/v1/map/route/401/trips?cursor=bogus").await;
+    assert_eq!(status, Status::UnprocessableEntity);
+    assert_eq!(body["errors"][0]["name"], "cursor");
+}
+
+#[rocket::async_test]
+async fn search_suggestions() {
+    let client = client().await;
+    let response = client.get("/api/v1/map/search?q=pov&kinds=stop").dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(header(&response, "X-Total-Count"), Some("1"));
+    let body = response.into_json::<Value>().await.expect("JSON body");
+    assert_eq!(body[0]["kind"], "stop");
+    assert_eq!(body[0]["id"], 2);
+    assert_eq!(body[0]["label"], "Povo Polo Scientifico");
+
+    let (status, _) = get_json(&client, "/api/v1/map/search?q=pov&kinds=bus").await;
+    assert_eq!(status, Status::UnprocessableEntity);
+}
+
+#[rocket::async_test]
+async fn search_routes() {
+    let client = client().await;
+    let (status, body) = get_json(&client, "/api/v1/map/search?q=5&kinds=route").await;
+    assert_eq!(status, Status::Ok);
+    assert_eq!(ids(&body), [400, 402]);
+    assert_eq!(body[0]["label"], "5");
+    assert_eq!(body[0]["detail"], "P.Dante Povo");
+    // the area type of routes, not their GTFS route type
+    assert_eq!(body[0]["area_type"], "u");
+    assert_eq!(body[0]["score"], 1.0);
+}
+
+#[rocket::async_test]
+async fn search_all_kinds_by_default() {
+    let client = client().await;
+    let (status, body) = get_json(&client, "/api/v1/map/search?q=povo").await;
+    assert_eq!(status, Status::Ok);
+    let found = body.as_array().expect("array body").iter()
+        .map(|s| (s["kind"].as_str().expect("kind"), s["id"].as_i64().expect("id")))
+        .collect::<Vec<_>>();
+    // the route only matches through its name, the detail of its suggestion
+    assert_eq!(found, [("stop", 2), ("route", 400)]);
+    assert_eq!(body[1]["area_type"], "u");
+}
+
+#[rocket::async_test]
+async fn search_ranks_before_limiting() {
+    let client = client().await;
+    // the stops in the town of Trento have shorter names than the one named after it
+    let (status, body) = get_json(&client, "/api/v1/map/search?q=trento&kinds=stop&limit=1").await;
+    assert_eq!(status, Status::Ok);
+    assert_eq!(ids(&body), [3]);
+    assert_eq!(body[0]["label"], "Trento Autostazione");
+}
+
 // #[tokio::test]
 // async fn test_trips() {
 //     use tt::{TTClient,RequestOptions,TripQuery};
@@ -32,3 +354,370 @@ use tt::{AreaType, TTTrip, TTType};
 //     println!("{ids:?}");
 //     assert!(ids.contains(&t.id))
 // }
+
+#[rocket::async_test]
+async fn batch_answers_in_order() {
+    let client = client().await;
+    let requests = json!([
+        {"path": "map/area/1", "headers": {"X-Request-Id": "ignored"}},
+        {"path": "/api/v1/map/stop/u/9"},
+        {"method": "POST", "path": "map/area"},
+        {"path": "map/route?area=1&limit=1"},
+    ]);
+    let response = client.post("/api/v1/batch")
+        .header(rocket::http::Header::new("X-Request-Id", "b1"))
+        .json(&requests)
+        .dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    let body = response.into_json::<Value>().await.expect("JSON body");
+    let statuses = body.as_array().expect("array body").iter().map(|r| r["status"].clone()).collect::<Vec<_>>();
+    assert_eq!(statuses, [200, 404, 400, 200]);
+
+    // sub-requests are identified after the batch, whatever their own headers
+    assert_eq!(body[0]["headers"]["x-request-id"], "b1.0");
+    assert_eq!(body[0]["body"]["label"], "Urbano Trento");
+    assert_eq!(body[1]["headers"]["x-request-id"], "b1.1");
+    assert_eq!(body[1]["body"]["status"], 404);
+    assert_eq!(body[2]["headers"]["content-type"], "application/problem+json");
+    assert!(body[2]["body"]["detail"].as_str().is_some_and(|d| d.contains("only GET")), "{}", body[2]);
+    assert_eq!(body[3]["headers"]["x-total-count"], "3");
+    assert_eq!(ids(&body[3]["body"]), [400]);
+}
+
+#[rocket::async_test]
+async fn batch_size_is_limited() {
+    let client = client().await;
+    let requests = vec![json!({"path": "map/area/1"}); API_CONFIG.batch_max_requests + 1];
+    let response = client.post("/api/v1/batch").json(&requests).dispatch().await;
+    assert_eq!(response.status(), Status::BadRequest);
+
+    let requests = vec![json!({"path": "map/area/1"}); API_CONFIG.batch_max_requests];
+    let (status, body) = {
+        let response = client.post("/api/v1/batch").json(&requests).dispatch().await;
+        (response.status(), response.into_json::<Value>().await.expect("JSON body"))
+    };
+    assert_eq!(status, Status::Ok);
+    assert_eq!(body.as_array().map(Vec::len), Some(API_CONFIG.batch_max_requests));
+}
+
+async fn graphql(client: &Client, query: &str) -> Value {
+    let response = client.post("/api/v1/graphql").json(&json!({"query": query})).dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    response.into_json().await.expect("JSON body")
+}
+
+fn graphql_error(body: &Value) -> &str {
+    body["errors"][0]["message"].as_str().unwrap_or_else(|| panic!("no error in {}", body))
+}
+
+#[rocket::async_test]
+async fn graphql_schema() {
+    let client = client().await;
+    let response = client.get("/api/v1/graphql/schema").dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    let sdl = response.into_string().await.expect("schema");
+    for ty in ["type Area", "type Route", "type Stop", "type Trip", "type Path", "type Segment", "type Departure"] {
+        assert!(sdl.contains(ty), "missing `{}`", ty);
+    }
+    assert!(sdl.contains("stops(limit: Int): [Stop!]!"), "{}", sdl);
+    assert!(sdl.contains("segments(limit: Int): [Segment!]!"), "{}", sdl);
+}
+
+#[rocket::async_test]
+async fn graphql_resolves_through_loaders() {
+    let client = client().await;
+    let body = graphql(&client, r#"{
+        stop(type: "u", id: 1) { name routes { code area { label } } }
+        trip(id: "t1") { route { code } path { stops { id } segments { from to } } }
+        firsts: path(id: "p1") { stops(limit: 1) { id } segments(limit: 0) { from } }
+        missing: area(id: 9) { id }
+    }"#).await;
+    assert!(body["errors"].is_null(), "{}", body);
+    assert_eq!(body["data"], json!({
+        "stop": {"name": "Piazza Dante", "routes": [{"code": "5", "area": {"label": "Urbano Trento"}}]},
+        "trip": {"route": {"code": "5"}, "path": {"stops": [{"id": 1}, {"id": 2}], "segments": [{"from": 1, "to": 2}]}},
+        "firsts": {"stops": [{"id": 1}], "segments": []},
+        "missing": null,
+    }));
+}
+
+#[rocket::async_test]
+async fn graphql_rejects_ids_out_of_range() {
+    let client = client_with(MemoryStore::new(HashMap::from([
+        (Route::TYPE.collection().to_string(), vec![doc!{"id": 70000, "type": 3, "area": 1, "area_ty": "u", "code": "X"}]),
+        (Stop::TYPE.collection().to_string(), vec![doc!{"id": 70000, "type": "u", "name": "Nowhere"}]),
+    ]))).await;
+
+    let body = graphql(&client, r#"{ route(id: 70000) { code departures { departure } } }"#).await;
+    assert!(graphql_error(&body).contains("out of range"), "{}", body);
+    let body = graphql(&client, r#"{ stop(type: "u", id: 70000) { name departures { departure } } }"#).await;
+    assert!(graphql_error(&body).contains("out of range"), "{}", body);
+}
+
+#[rocket::async_test]
+async fn graphql_limits_depth() {
+    let client = client().await;
+    // every cycle nests three levels, each counting little towards the complexity
+    let cycles = API_CONFIG.graphql_max_depth / 3 + 1;
+    let query = format!(
+        "{{ route(id: 400) {{ {} id {} }} }}",
+        "departures(limit: 1) { trip { route { ".repeat(cycles),
+        "} } } ".repeat(cycles),
+    );
+    let body = graphql(&client, &query).await;
+    assert!(graphql_error(&body).contains("too deep"), "{}", body);
+    assert!(body["data"].is_null(), "{}", body);
+}
+
+#[rocket::async_test]
+async fn graphql_limits_complexity() {
+    let client = client().await;
+    let body = graphql(&client, r#"{ path(id: "p1") { stops { routes { departures(limit: 100) { trip { id } } } } } }"#).await;
+    assert!(graphql_error(&body).contains("too complex"), "{}", body);
+
+    // the same query is accepted once the lists are short enough
+    let body = graphql(&client, r#"{ path(id: "p1") { stops(limit: 2) { routes { code } } } }"#).await;
+    assert!(body["errors"].is_null(), "{}", body);
+    assert_eq!(body["data"]["path"]["stops"][0]["routes"], json!([{"code": "5"}]));
+}
+
+fn near(body: &Value) -> Vec<(i64, u64)> {
+    body.as_array().expect("array body").iter()
+        .map(|s| (s["id"].as_i64().expect("numeric id"), s["distance_m"].as_u64().expect("distance")))
+        .collect()
+}
+
+#[rocket::async_test]
+async fn stops_near_are_ranked_by_distance() {
+    let client = client().await;
+    // from Piazza Dante: the bus station is 32 m away, Povo 2.4 km
+    let response = client.get("/api/v1/map/stop/near?lat=46.0725&lon=11.1196").dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(header(&response, "X-Total-Count"), Some("2"));
+    let body = response.into_json::<Value>().await.expect("JSON body");
+    assert_eq!(near(&body), [(1, 0), (3, 32)]);
+    assert_eq!(body[0]["name"], "Piazza Dante");
+
+    // from Povo, the bus station is a little closer than Piazza Dante
+    let uri = "/api/v1/map/stop/near?lat=46.0670&lon=11.1500&radius=5000";
+    let (status, body) = get_json(&client, uri).await;
+    assert_eq!(status, Status::Ok);
+    assert_eq!(near(&body), [(2, 0), (3, 2407), (1, 2424)]);
+
+    let response = client.get(format!("{}&skip=1&limit=1", uri)).dispatch().await;
+    assert_eq!(header(&response, "X-Total-Count"), Some("3"));
+    assert!(header(&response, "Link").is_some_and(|l| l.contains("rel=\"next\"")));
+    let body = response.into_json::<Value>().await.expect("JSON body");
+    assert_eq!(near(&body), [(3, 2407)]);
+
+    let (_, body) = get_json(&client, &format!("{}&type=e", uri)).await;
+    assert_eq!(near(&body), [(3, 2407)]);
+}
+
+#[rocket::async_test]
+async fn stops_near_radius_bounds() {
+    let client = client().await;
+    let (status, body) = get_json(&client, "/api/v1/map/stop/near?lat=46.0725&lon=11.1196&radius=0").await;
+    assert_eq!(status, Status::Ok);
+    assert_eq!(near(&body), [(1, 0)]);
+
+    let (status, body) = get_json(&client, "/api/v1/map/stop/near?lat=46.0670&lon=11.1500&radius=2410").await;
+    assert_eq!(status, Status::Ok);
+    assert_eq!(near(&body), [(2, 0), (3, 2407)]);
+
+    let (status, body) = get_json(&client, "/api/v1/map/stop/near?lat=46.0670&lon=11.1500&radius=5000.0").await;
+    assert_eq!(status, Status::Ok);
+    assert_eq!(near(&body).len(), 3);
+
+    for query in ["radius=5001", "radius=-1", "radius=far"] {
+        let (status, _) = get_json(&client, &format!("/api/v1/map/stop/near?lat=46.0670&lon=11.1500&{}", query)).await;
+        assert_eq!(status, Status::UnprocessableEntity, "{}", query);
+    }
+    for query in ["lat=91&lon=11", "lat=46&lon=-181", "lat=46"] {
+        let (status, _) = get_json(&client, &format!("/api/v1/map/stop/near?{}", query)).await;
+        assert_eq!(status, Status::UnprocessableEntity, "{}", query);
+    }
+}
+
+/// Query cache of a dataset imported at `imported_at`, as dated by the dataset watcher.
+fn dated_cache(imported_at: DateTime<chrono::Utc>) -> QueryCache {
+    let cache = QueryCache::default();
+    cache.update_version(DatasetVersion { collections: vec![], imported_at: Some(imported_at) });
+    cache
+}
+
+#[rocket::async_test]
+async fn not_modified() {
+    use chrono::TimeZone;
+
+    let imported_at = chrono::Utc.with_ymd_and_hms(2024, 3, 1, 12, 0, 0).unwrap();
+    let client = Client::tracked(super::app().share(fixture()).share(dated_cache(imported_at))).await
+        .expect("valid rocket instance");
+    let get = |headers: &[(&'static str, &str)]| {
+        let mut request = client.get("/api/v1/map/area/1");
+        for (name, value) in headers {
+            request.add_header(rocket::http::Header::new(*name, value.to_string()));
+        }
+        request.dispatch()
+    };
+
+    let response = get(&[]).await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(header(&response, "Last-Modified"), Some("Fri, 01 Mar 2024 12:00:00 GMT"));
+    let tag = header(&response, "ETag").expect("ETag header").to_owned();
+
+    for if_none_match in [tag.clone(), format!("W/{}", tag), format!("\"other\", {}", tag), "*".to_owned()] {
+        let response = get(&[("If-None-Match", &if_none_match)]).await;
+        assert_eq!(response.status(), Status::NotModified, "{}", if_none_match);
+        assert_eq!(header(&response, "ETag"), Some(tag.as_str()));
+        assert!(response.into_bytes().await.unwrap_or_default().is_empty());
+    }
+    let response = get(&[("If-None-Match", "\"other\"")]).await;
+    assert_eq!(response.status(), Status::Ok);
+
+    let response = get(&[("If-Modified-Since", "Fri, 01 Mar 2024 12:00:00 GMT")]).await;
+    assert_eq!(response.status(), Status::NotModified);
+    let response = get(&[("If-Modified-Since", "Fri, 01 Mar 2024 11:00:00 GMT")]).await;
+    assert_eq!(response.status(), Status::Ok);
+    // `If-Modified-Since` is ignored along with `If-None-Match`
+    let response = get(&[("If-None-Match", "\"other\""), ("If-Modified-Since", "Fri, 01 Mar 2024 12:00:00 GMT")]).await;
+    assert_eq!(response.status(), Status::Ok);
+
+    // paths aren't dated by the watcher: they are only tagged
+    let response = client.get("/api/v1/map/path/p1")
+        .header(rocket::http::Header::new("If-Modified-Since", "Fri, 01 Mar 2024 12:00:00 GMT"))
+        .dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(header(&response, "Last-Modified"), None);
+    assert!(header(&response, "ETag").is_some());
+}
+
+#[rocket::async_test]
+async fn request_ids() {
+    let client = client().await;
+    let response = client.get("/api/v1/map/area/1")
+        .header(rocket::http::Header::new("X-Request-Id", "abc-123"))
+        .dispatch().await;
+    assert_eq!(header(&response, "X-Request-Id"), Some("abc-123"));
+
+    // generated when missing or unfit for logs
+    let response = client.get("/api/v1/map/area/1").dispatch().await;
+    let generated = header(&response, "X-Request-Id").expect("X-Request-Id header").to_owned();
+    assert!(uuid::Uuid::parse_str(&generated).is_ok(), "{}", generated);
+    for sent in ["", "two words", &"x".repeat(129)] {
+        let response = client.get("/api/v1/map/area/1")
+            .header(rocket::http::Header::new("X-Request-Id", sent.to_owned()))
+            .dispatch().await;
+        let id = header(&response, "X-Request-Id").expect("X-Request-Id header");
+        assert!(uuid::Uuid::parse_str(id).is_ok(), "{:?} kept as {}", sent, id);
+    }
+
+    // errors, those of catchers included, quote the id
+    for uri in ["/api/v1/map/area/9", "/api/v1/nowhere"] {
+        let response = client.get(uri)
+            .header(rocket::http::Header::new("X-Request-Id", "err-1"))
+            .dispatch().await;
+        assert_eq!(response.status(), Status::NotFound);
+        assert_eq!(header(&response, "X-Request-Id"), Some("err-1"));
+        let body = response.into_json::<Value>().await.expect("JSON body");
+        assert_eq!(body["request_id"], "err-1");
+    }
+}
+
+#[rocket::async_test]
+async fn query_timeout() {
+    let client = client().await;
+    // a batch with no time left: the queries are never sent
+    let response = client.get("/api/v1/map/area")
+        .header(rocket::http::Header::new(crate::deadline::BUDGET_HEADER, "0"))
+        .dispatch().await;
+    assert_eq!(response.status(), Status::GatewayTimeout);
+    assert_eq!(header(&response, "Content-Type"), Some("application/problem+json"));
+    assert_eq!(header(&response, "Retry-After"), None);
+    let body = response.into_json::<Value>().await.expect("JSON body");
+    assert_eq!(body["status"], 504);
+    assert_eq!(body["code"], "query.timeout");
+    assert!(body["detail"].as_str().is_some_and(|d| d.contains("longer than allowed")), "{}", body);
+    assert!(body["request_id"].as_str().is_some_and(|id| !id.is_empty()), "{}", body);
+}
+
+#[rocket::async_test]
+async fn negotiated_formats() {
+    let client = client().await;
+    let get = |uri: &'static str, accept: &'static str| client.get(uri)
+        .header(rocket::http::Header::new("Accept", accept))
+        .dispatch();
+    let (_, json) = get_json(&client, "/api/v1/map/area/1").await;
+
+    let response = get("/api/v1/map/area/1", "application/msgpack").await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(header(&response, "Content-Type"), Some("application/msgpack"));
+    let body = response.into_bytes().await.expect("body");
+    assert_eq!(rmp_serde::from_slice::<Value>(&body).expect("MessagePack body"), json);
+
+    let response = get("/api/v1/map/area/1", "text/html;q=0.9, application/cbor;q=0.8").await;
+    assert_eq!(header(&response, "Content-Type"), Some("application/cbor"));
+    let body = response.into_bytes().await.expect("body");
+    assert_eq!(ciborium::from_reader::<Value, _>(body.as_slice()).expect("CBOR body"), json);
+
+    // errors are negotiated too
+    let response = get("/api/v1/map/area/9", "application/cbor").await;
+    assert_eq!(response.status(), Status::NotFound);
+    assert_eq!(header(&response, "Content-Type"), Some("application/cbor"));
+
+    // wildcards and unsupported types get JSON, never a 406
+    for accept in ["*/*", "application/*", "text/html", "application/msgpack;q=0"] {
+        let response = get("/api/v1/map/area/1", accept).await;
+        assert_eq!(response.status(), Status::Ok, "{}", accept);
+        assert_eq!(header(&response, "Content-Type"), Some("application/json"), "{}", accept);
+        assert!(response.headers().get("Vary").any(|v| v == "Accept"), "{}", accept);
+    }
+}
+
+async fn cache_stats(client: &Client) -> Value {
+    let (status, stats) = get_json(client, "/api/v1/cache/stats").await;
+    // query cache statistics are always exposed in debug builds
+    assert_eq!(status, Status::Ok);
+    stats
+}
+
+#[rocket::async_test]
+async fn query_cache() {
+    let cache = QueryCache::default();
+    let client = Client::tracked(super::app().share(fixture()).share(cache.clone())).await
+        .expect("valid rocket instance");
+    let counts = |stats: Value| (stats["entries"].clone(), stats["hits"].clone(), stats["misses"].clone());
+
+    let (_, first) = get_json(&client, "/api/v1/map/area/1").await;
+    assert_eq!(counts(cache_stats(&client).await), (json!(1), json!(0), json!(1)));
+    let (_, again) = get_json(&client, "/api/v1/map/area/1").await;
+    assert_eq!(again, first);
+    assert_eq!(counts(cache_stats(&client).await), (json!(1), json!(1), json!(1)));
+
+    let response = client.get("/api/v1/map/area?limit=2").dispatch().await;
+    assert_eq!(header(&response, "X-Total-Count"), Some("3"));
+    let response = client.get("/api/v1/map/area?limit=2").dispatch().await;
+    assert_eq!(header(&response, "X-Total-Count"), Some("3"));
+    assert_eq!(counts(cache_stats(&client).await), (json!(2), json!(2), json!(2)));
+
+    // nor free-form searches nor the stops near a point are kept
+    for uri in ["/api/v1/map/stop/near?lat=46.0725&lon=11.1196", "/api/v1/map/search?q=povo", "/api/v1/map/stop/search?q=povo"] {
+        for _ in 0..2 {
+            let (status, _) = get_json(&client, uri).await;
+            assert_eq!(status, Status::Ok, "{}", uri);
+        }
+    }
+    // paths aren't cached at all
+    get_json(&client, "/api/v1/map/path/p1").await;
+    assert_eq!(counts(cache_stats(&client).await), (json!(2), json!(2), json!(2)));
+
+    // a new dataset, as detected by the watcher, drops the cache
+    let imported_at = chrono::Utc::now();
+    cache.update_version(DatasetVersion { collections: vec![("areas".to_owned(), 3, None)], imported_at: Some(imported_at) });
+    cache.update_version(DatasetVersion { collections: vec![("areas".to_owned(), 4, None)], imported_at: Some(imported_at) });
+    let stats = cache_stats(&client).await;
+    assert_eq!(stats["entries"], 0);
+    assert_eq!(stats["invalidations"], 1);
+    get_json(&client, "/api/v1/map/area/1").await;
+    assert_eq!(counts(cache_stats(&client).await), (json!(1), json!(2), json!(3)));
+}
@@ -0,0 +1,229 @@
+use std::error::Error as StdError;
+use std::fmt::Display;
+use std::time::Duration;
+
+/// Category of a failure of the Trentino Trasporti API.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpstreamErrorKind {
+    /// No response within the client timeout.
+    Timeout,
+    /// The upstream server could not be reached.
+    Connection,
+    /// The upstream server answered with an error status.
+    Status(u16),
+    /// The upstream server answered with a payload that could not be decoded.
+    Malformed,
+    Other,
+}
+
+/// Failure of a request to the Trentino Trasporti API.
+#[derive(Debug)]
+pub struct UpstreamError {
+    pub kind: UpstreamErrorKind,
+    pub url: Option<String>,
+    pub latency: Option<Duration>,
+    message: String,
+}
+
+impl UpstreamError {
+    /// Classify a `TTError` by looking for the underlying http or decoding error.
+    pub fn from_tt(error: &tt::TTError, latency: Option<Duration>) -> Self {
+        let (kind, url) = classify(error);
+        UpstreamError { kind, url, latency, message: error.to_string() }
+    }
+
+    /// Upstream calls abandoned after `latency`, as the deadline of the request expired.
+    pub fn deadline_exceeded(latency: Duration) -> Self {
+        UpstreamError {
+            kind: UpstreamErrorKind::Timeout,
+            url: None,
+            latency: Some(latency),
+            message: "deadline of the request exceeded".to_owned(),
+        }
+    }
+
+    pub fn status(&self) -> u16 {
+        match self.kind {
+            UpstreamErrorKind::Timeout => 504,
+            UpstreamErrorKind::Connection => 503,
+            // upstream is rate limiting us
+            UpstreamErrorKind::Status(429) => 503,
+            UpstreamErrorKind::Status(_) | UpstreamErrorKind::Malformed | UpstreamErrorKind::Other => 502,
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self.kind {
+            UpstreamErrorKind::Timeout => "upstream.timeout",
+            UpstreamErrorKind::Connection => "upstream.unavailable",
+            UpstreamErrorKind::Status(_) => "upstream.bad_status",
+            UpstreamErrorKind::Malformed => "upstream.malformed_response",
+            UpstreamErrorKind::Other => "upstream.error",
+        }
+    }
+
+    pub fn detail(&self) -> String {
+        match self.kind {
+            UpstreamErrorKind::Timeout => "the real-time data provider did not answer in time".to_owned(),
+            UpstreamErrorKind::Connection => "the real-time data provider is unreachable".to_owned(),
+            UpstreamErrorKind::Status(s) => format!("the real-time data provider answered with status {}", s),
+            UpstreamErrorKind::Malformed => "the real-time data provider sent an invalid response".to_owned(),
+            UpstreamErrorKind::Other => "the real-time data provider failed".to_owned(),
+        }
+    }
+
+    /// Seconds after which the client may retry, for transient failures.
+    pub fn retry_after(&self) -> Option<u64> {
+        match self.kind {
+            UpstreamErrorKind::Timeout => Some(10),
+            UpstreamErrorKind::Connection => Some(30),
+            UpstreamErrorKind::Status(s) if s == 429 || s >= 500 => Some(30),
+            _ => None,
+        }
+    }
+}
+
+/// Kind of the first http or decoding error in the chain of sources of `error`, with the url
+/// requested, if known.
+fn classify(error: &(dyn StdError + 'static)) -> (UpstreamErrorKind, Option<String>) {
+    let mut source = Some(error);
+    while let Some(e) = source {
+        if let Some(e) = e.downcast_ref::<reqwest::Error>() {
+            let kind = if e.is_timeout() {
+                UpstreamErrorKind::Timeout
+            } else if e.is_connect() {
+                UpstreamErrorKind::Connection
+            } else if let Some(s) = e.status() {
+                UpstreamErrorKind::Status(s.as_u16())
+            } else if e.is_decode() {
+                UpstreamErrorKind::Malformed
+            } else {
+                UpstreamErrorKind::Other
+            };
+            return (kind, e.url().map(|u| u.to_string()));
+        }
+        if e.downcast_ref::<serde_json::Error>().is_some() {
+            return (UpstreamErrorKind::Malformed, None);
+        }
+        source = e.source();
+    }
+    (UpstreamErrorKind::Other, None)
+}
+
+impl Display for UpstreamError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({})", self.message, self.code())?;
+        if let Some(ref url) = self.url {
+            write!(f, " requesting {}", url)?;
+        }
+        if let Some(latency) = self.latency {
+            write!(f, " after {:?}", latency)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error as StdError;
+    use std::fmt::Display;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::time::Duration;
+
+    use super::{classify, UpstreamError, UpstreamErrorKind::{self, *}};
+
+    /// Error wrapping another, as `TTError` does with http errors.
+    #[derive(Debug)]
+    struct Wrapped(Box<dyn StdError + 'static>);
+
+    impl Display for Wrapped {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "wrapped: {}", self.0)
+        }
+    }
+
+    impl StdError for Wrapped {
+        fn source(&self) -> Option<&(dyn StdError + 'static)> {
+            Some(self.0.as_ref())
+        }
+    }
+
+    /// Url of a local server answering a single request with `response`.
+    fn serve(response: &'static str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/routes", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let _ = stream.read(&mut [0; 4096]);
+            let _ = stream.write_all(response.as_bytes());
+        });
+        url
+    }
+
+    fn client() -> reqwest::Client {
+        reqwest::Client::builder().no_proxy().timeout(Duration::from_millis(200)).build().unwrap()
+    }
+
+    async fn classify_request(url: &str) -> (UpstreamErrorKind, Option<String>) {
+        let error = match client().get(url).send().await.and_then(|r| r.error_for_status()) {
+            Ok(r) => r.json::<serde_json::Value>().await.expect_err("invalid JSON body"),
+            Err(e) => e,
+        };
+        let classified = classify(&error);
+        assert_eq!(classify(&Wrapped(Box::new(error))), classified);
+        classified
+    }
+
+    #[rocket::async_test]
+    async fn classify_http_errors() {
+        // nobody answers: the request times out
+        let silent = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/routes", silent.local_addr().unwrap());
+        assert_eq!(classify_request(&url).await, (Timeout, Some(url)));
+
+        let closed = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        assert_eq!(classify_request(&format!("http://{}/", closed)).await.0, Connection);
+
+        let url = serve("HTTP/1.1 503 Service Unavailable\r\ncontent-length: 0\r\n\r\n");
+        assert_eq!(classify_request(&url).await, (Status(503), Some(url)));
+
+        let url = serve("HTTP/1.1 200 OK\r\ncontent-length: 7\r\n\r\n{routes");
+        assert_eq!(classify_request(&url).await.0, Malformed);
+    }
+
+    #[test]
+    fn classify_other_errors() {
+        let json = serde_json::from_str::<serde_json::Value>("{").unwrap_err();
+        assert_eq!(classify(&Wrapped(Box::new(json))), (Malformed, None));
+        let io = std::io::Error::other("boom");
+        assert_eq!(classify(&Wrapped(Box::new(io))), (Other, None));
+    }
+
+    #[test]
+    fn responses() {
+        let error = |kind| UpstreamError { kind, url: None, latency: None, message: String::new() };
+        let cases = [
+            (Timeout, 504, "upstream.timeout", Some(10)),
+            (Connection, 503, "upstream.unavailable", Some(30)),
+            (Status(429), 503, "upstream.bad_status", Some(30)),
+            (Status(500), 502, "upstream.bad_status", Some(30)),
+            (Status(404), 502, "upstream.bad_status", None),
+            (Malformed, 502, "upstream.malformed_response", None),
+            (Other, 502, "upstream.error", None),
+        ];
+        for (kind, status, code, retry_after) in cases {
+            let e = error(kind);
+            assert_eq!((e.status(), e.code(), e.retry_after()), (status, code, retry_after), "{:?}", kind);
+        }
+        assert!(error(Status(404)).detail().contains("404"));
+    }
+
+    #[test]
+    fn deadline_exceeded() {
+        let e = UpstreamError::deadline_exceeded(Duration::from_millis(1500));
+        assert_eq!(e.kind, Timeout);
+        assert_eq!((e.status(), e.code(), e.retry_after()), (504, "upstream.timeout", Some(10)));
+        assert_eq!(e.to_string(), "deadline of the request exceeded (upstream.timeout) after 1.5s");
+    }
+}